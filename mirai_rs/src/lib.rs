@@ -11,11 +11,17 @@ use response::{AboutResponse, BindResponse, VerifyResponse};
 use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::sync::Arc;
+use tracing::{debug, error, info, warn};
 
 pub type HttpResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 pub type Target = u64;
 
+/// 连续获取消息失败多少次后视为连接断开
+const MAX_FETCH_FAILURES: u32 = 10;
+/// 会话失效（3）或未认证（4）的状态码，需要重新认证
+const INVALID_SESSION_CODES: [u16; 2] = [3, 4];
+
 pub struct Mirai {
     host: String,
     port: u32,
@@ -67,7 +73,7 @@ impl Mirai {
         data.insert("sessionKey", json!(self.session_key));
         data.insert("qq", json!(self.qq));
 
-        println!("{:?}", data);
+        debug!("{:?}", data);
 
         let resp: BindResponse = client
             .post(self.get_url("/bind"))
@@ -108,6 +114,8 @@ impl Mirai {
         Ok(resp)
     }
 
+    /// 轮询并处理消息
+    /// - 连续获取失败或会话失效时返回，由调用方重新认证
     pub async fn start(&mut self) {
         let event_handler = match &self.event_handler {
             Some(event_handler) => event_handler,
@@ -115,23 +123,34 @@ impl Mirai {
                 panic!("");
             }
         };
+        let mut failures = 0;
         loop {
+            let begin = std::time::Instant::now();
             let result = self.fetch_message(1).await;
             let event_handler = event_handler.clone();
+            event_handler.polled(begin.elapsed(), result.is_ok());
             match result {
+                Ok(res) if INVALID_SESSION_CODES.contains(&res.code) => {
+                    warn!("mirai 会话已失效: {} {}", res.code, res.msg);
+                    return;
+                }
                 Ok(res) => {
+                    failures = 0;
                     for item in res.data {
                         if let EventPacket::MessageEvent(message) = item {
                             event_handler.message(message).await;
                             continue;
                         }
-                        println!("接收到其它消息");
-                        println!("{:?}", serde_json::to_string(&item).unwrap());
+                        debug!("接收到其它消息: {:?}", serde_json::to_string(&item).unwrap());
                     }
                 }
                 Err(err) => {
-                    println!("{:?}", err);
-                    println!("获取信息失败");
+                    warn!("获取信息失败: {:?}", err);
+                    failures += 1;
+                    if failures >= MAX_FETCH_FAILURES {
+                        warn!("连续 {} 次获取信息失败", failures);
+                        return;
+                    }
                 }
             }
             tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
@@ -181,7 +200,19 @@ impl MiraiBuilder {
         self
     }
     /// Sets an event handler with multiple methods for each possible event.
-    pub async fn event_handler<H: EventHandler + 'static>(mut self, event_handler: H) -> Mirai {
+    /// - 认证失败时 panic；需要处理失败时使用 [`MiraiBuilder::try_event_handler`]
+    pub async fn event_handler<H: EventHandler + 'static>(self, event_handler: H) -> Mirai {
+        match self.try_event_handler(event_handler).await {
+            Ok(mirai) => mirai,
+            Err(err) => {
+                error!("{:?}", err);
+                panic!("获取verify请求出错");
+            }
+        }
+    }
+
+    /// 设置事件处理并完成认证、绑定 qq；mirai 无法连接或凭据错误时返回错误
+    pub async fn try_event_handler<H: EventHandler + 'static>(mut self, event_handler: H) -> HttpResult<Mirai> {
         self.event_handler = Some(Arc::new(event_handler));
 
         let mut mirai = Mirai {
@@ -193,26 +224,15 @@ impl MiraiBuilder {
             session_key: "".to_string(),
         };
 
-        println!("{},{}", &mirai.host, &mirai.port);
+        info!("{},{}", &mirai.host, &mirai.port);
 
-        match mirai.verify().await {
-            Ok(res) => {
-                mirai.session_key = res.session;
-            }
-            Err(err) => {
-                eprintln!("{:?}", err);
-                panic!("获取verify请求出错");
-            }
+        mirai.session_key = mirai.verify().await?.session;
+        let bind = mirai.bind().await?;
+        if bind.code != 0 {
+            return Err(format!("绑定qq失败: {} {}", bind.code, bind.msg).into());
         }
 
-        match mirai.bind().await {
-            Ok(res) => {}
-            Err(err) => {
-                println!("绑定qq请求出错")
-            }
-        }
-
-        mirai
+        Ok(mirai)
     }
 }
 
@@ -225,4 +245,9 @@ pub mod api {
 #[async_trait]
 pub trait EventHandler: Send + Sync {
     async fn message(&self, msg: MessageEvent);
+
+    /// 每次拉取消息后调用
+    /// - elapsed 请求耗时
+    /// - success 请求是否成功
+    fn polled(&self, _elapsed: std::time::Duration, _success: bool) {}
 }
@@ -10,6 +10,8 @@ use crate::Target;
 pub struct BaseResponse<T> {
     pub code: u16,
     pub msg: String,
+    /// 出错时没有 data
+    #[serde(default)]
     pub data: T,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -58,6 +60,8 @@ pub struct GroupMessage {
     pub sender: sender::GroupSender,
 }
 
+pub use sender::Permission;
+
 mod sender {
     use crate::Target;
     use serde::Deserialize;
@@ -122,8 +126,8 @@ mod sender {
 
 pub type MessageChain = Vec<MessageContent>;
 
-#[serde(tag = "type")]
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
 pub enum MessageContent {
     Source {
         id: Target,
@@ -1,11 +1,14 @@
 use crate::message::MessageChain;
 use crate::message::{BaseResponse, EventPacket, MessageEvent};
-use crate::model::SendGroupMessageResponse;
+use crate::model::{FileResponse, SendGroupMessageResponse, UploadType, UploadVoiceResponse};
 use crate::{response, HttpResult, Mirai};
 
+use serde::de::DeserializeOwned;
 use serde_json::{json, Value};
 use std::collections::HashMap;
+use tracing::{debug, error};
 
+#[derive(Clone)]
 pub struct MiraiHttp {
     host: String,
     port: u32,
@@ -52,40 +55,99 @@ impl MiraiHttp {
             "group": group,
             "messageChain": message_chain
         });
-        let client = reqwest::Client::new();
-        // let mut data = HashMap::new();
-        // data.insert("verifyKey", self.verify_key.as_str());
+        self.send_message("/sendGroupMessage", js).await
+    }
 
-        let response = match self
-            .req
-            .post(self.get_url("/sendGroupMessage"))
-            .json(&js)
-            .send()
-            .await
-        {
-            Ok(resp) => resp,
-            Err(err) => {
-                println!("[mirai_http] send_group_message请求失败");
-                println!("[mirai_http] {:?}", err);
-                Result::Err(err)?
-            }
-        };
-        println!("[mirai_http] send_group_message {}", response.status());
-        let resp = response.text().await.unwrap();
-        let resp: SendGroupMessageResponse = match serde_json::from_str(resp.as_str()) {
+    /// 发送好友消息
+    pub async fn send_friend_message(
+        &self,
+        message_chain: MessageChain,
+        target: u64,
+    ) -> HttpResult<SendGroupMessageResponse> {
+        let js = json!({
+            "sessionKey": self.session_key,
+            "target": target,
+            "messageChain": message_chain
+        });
+        self.send_message("/sendFriendMessage", js).await
+    }
+
+    /// 发送群临时会话消息
+    pub async fn send_temp_message(
+        &self,
+        message_chain: MessageChain,
+        qq: u64,
+        group: u64,
+    ) -> HttpResult<SendGroupMessageResponse> {
+        let js = json!({
+            "sessionKey": self.session_key,
+            "qq": qq,
+            "group": group,
+            "messageChain": message_chain
+        });
+        self.send_message("/sendTempMessage", js).await
+    }
+
+    /// 上传语音，返回的 voiceId 用于发送语音消息；支持 amr 与 silk
+    /// - kind 为语音将发送到的会话类型
+    pub async fn upload_voice(&self, data: Vec<u8>, file_name: &str, kind: UploadType) -> HttpResult<UploadVoiceResponse> {
+        let form = reqwest::multipart::Form::new()
+            .text("sessionKey", self.session_key.clone())
+            .text("type", kind.as_str())
+            .part("voice", reqwest::multipart::Part::bytes(data).file_name(file_name.to_string()));
+        let response = self.req.post(self.get_url("/uploadVoice")).multipart(form).send().await?;
+        parse_response("/uploadVoice", response).await
+    }
+
+    /// 查询群文件信息，包括下载地址
+    pub async fn file_info(&self, id: &str, group: u64) -> HttpResult<FileResponse> {
+        let query = [
+            ("sessionKey", self.session_key.clone()),
+            ("id", id.to_string()),
+            ("target", group.to_string()),
+            ("withDownloadInfo", "true".to_string()),
+        ];
+        let response = self.req.get(self.get_url("/file/info")).query(&query).send().await?;
+        parse_response("/file/info", response).await
+    }
+
+    /// 上传文件到群文件的根目录
+    pub async fn upload_file(&self, data: Vec<u8>, file_name: &str, group: u64) -> HttpResult<FileResponse> {
+        let form = reqwest::multipart::Form::new()
+            .text("sessionKey", self.session_key.clone())
+            .text("type", "group")
+            .text("target", group.to_string())
+            .text("path", "")
+            .part("file", reqwest::multipart::Part::bytes(data).file_name(file_name.to_string()));
+        let response = self.req.post(self.get_url("/file/upload")).multipart(form).send().await?;
+        parse_response("/file/upload", response).await
+    }
+
+    async fn send_message(&self, uri: &str, js: Value) -> HttpResult<SendGroupMessageResponse> {
+        let response = match self.req.post(self.get_url(uri)).json(&js).send().await {
             Ok(resp) => resp,
             Err(err) => {
-                println!("[mirai_http] send_group_message转换json失败");
-                println!("[mirai_http] {:?}", resp);
-                println!("[mirai_http] {:?}", err);
+                error!("{}请求失败: {:?}", uri, err);
                 Result::Err(err)?
             }
         };
-
-        Ok(resp)
+        parse_response(uri, response).await
     }
 
     pub fn get_url(&self, uri: &str) -> String {
         return format!("http://{}:{}{}", self.host, self.port, uri);
     }
 }
+
+/// 读取响应并解析 json，失败时记录原始内容
+async fn parse_response<T: DeserializeOwned>(uri: &str, response: reqwest::Response) -> HttpResult<T> {
+    debug!("{} {}", uri, response.status());
+    let resp = response.text().await?;
+    match serde_json::from_str(&resp) {
+        Ok(resp) => Ok(resp),
+        Err(err) => {
+            error!("{}转换json失败: {:?}; {:?}", uri, err, resp);
+            Err(err)?
+        }
+    }
+}
@@ -4,7 +4,61 @@ use serde_json::Value;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SendGroupMessageResponse {
-    code: u32,
-    msg: String,
-    messageId: u64,
+    pub code: u32,
+    pub msg: String,
+    pub messageId: u64,
+}
+
+/// 上传的语音、图片用于发送到的会话类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadType {
+    Group,
+    Friend,
+    Temp,
+}
+
+impl UploadType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UploadType::Group => "group",
+            UploadType::Friend => "friend",
+            UploadType::Temp => "temp",
+        }
+    }
+}
+
+/// 上传语音的结果
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadVoiceResponse {
+    #[serde(rename = "voiceId")]
+    pub voice_id: String,
+    #[serde(default)]
+    pub url: Option<String>,
+}
+
+/// 群文件接口的结果；请求失败时没有 data
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileResponse {
+    pub code: u32,
+    pub msg: String,
+    #[serde(default)]
+    pub data: Option<FileInfo>,
+}
+
+/// 群文件信息
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    /// 查询时指定 withDownloadInfo 才有下载信息
+    #[serde(default, rename = "downloadInfo")]
+    pub download_info: Option<DownloadInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadInfo {
+    pub url: String,
 }
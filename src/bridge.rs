@@ -1,3 +1,6 @@
+use crate::bridge_archive::Archive;
+use crate::bridge_cmd::Permission;
+use crate::bridge_metrics::METRICS;
 use crate::BridgeConfig;
 
 use std::sync::{Arc, Mutex};
@@ -5,22 +8,81 @@ use std::sync::{Arc, Mutex};
 use serde::Deserialize;
 use serde::Serialize;
 use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, warn};
+
+/// 桥客户端名称
+pub const DC_CLIENT: &str = "bridge_dc_client";
+pub const QQ_CLIENT: &str = "bridge_qq_client";
+/// 管理接口注入消息使用的客户端
+pub const ADMIN_CLIENT: &str = "bridge_admin";
+/// 指令频道；普通消息不会广播到这里，只接收 `send_to` 转交的指令
+pub const CMD_CLIENT: &str = "bridge_cmd_adapter";
 
 /// 客户端所属平台
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum BridgeClientPlatform {
     Discord,
     QQ,
 }
 
+impl BridgeClientPlatform {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BridgeClientPlatform::Discord => "Discord",
+            BridgeClientPlatform::QQ => "QQ",
+        }
+    }
+
+    /// 该平台对应的桥客户端名称
+    pub fn client_name(&self) -> &'static str {
+        match self {
+            BridgeClientPlatform::Discord => DC_CLIENT,
+            BridgeClientPlatform::QQ => QQ_CLIENT,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BridgeMessage {
+    /// 桥内消息 id
+    pub id: String,
+    /// 来源平台的消息 id
+    pub origin_id: Option<String>,
     pub bridge_config: BridgeConfig,
     pub message_chain: MessageChain,
     pub user: User,
+    /// 消息来源的会话
+    #[serde(default)]
+    pub origin: Option<Channel>,
+    /// 指定投递的会话；为空时发往桥配置的群与频道
+    #[serde(default)]
+    pub target: Option<Channel>,
+    /// 回复的桥内消息 id
+    #[serde(default)]
+    pub reply_to: Option<String>,
 }
 impl BridgeMessage {
 
+    /// 与发送者私聊的会话
+    /// - qq 通过来源群发起临时会话
+    pub fn private_channel(&self) -> Option<Channel> {
+        if self.user.id == 0 {
+            return None;
+        }
+        let channel = match self.from_platform()? {
+            BridgeClientPlatform::QQ => {
+                let group = match self.origin {
+                    Some(Channel::QQGroup { group }) => group,
+                    _ => self.bridge_config.qqGroup,
+                };
+                Channel::QQPrivate { qq: self.user.id, group }
+            }
+            BridgeClientPlatform::Discord => Channel::DiscordDM { user: self.user.id },
+        };
+        Some(channel)
+    }
+
     /// 识别消息来自哪个平台
     pub fn from_platform(&self) -> Option<BridgeClientPlatform> {
         let user = &self.user.name;
@@ -36,7 +98,31 @@ impl BridgeMessage {
 
 pub type MessageChain = Vec<MessageContent>;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// 会话：消息的来源或投递目标
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Channel {
+    /// qq 群
+    QQGroup { group: u64 },
+    /// qq 私聊；group 不为 0 时通过该群发起临时会话，否则为好友消息
+    QQPrivate { qq: u64, group: u64 },
+    /// discord 频道
+    DiscordChannel { channel: u64 },
+    /// discord 私信
+    DiscordDM { user: u64 },
+}
+
+impl Channel {
+    /// 会话所在平台
+    pub fn platform(&self) -> BridgeClientPlatform {
+        match self {
+            Channel::QQGroup { .. } | Channel::QQPrivate { .. } => BridgeClientPlatform::QQ,
+            Channel::DiscordChannel { .. } | Channel::DiscordDM { .. } => BridgeClientPlatform::Discord,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum MessageContent {
     Plain {
@@ -45,20 +131,136 @@ pub enum MessageContent {
     Image {
         url: Option<String>, // 图片地址, 通常是cdn或者远程
     },
+    /// 提及同一平台的用户
+    At {
+        /// 被提及用户在平台上的 id
+        id: u64,
+        /// 显示名
+        name: String,
+    },
+    /// 带格式的文本，可以嵌套
+    Styled {
+        style: TextStyle,
+        chain: MessageChain,
+    },
+    /// 代码；block 为代码块
+    Code {
+        text: String,
+        #[serde(default)]
+        language: Option<String>,
+        #[serde(default)]
+        block: bool,
+    },
+    /// 提及 discord 频道
+    ChannelMention {
+        id: u64,
+        name: String,
+    },
+    /// 提及 discord 身份组
+    RoleMention {
+        id: u64,
+        name: String,
+    },
+    /// discord 自定义表情
+    Emoji {
+        id: u64,
+        name: String,
+        #[serde(default)]
+        animated: bool,
+    },
+    /// 提及所有人
+    AtAll,
+    /// qq 表情
+    Face {
+        id: u16,
+        name: String,
+    },
+    /// 回复消息；message_id 为来源平台上的消息 id
+    Reply {
+        message_id: String,
+    },
+    /// 文件；id 为来源平台上的文件 id
+    File {
+        name: String,
+        size: u64,
+        #[serde(default)]
+        url: Option<String>,
+        #[serde(default)]
+        id: Option<String>,
+    },
+    /// 语音；length 为时长（秒）
+    Voice {
+        url: Option<String>,
+        #[serde(default)]
+        length: Option<u32>,
+    },
+    /// 视频
+    Video {
+        url: String,
+        #[serde(default)]
+        name: Option<String>,
+    },
+    /// 链接预览，如 discord 的 embed、qq 的音乐分享
+    LinkPreview {
+        url: String,
+        #[serde(default)]
+        title: Option<String>,
+        #[serde(default)]
+        description: Option<String>,
+        #[serde(default)]
+        image: Option<String>,
+    },
+    /// 合并转发的消息
+    Forward {
+        nodes: Vec<ForwardNode>,
+    },
+}
+
+/// 合并转发中的一条消息
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForwardNode {
+    pub sender_id: u64,
+    pub sender_name: String,
+    /// 发送时间（秒）
+    pub time: i64,
+    pub chain: MessageChain,
+}
+
+/// 文本格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TextStyle {
+    Bold,
+    Italic,
+    Underline,
+    Strikethrough,
+    /// 剧透，点击后才显示
+    Spoiler,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub name: String,
     pub avatar_url: Option<String>,
+    /// 平台上的用户 id：qq 号、discord 用户 id；桥自身发出的消息为 0
+    #[serde(default)]
+    pub id: u64,
+    /// 在 qq 群或 discord 服务器中的权限；不含配置的桥管理员
+    #[serde(default)]
+    pub permission: Permission,
 }
 
 pub struct BridgeService {
     pub clients: Vec<Arc<BridgeClient>>,
+    /// 消息归档；未开启时为空
+    pub archive: Option<Arc<Archive>>,
 }
 
 impl BridgeService {
     pub fn new() -> Self {
-        BridgeService { clients: vec![] }
+        BridgeService {
+            clients: vec![],
+            archive: None,
+        }
     }
 
     pub fn create_client(name: &str, service: Arc<Mutex<BridgeService>>) -> Arc<BridgeClient> {
@@ -77,29 +279,74 @@ pub struct BridgeClient {
     pub name: String,
     pub bridge: Arc<Mutex<BridgeService>>,
     pub sender: broadcast::Sender<BridgeMessage>,
-    pub receiver: broadcast::Receiver<BridgeMessage>,
 }
 
 impl BridgeClient {
     pub fn new(name: &str, bridge: Arc<Mutex<BridgeService>>) -> Self {
-        let (sender, mut receiver) = broadcast::channel(32);
+        // 不保留接收端，队列长度只计算订阅者未处理的消息
+        let (sender, _) = broadcast::channel(32);
         BridgeClient {
             bridge: bridge,
             name: name.to_string(),
             sender,
-            receiver,
         }
     }
 
     pub fn send(&self, message: BridgeMessage) {
-        let bridge = self.bridge.lock().unwrap();
-        for client in bridge.clients.iter() {
-            if &client.name != &self.name {
-                client.sender.send(message.clone());
+        self.send_from(&self.name, message);
+    }
+
+    /// 代替消息来源的客户端广播消息，不会发回来源
+    /// - origin 来源客户端名
+    pub fn send_from(&self, origin: &str, message: BridgeMessage) {
+        let archive = {
+            let bridge = self.bridge.lock().unwrap();
+            for client in bridge.clients.iter() {
+                if client.name != origin && client.name != self.name && client.name != CMD_CLIENT {
+                    client.enqueue(message.clone());
+                }
+            }
+            bridge.archive.clone()
+        };
+        if let Some(archive) = archive {
+            save_archive(archive, message);
+        }
+    }
+
+    /// 放入本客户端的队列并更新队列长度；没有订阅者时返回 false
+    fn enqueue(&self, message: BridgeMessage) -> bool {
+        let sent = self.sender.send(message).is_ok();
+        METRICS.queue_depth.with_label_values(&[&self.name]).set(self.sender.len() as i64);
+        sent
+    }
+
+    /// 接收下一条发往本客户端的消息
+    /// - 处理过慢被跳过的消息计入指标
+    /// - 桥关闭时返回 None
+    pub async fn recv(&self, rx: &mut broadcast::Receiver<BridgeMessage>) -> Option<BridgeMessage> {
+        loop {
+            match rx.recv().await {
+                Ok(message) => {
+                    METRICS.queue_depth.with_label_values(&[&self.name]).set(rx.len() as i64);
+                    return Some(message);
+                }
+                Err(RecvError::Lagged(n)) => {
+                    warn!("[{}] 处理过慢, 跳过了 {} 条消息", self.name, n);
+                    METRICS.broadcast_lag.with_label_values(&[&self.name]).inc_by(n);
+                }
+                Err(RecvError::Closed) => return None,
             }
         }
     }
 
+    /// 取消息归档
+    pub fn archive(&self) -> Option<Arc<Archive>> {
+        match self.bridge.lock() {
+            Ok(b) => b.archive.clone(),
+            Err(_) => None,
+        }
+    }
+
     /// 发送到指定频道
     /// - cli 消息频道名
     pub fn send_to(&self, cli: &str, msg: &BridgeMessage) {
@@ -110,18 +357,96 @@ impl BridgeClient {
                     .iter()
                     .find(|c| c.name == cli.to_string());
                 if let Some(cli) = client {
-                    if let Err(_) = cli.sender.send(msg.clone()) {
-                        println!("All Share-Receiver handles have already been dropped");
+                    if !cli.enqueue(msg.clone()) {
+                        warn!("All Share-Receiver handles have already been dropped");
                     }
                 } else {
-                    println!(r#"Can not found "{cli}""#);
+                    warn!(r#"Can not found "{cli}""#);
                 }
             },
             Err(_) => {
-                println!("Err when get bridge lock");
+                error!("Err when get bridge lock");
                 return;
             }
         }// match
     }// fn share
 
 }
+
+/// 归档消息；写 SQLite 会阻塞，在 tokio 中交给阻塞线程执行
+fn save_archive(archive: Arc<Archive>, message: BridgeMessage) {
+    let save = move || {
+        if let Err(e) = archive.save(&message) {
+            error!("消息归档失败: {:?}", e);
+        }
+    };
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => {
+            runtime.spawn_blocking(save);
+        }
+        Err(_) => save(),
+    }
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+
+    fn message(name: &str, id: u64, origin: Option<Channel>) -> BridgeMessage {
+        BridgeMessage {
+            id: "1".to_string(),
+            origin_id: None,
+            bridge_config: crate::config::test_config().bridges[0].clone(),
+            message_chain: vec![],
+            user: User {
+                name: name.to_string(),
+                avatar_url: None,
+                id,
+                permission: Default::default(),
+            },
+            origin,
+            target: None,
+            reply_to: None,
+        }
+    }
+
+    #[test]
+    fn privateChannel() {
+        let msg = message("[QQ] dong(123)", 123, Some(Channel::QQGroup { group: 31 }));
+        assert_eq!(msg.private_channel(), Some(Channel::QQPrivate { qq: 123, group: 31 }));
+        let msg = message("[QQ] dong(123)", 123, None);
+        assert_eq!(msg.private_channel(), Some(Channel::QQPrivate { qq: 123, group: 30 }));
+        let msg = message("[DC] dong#0001", 456, Some(Channel::DiscordChannel { channel: 20 }));
+        assert_eq!(msg.private_channel(), Some(Channel::DiscordDM { user: 456 }));
+        assert_eq!(message("[Bridge]", 0, None).private_channel(), None);
+    }
+
+    /// 消费者阻塞时队列长度也会更新
+    #[test]
+    fn queueDepthOnSend() {
+        let service = Arc::new(Mutex::new(BridgeService::new()));
+        let sender = BridgeService::create_client("queue_sender", service.clone());
+        let receiver = BridgeService::create_client("queue_receiver", service);
+        let _rx = receiver.sender.subscribe();
+        sender.send_to("queue_receiver", &message("[QQ] dong(123)", 123, None));
+        sender.send_to("queue_receiver", &message("[QQ] dong(123)", 123, None));
+        assert_eq!(METRICS.queue_depth.with_label_values(&["queue_receiver"]).get(), 2);
+    }
+
+    #[test]
+    fn channelJson() {
+        let channel = Channel::QQPrivate { qq: 1, group: 2 };
+        let json = serde_json::to_value(channel).unwrap();
+        assert_eq!(json, serde_json::json!({ "type": "QQPrivate", "qq": 1, "group": 2 }));
+        assert_eq!(serde_json::from_value::<Channel>(json).unwrap(), channel);
+        assert_eq!(channel.platform(), BridgeClientPlatform::QQ);
+
+        // 旧的消息没有会话信息
+        let mut json = serde_json::to_value(message("[DC] a#1", 1, None)).unwrap();
+        json.as_object_mut().unwrap().remove("origin");
+        json.as_object_mut().unwrap().remove("target");
+        let msg: BridgeMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(msg.origin, None);
+    }
+}
@@ -0,0 +1,346 @@
+//! 管理接口：健康检查、桥的启停、测试消息注入、绑定关系维护
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use axum::extract::{Path, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use chrono::Local;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info};
+
+use crate::bridge::{BridgeClient, BridgeMessage, MessageContent, User};
+use crate::bridge_cmd::Permission;
+use crate::bridge_data::{bind_map, Identity, Person};
+use crate::config::AdminConfig;
+use crate::SharedConfig;
+
+/// 适配器名称
+pub const MIRAI: &str = "mirai";
+pub const DISCORD: &str = "discord";
+
+/// 适配器运行状态
+#[derive(Debug, Clone, Serialize)]
+pub struct AdapterStatus {
+    pub healthy: bool,
+    pub detail: String,
+    /// 状态更新时间（毫秒）
+    pub updated_at: i64,
+}
+
+static STATUS: Lazy<RwLock<HashMap<&'static str, AdapterStatus>>> = Lazy::new(Default::default);
+
+/// 上报适配器状态
+pub fn report(adapter: &'static str, healthy: bool, detail: impl Into<String>) {
+    let status = AdapterStatus {
+        healthy,
+        detail: detail.into(),
+        updated_at: Local::now().timestamp_millis(),
+    };
+    if let Ok(mut map) = STATUS.write() {
+        map.insert(adapter, status);
+    }
+}
+
+/// 取适配器状态；未上报过的视为不健康
+fn adapters() -> HashMap<&'static str, AdapterStatus> {
+    let mut map = match STATUS.read() {
+        Ok(map) => map.clone(),
+        Err(_) => HashMap::new(),
+    };
+    for adapter in [MIRAI, DISCORD] {
+        map.entry(adapter).or_insert_with(|| AdapterStatus {
+            healthy: false,
+            detail: "未连接".to_string(),
+            updated_at: 0,
+        });
+    }
+    map
+}
+
+#[derive(Clone)]
+struct AdminState {
+    config: SharedConfig,
+    bridge: Arc<BridgeClient>,
+    token: Arc<String>,
+}
+
+/// 开启管理服务；未开启或无法启动时一直挂起，不影响桥的运行
+pub async fn serve(config: SharedConfig, bridge: Arc<BridgeClient>) {
+    let admin: AdminConfig = config.load().adminConfig.clone();
+    if !admin.enable {
+        return std::future::pending().await;
+    }
+    let addr: SocketAddr = match format!("{}:{}", admin.host, admin.port).parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("管理服务地址错误: {:?}", e);
+            return std::future::pending().await;
+        }
+    };
+    // 没有 token 时任何人都能注入消息、启停桥，只允许本机访问
+    if admin.token.is_empty() && !admin.is_loopback() {
+        error!("管理服务监听 {} 时必须配置 token, 不启动管理服务", addr);
+        return std::future::pending().await;
+    }
+    let state = AdminState {
+        config,
+        bridge,
+        token: Arc::new(admin.token),
+    };
+    info!("管理服务: http://{}", addr);
+    match axum::Server::try_bind(&addr) {
+        Ok(server) => {
+            if let Err(e) = server.serve(router(state).into_make_service()).await {
+                error!("管理服务退出: {:?}", e);
+            }
+        }
+        Err(e) => error!("管理服务无法监听 {}: {:?}", addr, e),
+    }
+    std::future::pending().await
+}
+
+fn router(state: AdminState) -> Router {
+    let admin = Router::new()
+        .route("/bridges", get(list_bridges))
+        .route("/bridges/:id/enable", post(enable_bridge))
+        .route("/bridges/:id/disable", post(disable_bridge))
+        .route("/bridges/:id/message", post(inject_message))
+        .route("/bindings", get(list_bindings).post(add_binding))
+        .route("/bindings/:user", get(get_binding).delete(remove_user_bindings))
+        .route("/bindings/:user/:other", delete(remove_binding))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth));
+    Router::new()
+        .route("/health", get(health))
+        .merge(admin)
+        .with_state(state)
+}
+
+/// 校验 `Authorization: Bearer <token>`；未配置 token 时不校验，此时只监听本机地址
+async fn auth<B>(State(state): State<AdminState>, req: Request<B>, next: Next<B>) -> Response {
+    if state.token.is_empty() {
+        return next.run(req).await;
+    }
+    let expected = format!("Bearer {}", state.token);
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v == expected)
+        .unwrap_or(false);
+    if !authorized {
+        return error_response(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+    next.run(req).await
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+async fn health() -> Response {
+    let adapters = adapters();
+    let healthy = adapters.values().all(|s| s.healthy);
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(json!({ "healthy": healthy, "adapters": adapters }))).into_response()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BridgeInfo {
+    id: String,
+    qq_group: u64,
+    discord_channel: u64,
+    enable: bool,
+}
+
+async fn list_bridges(State(state): State<AdminState>) -> Json<Vec<BridgeInfo>> {
+    let config = state.config.load();
+    let list = config
+        .bridges
+        .iter()
+        .map(|b| BridgeInfo {
+            id: b.id(),
+            qq_group: b.qqGroup,
+            discord_channel: b.discord.channelId,
+            enable: b.enable,
+        })
+        .collect();
+    Json(list)
+}
+
+async fn enable_bridge(State(state): State<AdminState>, Path(id): Path<String>) -> Response {
+    set_enable(&state.config, &id, true)
+}
+
+async fn disable_bridge(State(state): State<AdminState>, Path(id): Path<String>) -> Response {
+    set_enable(&state.config, &id, false)
+}
+
+/// 启停桥；仅修改运行时配置
+fn set_enable(config: &SharedConfig, id: &str, enable: bool) -> Response {
+    if config.load().find_bridge(id).is_none() {
+        return error_response(StatusCode::NOT_FOUND, "bridge not found");
+    }
+    config.rcu(|current| {
+        let mut next = (**current).clone();
+        for bridge in next.bridges.iter_mut().filter(|b| b.id() == id) {
+            bridge.enable = enable;
+        }
+        next
+    });
+    info!("桥 {} 已{}", id, if enable { "开启" } else { "关闭" });
+    Json(json!({ "id": id, "enable": enable })).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct InjectMessage {
+    text: String,
+    user: Option<String>,
+}
+
+/// 向桥注入一条测试消息，同步到两端
+async fn inject_message(
+    State(state): State<AdminState>,
+    Path(id): Path<String>,
+    Json(body): Json<InjectMessage>,
+) -> Response {
+    let bridge_config = match state.config.load().find_bridge(&id) {
+        Some(b) => b.clone(),
+        None => return error_response(StatusCode::NOT_FOUND, "bridge not found"),
+    };
+    let message = BridgeMessage {
+        id: uuid::Uuid::new_v4().to_string(),
+        origin_id: None,
+        bridge_config,
+        message_chain: vec![MessageContent::Plain { text: body.text }],
+        user: User {
+            name: format!("[Admin] {}", body.user.unwrap_or_else(|| "admin".to_string())),
+            avatar_url: None,
+            id: 0,
+            permission: Permission::Member,
+        },
+        origin: None,
+        target: None,
+        reply_to: None,
+    };
+    let message_id = message.id.clone();
+    state.bridge.send(message);
+    Json(json!({ "id": message_id })).into_response()
+}
+
+async fn list_bindings() -> Json<Vec<Person>> {
+    Json(bind_map::all())
+}
+
+/// 解析平台身份
+fn identity(s: &str) -> Result<Identity, String> {
+    s.parse()
+}
+
+async fn get_binding(Path(user): Path<String>) -> Response {
+    let user = match identity(&user) {
+        Ok(user) => user,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+    };
+    match bind_map::person_of(&user) {
+        Some(person) => Json(person).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, "binding not found"),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BindingPair {
+    user1: String,
+    user2: String,
+}
+
+/// 将两个身份归为同一个人
+async fn add_binding(Json(pair): Json<BindingPair>) -> Response {
+    let (user1, user2) = match (identity(&pair.user1), identity(&pair.user2)) {
+        (Ok(user1), Ok(user2)) => (user1, user2),
+        (Err(e), _) | (_, Err(e)) => return error_response(StatusCode::BAD_REQUEST, &e),
+    };
+    bind_map::merge(user1, user2);
+    match bind_map::person_of(&user1) {
+        Some(person) => Json(person).into_response(),
+        None => error_response(StatusCode::BAD_REQUEST, "cannot bind an identity to itself"),
+    }
+}
+
+/// 将身份从所属的人中分离
+async fn remove_user_bindings(Path(user): Path<String>) -> Response {
+    match identity(&user) {
+        Ok(user) => {
+            bind_map::split(&user);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e),
+    }
+}
+
+/// 将 other 从 user 所属的人中分离
+async fn remove_binding(Path((user, other)): Path<(String, String)>) -> Response {
+    let (user, other) = match (identity(&user), identity(&other)) {
+        (Ok(user), Ok(other)) => (user, other),
+        (Err(e), _) | (_, Err(e)) => return error_response(StatusCode::BAD_REQUEST, &e),
+    };
+    if !bind_map::bound(&user).contains(&other) {
+        return error_response(StatusCode::NOT_FOUND, "binding not found");
+    }
+    bind_map::split(&other);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn adapterStatus() {
+        report(MIRAI, true, "ok");
+        let map = adapters();
+        assert!(map[MIRAI].healthy);
+        assert!(map.contains_key(DISCORD));
+    }
+
+    #[test]
+    fn toggleBridge() {
+        let config: SharedConfig = Arc::new(arc_swap::ArcSwap::from_pointee(crate::config::test_config()));
+        let resp = set_enable(&config, "30-20", false);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!config.load().bridges[0].enable);
+        let resp = set_enable(&config, "0-0", true);
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// 端口被占用或对外开放却没有 token 时不启动，也不退出
+    #[tokio::test]
+    async fn refuseToServe() {
+        let service = Arc::new(std::sync::Mutex::new(crate::bridge::BridgeService::new()));
+        let bridge = crate::bridge::BridgeService::create_client("admin_test", service);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = crate::config::test_config();
+        config.adminConfig.enable = true;
+        config.adminConfig.port = listener.local_addr().unwrap().port();
+        let wait = std::time::Duration::from_millis(200);
+        let shared: SharedConfig = Arc::new(arc_swap::ArcSwap::from_pointee(config.clone()));
+        assert!(tokio::time::timeout(wait, serve(shared, bridge.clone())).await.is_err());
+
+        config.adminConfig.host = "0.0.0.0".to_string();
+        config.adminConfig.port = 0;
+        let shared: SharedConfig = Arc::new(arc_swap::ArcSwap::from_pointee(config));
+        assert!(tokio::time::timeout(wait, serve(shared, bridge)).await.is_err());
+    }
+}
@@ -0,0 +1,369 @@
+//! 消息归档：将经过桥的消息保存到本地 SQLite，并提供检索
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::Local;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+
+use crate::bridge::{BridgeClientPlatform, BridgeMessage};
+use crate::bridge_format;
+
+pub type ArchiveResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+/// 归档记录
+#[derive(Debug, Clone)]
+pub struct ArchivedMessage {
+    pub user_name: String,
+    /// 消息纯文本
+    pub text: String,
+    /// 归档时间（毫秒）
+    pub created_at: i64,
+}
+
+/// 检索条件；各条件为空时不参与过滤
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SearchQuery {
+    /// 只检索这个桥的消息：(qq群, discord频道)
+    pub bridge: Option<(u64, u64)>,
+    pub keyword: Option<String>,
+    pub user: Option<String>,
+    /// 起始时间（毫秒，含）
+    pub since: Option<i64>,
+    /// 结束时间（毫秒，不含）
+    pub until: Option<i64>,
+    pub limit: usize,
+}
+
+pub struct Archive {
+    conn: Mutex<Connection>,
+}
+
+impl Archive {
+    /// 打开（或创建）归档库
+    pub fn open(path: impl AsRef<Path>) -> ArchiveResult<Self> {
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Self::init(Connection::open(path)?)
+    }
+
+    /// 内存库，用于测试
+    #[cfg(test)]
+    pub fn in_memory() -> ArchiveResult<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> ArchiveResult<Self> {
+        conn.execute_batch(
+            r#"
+            CREATE TABLE IF NOT EXISTS messages (
+                id              TEXT PRIMARY KEY,
+                platform        TEXT NOT NULL,
+                qq_group        INTEGER NOT NULL,
+                discord_channel INTEGER NOT NULL,
+                user_name       TEXT NOT NULL,
+                avatar_url      TEXT,
+                text            TEXT NOT NULL,
+                chain           TEXT NOT NULL,
+                origin_id       TEXT,
+                created_at      INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_messages_time ON messages (created_at);
+            CREATE INDEX IF NOT EXISTS idx_messages_user ON messages (user_name);
+            CREATE TABLE IF NOT EXISTS deliveries (
+                message_id   TEXT NOT NULL,
+                platform     TEXT NOT NULL,
+                remote_id    TEXT NOT NULL,
+                delivered_at INTEGER NOT NULL,
+                PRIMARY KEY (message_id, platform)
+            );
+            CREATE INDEX IF NOT EXISTS idx_deliveries_remote ON deliveries (platform, remote_id);
+            CREATE TABLE IF NOT EXISTS discord_threads (
+                message_id TEXT PRIMARY KEY,
+                thread_id  TEXT NOT NULL
+            );
+            "#,
+        )?;
+        Ok(Archive {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// 保存一条桥消息
+    pub fn save(&self, message: &BridgeMessage) -> ArchiveResult<()> {
+        let platform = match message.from_platform() {
+            Some(p) => p.as_str(),
+            None => "Unknown",
+        };
+        let conn = self.conn.lock().map_err(|_| "archive lock poisoned")?;
+        conn.execute(
+            "INSERT OR IGNORE INTO messages
+                (id, platform, qq_group, discord_channel, user_name, avatar_url, text, chain, origin_id, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                message.id,
+                platform,
+                message.bridge_config.qqGroup as i64,
+                message.bridge_config.discord.channelId as i64,
+                message.user.name,
+                message.user.avatar_url,
+                bridge_format::plain_text(&message.message_chain),
+                serde_json::to_string(&message.message_chain)?,
+                message.origin_id,
+                Local::now().timestamp_millis(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// 记录消息同步到某个平台后，在该平台上的消息 id
+    pub fn record_delivery(
+        &self,
+        message_id: &str,
+        platform: BridgeClientPlatform,
+        remote_id: &str,
+    ) -> ArchiveResult<()> {
+        let conn = self.conn.lock().map_err(|_| "archive lock poisoned")?;
+        conn.execute(
+            "INSERT OR REPLACE INTO deliveries (message_id, platform, remote_id, delivered_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![message_id, platform.as_str(), remote_id, Local::now().timestamp_millis()],
+        )?;
+        Ok(())
+    }
+
+    /// 取消息在指定平台上的 id；来源平台返回原消息 id
+    pub fn remote_id(&self, message_id: &str, platform: BridgeClientPlatform) -> ArchiveResult<Option<String>> {
+        let conn = self.conn.lock().map_err(|_| "archive lock poisoned")?;
+        let id = conn
+            .query_row(
+                "SELECT remote_id FROM deliveries WHERE message_id = ?1 AND platform = ?2
+                 UNION ALL
+                 SELECT origin_id FROM messages WHERE id = ?1 AND platform = ?2 AND origin_id IS NOT NULL
+                 LIMIT 1",
+                params![message_id, platform.as_str()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(id)
+    }
+
+    /// 由任一平台上的消息 id 找回桥内消息 id
+    /// - 只查找这个桥的消息：(qq群, discord频道)；不同群的消息 id 可能相同
+    pub fn find_by_remote_id(
+        &self,
+        bridge: (u64, u64),
+        platform: BridgeClientPlatform,
+        remote_id: &str,
+    ) -> ArchiveResult<Option<String>> {
+        let conn = self.conn.lock().map_err(|_| "archive lock poisoned")?;
+        let id = conn
+            .query_row(
+                "SELECT id FROM messages
+                 WHERE platform = ?1 AND origin_id = ?2 AND qq_group = ?3 AND discord_channel = ?4
+                 UNION ALL
+                 SELECT d.message_id FROM deliveries d JOIN messages m ON m.id = d.message_id
+                 WHERE d.platform = ?1 AND d.remote_id = ?2 AND m.qq_group = ?3 AND m.discord_channel = ?4
+                 LIMIT 1",
+                params![platform.as_str(), remote_id, bridge.0 as i64, bridge.1 as i64],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(id)
+    }
+
+    /// 记录 discord 消息所在的子区
+    pub fn record_thread(&self, message_id: &str, thread_id: &str) -> ArchiveResult<()> {
+        let conn = self.conn.lock().map_err(|_| "archive lock poisoned")?;
+        conn.execute(
+            "INSERT OR REPLACE INTO discord_threads (message_id, thread_id) VALUES (?1, ?2)",
+            params![message_id, thread_id],
+        )?;
+        Ok(())
+    }
+
+    /// 取 discord 消息所在的子区
+    pub fn thread_of(&self, message_id: &str) -> ArchiveResult<Option<String>> {
+        let conn = self.conn.lock().map_err(|_| "archive lock poisoned")?;
+        let id = conn
+            .query_row(
+                "SELECT thread_id FROM discord_threads WHERE message_id = ?1",
+                params![message_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(id)
+    }
+
+    /// 检索历史消息，按时间倒序
+    pub fn search(&self, query: &SearchQuery) -> ArchiveResult<Vec<ArchivedMessage>> {
+        let mut sql = String::from(
+            "SELECT user_name, text, created_at FROM messages WHERE 1 = 1",
+        );
+        let mut args: Vec<rusqlite::types::Value> = Vec::new();
+        if let Some((qq_group, discord_channel)) = query.bridge {
+            sql += " AND qq_group = ? AND discord_channel = ?";
+            args.push((qq_group as i64).into());
+            args.push((discord_channel as i64).into());
+        }
+        if let Some(keyword) = &query.keyword {
+            sql += " AND text LIKE ? ESCAPE '\\'";
+            args.push(format!("%{}%", escape_like(keyword)).into());
+        }
+        if let Some(user) = &query.user {
+            sql += " AND user_name LIKE ? ESCAPE '\\'";
+            args.push(format!("%{}%", escape_like(user)).into());
+        }
+        if let Some(since) = query.since {
+            sql += " AND created_at >= ?";
+            args.push(since.into());
+        }
+        if let Some(until) = query.until {
+            sql += " AND created_at < ?";
+            args.push(until.into());
+        }
+        sql += " ORDER BY created_at DESC LIMIT ?";
+        let limit = if query.limit == 0 { 10 } else { query.limit };
+        args.push((limit as i64).into());
+
+        let conn = self.conn.lock().map_err(|_| "archive lock poisoned")?;
+        let mut stmt = conn.prepare(&sql)?;
+        let rows = stmt.query_map(params_from_iter(args.iter()), |row| {
+            Ok(ArchivedMessage {
+                user_name: row.get(0)?,
+                text: row.get(1)?,
+                created_at: row.get(2)?,
+            })
+        })?;
+        let mut list = Vec::new();
+        for row in rows {
+            list.push(row?);
+        }
+        Ok(list)
+    }
+}
+
+/// 转义 `\` `%` `_`，用于 `LIKE ... ESCAPE '\'` 中按字面匹配
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+    use crate::bridge::{MessageContent, User};
+
+    fn message(name: &str, text: &str) -> BridgeMessage {
+        BridgeMessage {
+            id: uuid::Uuid::new_v4().to_string(),
+            origin_id: Some("1001".to_string()),
+            bridge_config: crate::config::test_config().bridges[0].clone(),
+            message_chain: vec![MessageContent::Plain {
+                text: text.to_string(),
+            }],
+            user: User {
+                name: name.to_string(),
+                avatar_url: None,
+                id: 0,
+                permission: Default::default(),
+            },
+            origin: None,
+            target: None,
+            reply_to: None,
+        }
+    }
+
+    #[test]
+    fn saveAndSearch() {
+        let archive = Archive::in_memory().unwrap();
+        archive.save(&message("[QQ] dong(123)", "hello world")).unwrap();
+        archive.save(&message("[DC] abc#0001", "100% done")).unwrap();
+
+        let all = archive.search(&SearchQuery::default()).unwrap();
+        assert_eq!(all.len(), 2);
+
+        let found = archive
+            .search(&SearchQuery {
+                keyword: Some("hello".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].user_name, "[QQ] dong(123)");
+
+        let found = archive
+            .search(&SearchQuery {
+                keyword: Some("%".to_string()),
+                user: Some("abc".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].text, "100% done");
+
+        let found = archive
+            .search(&SearchQuery {
+                until: Some(0),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(found.is_empty());
+    }
+
+    /// 只能检索到本桥的消息
+    #[test]
+    fn searchInBridge() {
+        let archive = Archive::in_memory().unwrap();
+        archive.save(&message("[QQ] dong(123)", "hello a")).unwrap();
+        let mut other = message("[QQ] dong(123)", "hello b");
+        other.bridge_config.qqGroup = 4;
+        other.bridge_config.discord.channelId = 5;
+        archive.save(&other).unwrap();
+
+        let query = |bridge| SearchQuery {
+            bridge: Some(bridge),
+            keyword: Some("hello".to_string()),
+            ..Default::default()
+        };
+        let found = archive.search(&query((30, 20))).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].text, "hello a");
+        let found = archive.search(&query((4, 5))).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].text, "hello b");
+        assert!(archive.search(&query((30, 5))).unwrap().is_empty());
+    }
+
+    #[test]
+    fn deliveryIds() {
+        let archive = Archive::in_memory().unwrap();
+        let msg = message("[QQ] dong(123)", "hello");
+        archive.save(&msg).unwrap();
+        archive
+            .record_delivery(&msg.id, BridgeClientPlatform::Discord, "99887766")
+            .unwrap();
+
+        let id = archive.find_by_remote_id((30, 20), BridgeClientPlatform::Discord, "99887766").unwrap();
+        assert_eq!(id.as_deref(), Some(msg.id.as_str()));
+        let id = archive.find_by_remote_id((30, 20), BridgeClientPlatform::QQ, "1001").unwrap();
+        assert_eq!(id.as_deref(), Some(msg.id.as_str()));
+        // 其他桥中相同的消息 id 不是这条消息
+        let id = archive.find_by_remote_id((4, 5), BridgeClientPlatform::QQ, "1001").unwrap();
+        assert_eq!(id, None);
+        let id = archive.find_by_remote_id((4, 5), BridgeClientPlatform::Discord, "99887766").unwrap();
+        assert_eq!(id, None);
+        let remote = archive.remote_id(&msg.id, BridgeClientPlatform::Discord).unwrap();
+        assert_eq!(remote.as_deref(), Some("99887766"));
+        let remote = archive.remote_id(&msg.id, BridgeClientPlatform::QQ).unwrap();
+        assert_eq!(remote.as_deref(), Some("1001"));
+    }
+
+    #[test]
+    fn discordThreads() {
+        let archive = Archive::in_memory().unwrap();
+        assert_eq!(archive.thread_of("99887766").unwrap(), None);
+        archive.record_thread("99887766", "5566").unwrap();
+        assert_eq!(archive.thread_of("99887766").unwrap().as_deref(), Some("5566"));
+    }
+}
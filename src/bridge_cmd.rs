@@ -1,40 +1,814 @@
 ///! 用户指令的实现
 
+use std::collections::HashMap;
+
+use chrono::{Local, NaiveDate, TimeZone};
+use serde::{Deserialize, Serialize};
+
 use crate::bridge::{MessageChain, MessageContent, User, BridgeClientPlatform};
-use crate::bridge_cmd::Cmd::Bind;
+use crate::bridge_archive::SearchQuery;
+use crate::bridge_cmd::Cmd::*;
+use crate::bridge_data::Identity;
+use crate::config::{BridgeConfig, Config, ForwardMode};
+
+/// 绑定码有效期（毫秒）
+pub const BIND_TIMEOUT: i64 = 300_000;
+/// 绑定码字符集；去掉了易混淆的字符
+const BIND_CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
 
 /// 指令
 #[derive(Debug)]
 pub struct CmdMeta {
     /// 操作者
     pub operator: User,
-    /// 指令文本链
-    pub token_chain: MessageChain,
     /// 指定客户端平台
     pub platform: BridgeClientPlatform,
 }
 
 /// 指令类别
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Cmd {
     /// dc,qq互相绑定
     Bind,
+    /// 检索历史消息
+    Search,
+    /// 解除绑定；管理员可指定用户
+    Unbind,
+    /// 查看自己的绑定
+    MyBind,
+    /// 查看指定用户的绑定
+    Whois,
+    /// 设置两端统一显示的名称
+    Nickname,
+    /// 设置头像来源
+    Avatar,
+    /// 列出指令及用法
+    Help,
+    /// 查看、暂停、恢复桥，或建立新的桥
+    Manage,
 }
 
-/// 识别指令类别
-pub fn kind(token_chain: &MessageChain) -> Option<Cmd> {
-    if let Some(first) = token_chain.get(0) {
-        return match first {
-            MessageContent::Plain { text } => {
-                // TODO 正则，检验指令格式
-                if text.starts_with("!绑定") {
-                    return Some(Bind);
+/// 用户权限，由低到高
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Permission {
+    /// 普通成员
+    #[default]
+    Member,
+    /// qq 群主、群管理员；discord 服务器所有者、拥有管理权限或管理角色的成员
+    Manager,
+    /// 配置的桥管理员
+    Admin,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::Member => "成员",
+            Permission::Manager => "管理者",
+            Permission::Admin => "桥管理员",
+        }
+    }
+}
+
+/// 取用户的权限：配置的桥管理员，否则为用户在群或服务器中的权限
+/// - platform 用户所在平台
+pub fn permission(config: &Config, platform: BridgeClientPlatform, user: &User) -> Permission {
+    if config.is_admin(&Identity::new(platform, user.id)) {
+        return Permission::Admin;
+    }
+    user.permission
+}
+
+/// 参数类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    /// 一个词
+    Word,
+    /// 用户：提及（`@用户`）、同平台的用户 id 或 `平台:用户id`
+    User,
+    /// 余下的全部文本
+    Rest,
+}
+
+/// 参数定义
+#[derive(Debug)]
+pub struct ArgDef {
+    /// 取值时使用的名称
+    pub name: &'static str,
+    /// 用法中显示的说明
+    pub hint: &'static str,
+    pub kind: ArgKind,
+    pub required: bool,
+}
+
+/// 指令定义
+#[derive(Debug)]
+pub struct CmdDef {
+    pub cmd: Cmd,
+    /// 指令名，第一个为主名称，其余为别名；不含前缀
+    pub names: &'static [&'static str],
+    pub args: &'static [ArgDef],
+    /// 简介
+    pub about: &'static str,
+    /// 使用指令所需的权限
+    pub permission: Permission,
+    /// 默认的同步方式；可在桥的配置中修改
+    pub forward: ForwardMode,
+}
+
+const fn arg(name: &'static str, hint: &'static str, kind: ArgKind, required: bool) -> ArgDef {
+    ArgDef {
+        name,
+        hint,
+        kind,
+        required,
+    }
+}
+
+/// 指令注册表；`!help` 按此顺序列出
+pub const COMMANDS: &[CmdDef] = &[
+    CmdDef {
+        cmd: Bind,
+        names: &["绑定", "bind"],
+        args: &[arg("arg", "另一平台的用户id | 绑定码", ArgKind::Word, true)],
+        about: "发起绑定，或在另一平台用绑定码确认",
+        permission: Permission::Member,
+        forward: ForwardMode::Hide,
+    },
+    CmdDef {
+        cmd: Unbind,
+        names: &["解绑", "unbind"],
+        args: &[arg("user", "用户", ArgKind::User, false)],
+        about: "解除绑定；管理员可指定用户",
+        permission: Permission::Member,
+        forward: ForwardMode::Hide,
+    },
+    CmdDef {
+        cmd: MyBind,
+        names: &["我的绑定", "mybind"],
+        args: &[],
+        about: "查看自己的绑定",
+        permission: Permission::Member,
+        forward: ForwardMode::Hide,
+    },
+    CmdDef {
+        cmd: Whois,
+        names: &["whois", "查询"],
+        args: &[arg("user", "用户", ArgKind::User, true)],
+        about: "查看用户的绑定",
+        permission: Permission::Member,
+        forward: ForwardMode::Mirror,
+    },
+    CmdDef {
+        cmd: Nickname,
+        names: &["昵称", "nick"],
+        args: &[arg("name", "名称", ArgKind::Rest, false)],
+        about: "设置两端显示的名称；不带参数时清除",
+        permission: Permission::Member,
+        forward: ForwardMode::Hide,
+    },
+    CmdDef {
+        cmd: Avatar,
+        names: &["头像", "avatar"],
+        args: &[arg("source", "auto | qq | discord", ArgKind::Word, true)],
+        about: "设置头像来源",
+        permission: Permission::Member,
+        forward: ForwardMode::Hide,
+    },
+    CmdDef {
+        cmd: Search,
+        names: &["搜索", "search"],
+        args: &[arg("query", "关键词 [user:用户] [from:YYYY-MM-DD] [to:YYYY-MM-DD]", ArgKind::Rest, true)],
+        about: "检索历史消息",
+        permission: Permission::Member,
+        forward: ForwardMode::Mirror,
+    },
+    CmdDef {
+        cmd: Manage,
+        names: &["bridge", "桥"],
+        args: &[
+            arg("action", "status | pause | resume | link", ArgKind::Word, true),
+            arg("target", "另一平台的群号或频道id", ArgKind::Word, false),
+        ],
+        about: "查看、暂停、恢复桥；link 将所在的群或频道与另一平台建立桥",
+        permission: Permission::Manager,
+        forward: ForwardMode::Hide,
+    },
+    CmdDef {
+        cmd: Help,
+        names: &["帮助", "help"],
+        args: &[arg("cmd", "指令", ArgKind::Word, false)],
+        about: "列出指令，或查看指令的用法",
+        permission: Permission::Member,
+        forward: ForwardMode::Mirror,
+    },
+];
+
+impl CmdDef {
+    /// 按名称或别名查找指令，不区分大小写
+    pub fn find(name: &str) -> Option<&'static CmdDef> {
+        let name = name.to_lowercase();
+        COMMANDS.iter().find(|def| def.names.contains(&name.as_str()))
+    }
+
+    /// 用法，如 `用法: !whois <用户>`
+    pub fn usage(&self, prefix: &str) -> String {
+        let mut usage = format!("用法: {}{}", prefix, self.names[0]);
+        for arg in self.args {
+            let hint = match arg.kind {
+                ArgKind::Rest => format!("{}...", arg.hint),
+                _ => arg.hint.to_string(),
+            };
+            if arg.required {
+                usage += &format!(" <{}>", hint);
+            } else {
+                usage += &format!(" [{}]", hint);
+            }
+        }
+        usage
+    }
+
+    /// 指令在桥上的同步方式
+    /// - 按指令名、别名的顺序查找配置，同一指令配置了多项时结果固定
+    pub fn forward_mode(&self, bridge_config: &BridgeConfig) -> ForwardMode {
+        let config = &bridge_config.cmdForward;
+        self.names
+            .iter()
+            .find_map(|name| {
+                config
+                    .commands
+                    .iter()
+                    .filter(|(key, _)| key.to_lowercase() == *name)
+                    .min_by_key(|(key, _)| key.as_str())
+                    .map(|(_, mode)| *mode)
+            })
+            .or(config.default)
+            .unwrap_or(self.forward)
+    }
+
+    /// 检查权限；权限不足时返回回复内容
+    pub fn check(&self, prefix: &str, level: Permission) -> Result<(), String> {
+        if level >= self.permission {
+            return Ok(());
+        }
+        Err(format!("权限不足: {}{} 需要{}权限", prefix, self.names[0], self.permission.as_str()))
+    }
+
+    /// 按定义解析参数
+    fn parse_args(&self, tokens: &[Token], platform: BridgeClientPlatform) -> Result<HashMap<&'static str, ArgValue>, String> {
+        let mut args = HashMap::new();
+        let mut rest = tokens.iter();
+        for def in self.args {
+            let value = match def.kind {
+                ArgKind::Word => match rest.next() {
+                    Some(Token::Word(word)) => Some(ArgValue::Text(word.clone())),
+                    Some(Token::At { name, .. }) => return Err(format!("<{}> 不能是提及: @{}", def.hint, name)),
+                    None => None,
+                },
+                ArgKind::User => match rest.next() {
+                    Some(Token::At { id, .. }) => Some(ArgValue::User(Identity::new(platform, *id))),
+                    Some(Token::Word(word)) => match parse_user(word, platform) {
+                        Some(user) => Some(ArgValue::User(user)),
+                        None => return Err(format!("无法识别用户: {}", word)),
+                    },
+                    None => None,
+                },
+                ArgKind::Rest => {
+                    let text: Vec<String> = rest.by_ref().map(|t| t.to_string()).collect();
+                    Some(ArgValue::Text(text.join(" "))).filter(|_| !text.is_empty())
                 }
+            };
+            match value {
+                Some(value) => {
+                    args.insert(def.name, value);
+                }
+                None if def.required => return Err(format!("缺少参数 <{}>", def.hint)),
+                None => {}
+            }
+        }
+        let extra: Vec<String> = rest.map(|t| t.to_string()).collect();
+        if !extra.is_empty() {
+            return Err(format!("多余的参数: {}", extra.join(" ")));
+        }
+        Ok(args)
+    }
+}
+
+/// 参数值
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgValue {
+    Text(String),
+    User(Identity),
+}
+
+/// 解析后的指令
+#[derive(Debug)]
+pub struct Invocation {
+    pub cmd: Cmd,
+    /// 指令所在桥的前缀
+    pub prefix: String,
+    /// 指令所在平台；提及与用户 id 视为该平台的用户
+    pub platform: BridgeClientPlatform,
+    /// 发送者的权限
+    pub permission: Permission,
+    args: HashMap<&'static str, ArgValue>,
+}
+
+impl Invocation {
+    /// 取文本参数
+    pub fn text(&self, name: &str) -> Option<&str> {
+        match self.args.get(name) {
+            Some(ArgValue::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// 取用户参数
+    pub fn user(&self, name: &str) -> Option<Identity> {
+        match self.args.get(name) {
+            Some(ArgValue::User(user)) => Some(*user),
+            _ => None,
+        }
+    }
+
+    pub fn def(&self) -> &'static CmdDef {
+        COMMANDS.iter().find(|def| def.cmd == self.cmd).expect("指令未注册")
+    }
+
+    /// 指令的用法
+    pub fn usage(&self) -> String {
+        self.def().usage(&self.prefix)
+    }
+}
+
+/// 指令中的词
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    At { id: u64, name: String },
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{}", word),
+            Token::At { name, .. } => write!(f, "@{}", name),
+        }
+    }
+}
+
+/// 按空白与提及切分消息链；忽略其他内容
+fn tokenize(chain: &MessageChain) -> Vec<Token> {
+    let mut tokens = vec![];
+    for content in chain {
+        match content {
+            MessageContent::Plain { text } => {
+                tokens.extend(text.split_whitespace().map(|w| Token::Word(w.to_string())));
+            }
+            MessageContent::At { id, name } => tokens.push(Token::At {
+                id: *id,
+                name: name.clone(),
+            }),
+            _ => {}
+        }
+    }
+    tokens
+}
+
+/// 解析指令
+/// - chain 消息链
+/// - prefix 指令所在桥的前缀
+/// - platform 指令所在平台
+/// - level 发送者的权限
+/// - 不是已注册的指令时返回 None；权限不足或参数有误时返回回复内容
+pub fn parse(
+    chain: &MessageChain,
+    prefix: &str,
+    platform: BridgeClientPlatform,
+    level: Permission,
+) -> Option<Result<Invocation, String>> {
+    let def = lookup(chain, prefix)?;
+    let tokens = tokenize(chain);
+    if let Err(denied) = def.check(prefix, level) {
+        return Some(Err(denied));
+    }
+    let result = match def.parse_args(&tokens[1..], platform) {
+        Ok(args) => Ok(Invocation {
+            cmd: def.cmd,
+            prefix: prefix.to_string(),
+            platform,
+            permission: level,
+            args,
+        }),
+        Err(e) => Err(format!("{}\n{}", e, def.usage(prefix))),
+    };
+    Some(result)
+}
+
+/// 识别消息是否为已注册的指令，不解析参数
+pub fn lookup(chain: &MessageChain, prefix: &str) -> Option<&'static CmdDef> {
+    let name = match chain.first()? {
+        MessageContent::Plain { text } => text.split_whitespace().next()?.strip_prefix(prefix)?,
+        _ => return None,
+    };
+    CmdDef::find(name)
+}
+
+/// 生成帮助
+/// - name 指定时只显示该指令的用法与别名
+/// - level 只列出该权限可用的指令
+pub fn help(prefix: &str, name: Option<&str>, level: Permission) -> Result<String, String> {
+    if let Some(name) = name {
+        let name = name.strip_prefix(prefix).unwrap_or(name);
+        let def = CmdDef::find(name).ok_or_else(|| format!("未知指令: {}, 发送 {}help 查看全部指令", name, prefix))?;
+        let aliases: Vec<String> = def.names.iter().map(|n| format!("{}{}", prefix, n)).collect();
+        return Ok(format!("{}\n{}\n别名: {}", def.about, def.usage(prefix), aliases.join(", ")));
+    }
+    let mut lines = vec!["可用指令:".to_string()];
+    for def in COMMANDS.iter().filter(|def| level >= def.permission) {
+        lines.push(format!("{}{} - {}", prefix, def.names.join(" / "), def.about));
+    }
+    lines.push(format!("发送 {}help <指令> 查看用法", prefix));
+    Ok(lines.join("\n"))
+}
+
+/// 解析用户参数
+/// - 支持同平台的用户 id（可带 `@`）与 `平台:用户id`
+fn parse_user(word: &str, platform: BridgeClientPlatform) -> Option<Identity> {
+    let word = word.trim_start_matches('@');
+    if word.contains(':') {
+        return word.parse().ok();
+    }
+    Some(Identity::new(platform, word.parse().ok()?))
+}
 
-                None// return
+/// 指令的回复
+#[derive(Debug)]
+pub struct Reply {
+    pub chain: MessageChain,
+    /// 私聊回复操作者，否则回复到指令来源的会话
+    pub private: bool,
+    /// 指令执行失败
+    pub failed: bool,
+}
+
+impl Reply {
+    /// 私聊回复
+    pub fn private(text: String) -> Self {
+        Reply {
+            private: true,
+            ..Reply::from(text)
+        }
+    }
+
+    /// 执行失败的回复
+    pub fn failed(text: String) -> Self {
+        Reply {
+            failed: true,
+            ..Reply::from(text)
+        }
+    }
+}
+
+impl From<String> for Reply {
+    fn from(text: String) -> Self {
+        Reply {
+            chain: vec![MessageContent::Plain { text }],
+            private: false,
+            failed: false,
+        }
+    }
+}
+
+/// 等待确认的绑定请求
+#[derive(Debug, Clone)]
+pub struct BindRequest {
+    /// 一次性绑定码
+    pub code: String,
+    /// 发起者
+    pub operator: User,
+    /// 发起者所在平台
+    pub platform: BridgeClientPlatform,
+    /// 另一平台上待确认的用户 id
+    pub target: u64,
+    /// 发起绑定的桥
+    pub bridge_config: BridgeConfig,
+    /// 发起时间（毫秒）
+    pub created_at: i64,
+}
+
+/// 绑定指令的处理结果
+#[derive(Debug)]
+pub enum BindStep {
+    /// 已发放绑定码，等待另一平台确认
+    Issued(BindRequest),
+    /// 另一平台已确认；返回发起时的请求
+    Confirmed(BindRequest),
+    /// 指令无效；附带回复内容
+    Rejected(String),
+}
+
+/// 绑定握手：一端发起 `!绑定 <对方id>` 取得绑定码，对方在另一平台发送 `!绑定 <绑定码>` 确认
+#[derive(Debug, Default)]
+pub struct BindCodes {
+    pending: Vec<BindRequest>,
+}
+
+impl BindCodes {
+    /// 处理一条绑定指令
+    /// - meta 指令
+    /// - arg 另一平台的用户 id 或绑定码
+    /// - bridge_config 指令所在的桥
+    /// - now 当前时间（毫秒）
+    pub fn handle(&mut self, meta: &CmdMeta, arg: &str, bridge_config: &BridgeConfig, now: i64) -> BindStep {
+        if meta.operator.id == 0 {
+            return BindStep::Rejected("无法识别用户id".to_string());
+        }
+        self.pending.retain(|r| now - r.created_at <= BIND_TIMEOUT);
+
+        // 确认
+        let code = arg.to_uppercase();
+        if let Some(i) = self.pending.iter().position(|r| r.code == code) {
+            let request = &self.pending[i];
+            if request.platform == meta.platform {
+                return BindStep::Rejected("请在另一平台确认绑定".to_string());
+            }
+            if request.target != meta.operator.id {
+                return BindStep::Rejected("该绑定码不是发给你的".to_string());
             }
-            _ => None,// return
+            return BindStep::Confirmed(self.pending.remove(i));
+        }
+
+        // 发起
+        let target: u64 = match arg.parse() {
+            Ok(id) => id,
+            Err(_) => return BindStep::Rejected("绑定码无效或已过期".to_string()),
+        };
+        // 同一用户重复发起时作废旧的绑定码
+        self.pending
+            .retain(|r| !(r.platform == meta.platform && r.operator.id == meta.operator.id));
+        let request = BindRequest {
+            code: self.new_code(),
+            operator: meta.operator.clone(),
+            platform: meta.platform,
+            target,
+            bridge_config: bridge_config.clone(),
+            created_at: now,
         };
+        self.pending.push(request.clone());
+        BindStep::Issued(request)
+    }
+
+    /// 移除并返回已过期的请求
+    pub fn expire(&mut self, now: i64) -> Vec<BindRequest> {
+        let (expired, pending) = self
+            .pending
+            .drain(..)
+            .partition(|r| now - r.created_at > BIND_TIMEOUT);
+        self.pending = pending;
+        expired
+    }
+
+    /// 作废绑定码
+    pub fn cancel(&mut self, code: &str) {
+        self.pending.retain(|r| r.code != code);
+    }
+
+    /// 等待确认的请求数
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// 生成未被占用的绑定码
+    fn new_code(&self) -> String {
+        loop {
+            let code: String = uuid::Uuid::new_v4().as_bytes()[..6]
+                .iter()
+                .map(|b| BIND_CODE_CHARS[*b as usize % BIND_CODE_CHARS.len()] as char)
+                .collect();
+            if !self.pending.iter().any(|r| r.code == code) {
+                return code;
+            }
+        }
+    }
+}
+
+/// 解析检索条件
+/// - 格式：`[关键词...] [user:用户] [from:YYYY-MM-DD] [to:YYYY-MM-DD]`
+/// - `to` 的日期包含当天
+pub fn search_query(text: &str) -> Result<SearchQuery, String> {
+    let mut query = SearchQuery {
+        limit: 10,
+        ..Default::default()
+    };
+    let mut keywords: Vec<&str> = vec![];
+    for token in text.split_whitespace() {
+        if let Some(user) = token.strip_prefix("user:") {
+            query.user = Some(user.to_string());
+        } else if let Some(date) = token.strip_prefix("from:") {
+            query.since = Some(day_start(date)?);
+        } else if let Some(date) = token.strip_prefix("to:") {
+            query.until = Some(day_start(date)? + 24 * 3600 * 1000);
+        } else {
+            keywords.push(token);
+        }
+    }
+    if !keywords.is_empty() {
+        query.keyword = Some(keywords.join(" "));
     }
-    None
+    if query.keyword.is_none() && query.user.is_none() {
+        return Err("缺少关键词或 user:用户".to_string());
+    }
+    Ok(query)
 }
 
+/// 取日期当天零点（本地时间，毫秒）
+fn day_start(date: &str) -> Result<i64, String> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| format!("日期格式错误: {}", date))?;
+    match Local.from_local_datetime(&date.and_hms(0, 0, 0)).earliest() {
+        Some(t) => Ok(t.timestamp_millis()),
+        None => Err(format!("日期格式错误: {}", date)),
+    }
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn searchQuery() {
+        let query = search_query("hello world user:dong from:2022-09-01 to:2022-09-01").unwrap();
+        assert_eq!(query.keyword.as_deref(), Some("hello world"));
+        assert_eq!(query.user.as_deref(), Some("dong"));
+        assert_eq!(query.until.unwrap() - query.since.unwrap(), 24 * 3600 * 1000);
+
+        assert!(search_query("").is_err());
+        assert!(search_query("hi from:2022/09/01").is_err());
+        assert!(search_query("user:abc").is_ok());
+    }
+
+    fn bind_meta(platform: BridgeClientPlatform, id: u64) -> CmdMeta {
+        CmdMeta {
+            operator: User {
+                name: format!("user{}", id),
+                avatar_url: None,
+                id,
+                permission: Permission::Member,
+            },
+            platform,
+        }
+    }
+
+    #[test]
+    fn bindHandshake() {
+        let bridge = crate::config::test_config().bridges[0].clone();
+        let mut codes = BindCodes::default();
+        let issued = match codes.handle(&bind_meta(BridgeClientPlatform::QQ, 123), "456", &bridge, 0) {
+            BindStep::Issued(r) => r,
+            step => panic!("{:?}", step),
+        };
+        assert_eq!(issued.code.len(), 6);
+        assert_eq!(issued.target, 456);
+
+        // 同平台、非目标用户不能确认
+        let confirm = issued.code.to_lowercase();
+        let step = codes.handle(&bind_meta(BridgeClientPlatform::QQ, 456), &confirm, &bridge, 1);
+        assert!(matches!(step, BindStep::Rejected(_)));
+        let step = codes.handle(&bind_meta(BridgeClientPlatform::Discord, 789), &confirm, &bridge, 1);
+        assert!(matches!(step, BindStep::Rejected(_)));
+
+        let step = codes.handle(&bind_meta(BridgeClientPlatform::Discord, 456), &confirm, &bridge, 2);
+        match step {
+            BindStep::Confirmed(r) => assert_eq!(r.operator.id, 123),
+            step => panic!("{:?}", step),
+        }
+        assert_eq!(codes.len(), 0);
+    }
+
+    fn chain(text: &str) -> MessageChain {
+        vec![MessageContent::Plain { text: text.to_string() }]
+    }
+
+    #[test]
+    fn targetUser() {
+        let qq = BridgeClientPlatform::QQ;
+        let dc = BridgeClientPlatform::Discord;
+        let user = |chain: &MessageChain, platform| parse(chain, "!", platform, Permission::Member).unwrap().map(|i| i.user("user"));
+        assert_eq!(user(&chain("!whois 123"), qq), Ok(Some(Identity::new(qq, 123))));
+        assert_eq!(user(&chain("!解绑 discord:456"), qq), Ok(Some(Identity::new(dc, 456))));
+        assert_eq!(user(&chain("!解绑"), qq), Ok(None));
+        assert!(user(&chain("!whois"), qq).unwrap_err().contains("缺少参数"));
+        assert!(user(&chain("!whois abc"), qq).unwrap_err().contains("无法识别用户"));
+        let mut at = chain("!whois ");
+        at.push(MessageContent::At { id: 789, name: "dong".to_string() });
+        assert_eq!(user(&at, dc), Ok(Some(Identity::new(dc, 789))));
+    }
+
+    #[test]
+    fn parseCmd() {
+        let qq = BridgeClientPlatform::QQ;
+        // 不是指令
+        assert!(parse(&chain("hello"), "!", qq, Permission::Member).is_none());
+        assert!(parse(&chain("!hello"), "!", qq, Permission::Member).is_none());
+        assert!(parse(&chain("/bind 1"), "!", qq, Permission::Member).is_none());
+
+        // 别名与前缀
+        let inv = parse(&chain("/BIND 456"), "/", qq, Permission::Member).unwrap().unwrap();
+        assert_eq!(inv.cmd, Bind);
+        assert_eq!(inv.text("arg"), Some("456"));
+        assert_eq!(inv.usage(), "用法: /绑定 <另一平台的用户id | 绑定码>");
+
+        // 余下的文本
+        let inv = parse(&chain("!昵称  Dong  Dong "), "!", qq, Permission::Member).unwrap().unwrap();
+        assert_eq!(inv.text("name"), Some("Dong Dong"));
+        let inv = parse(&chain("!nick"), "!", qq, Permission::Member).unwrap().unwrap();
+        assert_eq!(inv.text("name"), None);
+
+        // 用法错误
+        let err = parse(&chain("!我的绑定 123"), "!", qq, Permission::Member).unwrap().unwrap_err();
+        assert_eq!(err, "多余的参数: 123\n用法: !我的绑定");
+        let mut at = chain("!头像 ");
+        at.push(MessageContent::At { id: 1, name: "dong".to_string() });
+        assert!(parse(&at, "!", qq, Permission::Member).unwrap().is_err());
+    }
+
+    #[test]
+    fn permissionLevel() {
+        let mut config = crate::config::test_config();
+        config.admins = vec!["QQ:123".to_string()];
+        let mut user = User {
+            name: "dong".to_string(),
+            avatar_url: None,
+            id: 123,
+            permission: Permission::Member,
+        };
+        assert_eq!(permission(&config, BridgeClientPlatform::QQ, &user), Permission::Admin);
+        assert_eq!(permission(&config, BridgeClientPlatform::Discord, &user), Permission::Member);
+        user.permission = Permission::Manager;
+        assert_eq!(permission(&config, BridgeClientPlatform::Discord, &user), Permission::Manager);
+
+        let def = CmdDef {
+            cmd: Help,
+            names: &["test"],
+            args: &[],
+            about: "",
+            permission: Permission::Manager,
+            forward: ForwardMode::Mirror,
+        };
+        assert!(def.check("!", Permission::Admin).is_ok());
+        assert!(def.check("!", Permission::Manager).is_ok());
+        assert_eq!(def.check("!", Permission::Member).unwrap_err(), "权限不足: !test 需要管理者权限");
+    }
+
+    #[test]
+    fn forwardMode() {
+        let mut bridge = crate::config::test_config().bridges[0].clone();
+        let bind = lookup(&chain("!bind 123"), "!").unwrap();
+        let search = lookup(&chain("!搜索 abc"), "!").unwrap();
+        assert!(lookup(&chain("!hello"), "!").is_none());
+        assert_eq!(bind.forward_mode(&bridge), ForwardMode::Hide);
+        assert_eq!(search.forward_mode(&bridge), ForwardMode::Mirror);
+
+        bridge.cmdForward.default = Some(ForwardMode::OnSuccess);
+        bridge.cmdForward.commands.insert("SEARCH".to_string(), ForwardMode::Hide);
+        assert_eq!(bind.forward_mode(&bridge), ForwardMode::OnSuccess);
+        assert_eq!(search.forward_mode(&bridge), ForwardMode::Hide);
+
+        // 指令名优先于别名
+        bridge.cmdForward.commands.insert("搜索".to_string(), ForwardMode::Mirror);
+        assert_eq!(search.forward_mode(&bridge), ForwardMode::Mirror);
+    }
+
+    #[test]
+    fn helpText() {
+        let all = help("!", None, Permission::Member).unwrap();
+        for def in COMMANDS.iter().filter(|def| def.permission == Permission::Member) {
+            assert!(all.contains(&format!("!{}", def.names[0])));
+        }
+        // 只列出有权限使用的指令
+        assert!(!all.contains("!bridge"));
+        assert!(help("!", None, Permission::Manager).unwrap().contains("!bridge"));
+        let denied = parse(&chain("!bridge pause"), "!", BridgeClientPlatform::QQ, Permission::Member);
+        assert!(denied.unwrap().unwrap_err().starts_with("权限不足"));
+        let whois = help("#", Some("#查询"), Permission::Member).unwrap();
+        assert!(whois.contains("用法: #whois <用户>"));
+        assert!(whois.contains("#whois, #查询"));
+        assert!(help("!", Some("abc"), Permission::Member).is_err());
+    }
+
+    #[test]
+    fn bindTimeout() {
+        let bridge = crate::config::test_config().bridges[0].clone();
+        let mut codes = BindCodes::default();
+        codes.handle(&bind_meta(BridgeClientPlatform::QQ, 123), "456", &bridge, 0);
+        codes.handle(&bind_meta(BridgeClientPlatform::QQ, 123), "456", &bridge, 10);
+        assert_eq!(codes.len(), 1);
+        assert!(codes.expire(BIND_TIMEOUT).is_empty());
+        assert_eq!(codes.expire(BIND_TIMEOUT + 11).len(), 1);
+        assert!(matches!(
+            codes.handle(&bind_meta(BridgeClientPlatform::QQ, 1), "abc", &bridge, 0),
+            BindStep::Rejected(_)
+        ));
+    }
+}
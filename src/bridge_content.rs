@@ -0,0 +1,258 @@
+//! 桥消息内容与 mirai、serenity 消息类型之间的转换
+//!
+//! 转换只处理内容本身；跨平台的提及、回复等需要结合上下文，由适配器处理
+use mirai_rs::message::MessageContent as MiraiContent;
+use serenity::model::channel::{Attachment, Embed};
+use serenity::model::sticker::{StickerFormatType, StickerItem};
+use serenity::model::user::User;
+
+use crate::bridge::{ForwardNode, MessageContent};
+use crate::bridge_format;
+
+impl TryFrom<MiraiContent> for MessageContent {
+    type Error = String;
+
+    fn try_from(content: MiraiContent) -> Result<Self, Self::Error> {
+        let content = match content {
+            MiraiContent::Plain { text } => MessageContent::Plain { text },
+            MiraiContent::At { target, display } => {
+                let name = match display {
+                    Some(name) if !name.is_empty() => name.trim_start_matches('@').to_string(),
+                    _ => target.to_string(),
+                };
+                MessageContent::At { id: target, name }
+            }
+            MiraiContent::AtAll {} => MessageContent::AtAll,
+            MiraiContent::Face { face_id, name } => MessageContent::Face {
+                id: face_id.unwrap_or_default(),
+                name: name.unwrap_or_default(),
+            },
+            MiraiContent::Quote { id, .. } => MessageContent::Reply { message_id: id.to_string() },
+            MiraiContent::Image { url, .. } | MiraiContent::FlashImage { url, .. } => MessageContent::Image { url },
+            MiraiContent::Voice { url, length, .. } => MessageContent::Voice { url, length },
+            MiraiContent::File { id, name, size } => MessageContent::File {
+                name,
+                size: size as u64,
+                url: None,
+                id: Some(id),
+            },
+            MiraiContent::MusicShare { title, summary, jump_url, picture_url, .. } => MessageContent::LinkPreview {
+                url: jump_url,
+                title: Some(title),
+                description: Some(summary),
+                image: Some(picture_url),
+            },
+            MiraiContent::Dice { value } => MessageContent::Plain { text: format!("[骰子: {}]", value) },
+            MiraiContent::ForwardMessage { sender_id, time, sender_name, message_chain, .. } => MessageContent::Forward {
+                nodes: vec![ForwardNode {
+                    sender_id,
+                    sender_name,
+                    time: time as i64,
+                    chain: message_chain.into_iter().filter_map(|c| c.try_into().ok()).collect(),
+                }],
+            },
+            other => return Err(format!("不支持的 mirai 消息内容: {:?}", other)),
+        };
+        Ok(content)
+    }
+}
+
+impl TryFrom<&MessageContent> for MiraiContent {
+    type Error = String;
+
+    fn try_from(content: &MessageContent) -> Result<Self, Self::Error> {
+        let content = match content {
+            MessageContent::Plain { text } => MiraiContent::Plain { text: text.clone() },
+            MessageContent::At { id, .. } => MiraiContent::At { target: *id, display: None },
+            MessageContent::AtAll => MiraiContent::AtAll {},
+            MessageContent::Face { id, name } => MiraiContent::Face {
+                face_id: Some(*id),
+                name: Some(name.clone()),
+            },
+            MessageContent::Image { url } => MiraiContent::Image {
+                image_id: None,
+                url: url.clone(),
+                path: None,
+                base64: None,
+            },
+            MessageContent::Voice { url, length } => MiraiContent::Voice {
+                voice_id: None,
+                url: url.clone(),
+                path: None,
+                base64: None,
+                length: *length,
+            },
+            // qq 没有对应的格式，以纯文本显示
+            MessageContent::Styled { .. }
+            | MessageContent::Code { .. }
+            | MessageContent::ChannelMention { .. }
+            | MessageContent::RoleMention { .. }
+            | MessageContent::Emoji { .. }
+            | MessageContent::LinkPreview { .. } => MiraiContent::Plain {
+                text: bridge_format::plain_content(content),
+            },
+            // 回复在发送时指定，文件需要上传
+            other => return Err(format!("不能直接转为 mirai 消息内容: {:?}", other)),
+        };
+        Ok(content)
+    }
+}
+
+impl From<&Attachment> for MessageContent {
+    fn from(attachment: &Attachment) -> Self {
+        let kind = attachment.content_type.as_deref().unwrap_or_default();
+        let url = attachment.url.clone();
+        if kind.starts_with("image/") {
+            MessageContent::Image { url: Some(url) }
+        } else if kind.starts_with("audio/") {
+            MessageContent::Voice { url: Some(url), length: None }
+        } else if kind.starts_with("video/") {
+            MessageContent::Video { url, name: Some(attachment.filename.clone()) }
+        } else {
+            MessageContent::File {
+                name: attachment.filename.clone(),
+                size: attachment.size,
+                url: Some(url),
+                id: Some(attachment.id.to_string()),
+            }
+        }
+    }
+}
+
+impl From<&StickerItem> for MessageContent {
+    /// 图片贴纸作为图片；动画（lottie）贴纸其他平台无法显示，以名称代替
+    fn from(sticker: &StickerItem) -> Self {
+        match (sticker.format_type, sticker.image_url()) {
+            (StickerFormatType::Png | StickerFormatType::Apng, Some(url)) => MessageContent::Image { url: Some(url) },
+            _ => MessageContent::Plain { text: format!("[贴纸 {}]", sticker.name) },
+        }
+    }
+}
+
+impl From<&Embed> for MessageContent {
+    fn from(embed: &Embed) -> Self {
+        MessageContent::LinkPreview {
+            url: embed.url.clone().unwrap_or_default(),
+            title: embed.title.clone(),
+            description: embed.description.clone(),
+            image: embed
+                .image
+                .as_ref()
+                .map(|i| i.url.clone())
+                .or_else(|| embed.thumbnail.as_ref().map(|t| t.url.clone())),
+        }
+    }
+}
+
+impl From<&User> for MessageContent {
+    fn from(user: &User) -> Self {
+        MessageContent::At { id: user.id.0, name: user.name.clone() }
+    }
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+    use crate::bridge::TextStyle;
+
+    /// 各种内容都能序列化后原样解析回来
+    #[test]
+    fn serdeRoundTrip() {
+        let chain = vec![
+            MessageContent::Plain { text: "hi".to_string() },
+            MessageContent::Image { url: Some("https://a.com/1.png".to_string()) },
+            MessageContent::At { id: 1, name: "dong".to_string() },
+            MessageContent::Styled {
+                style: TextStyle::Spoiler,
+                chain: vec![MessageContent::Code { text: "x".to_string(), language: None, block: false }],
+            },
+            MessageContent::ChannelMention { id: 2, name: "general".to_string() },
+            MessageContent::RoleMention { id: 3, name: "mod".to_string() },
+            MessageContent::Emoji { id: 4, name: "kek".to_string(), animated: true },
+            MessageContent::AtAll,
+            MessageContent::Face { id: 14, name: "微笑".to_string() },
+            MessageContent::Reply { message_id: "5".to_string() },
+            MessageContent::File { name: "a.zip".to_string(), size: 10, url: None, id: Some("/abc".to_string()) },
+            MessageContent::Voice { url: Some("https://a.com/v.amr".to_string()), length: Some(3) },
+            MessageContent::Video { url: "https://a.com/v.mp4".to_string(), name: None },
+            MessageContent::LinkPreview {
+                url: "https://a.com".to_string(),
+                title: Some("a".to_string()),
+                description: None,
+                image: None,
+            },
+            MessageContent::Forward {
+                nodes: vec![ForwardNode {
+                    sender_id: 6,
+                    sender_name: "li".to_string(),
+                    time: 1_600_000_000,
+                    chain: vec![MessageContent::Plain { text: "old".to_string() }],
+                }],
+            },
+        ];
+        let json = serde_json::to_string(&chain).unwrap();
+        let parsed: Vec<MessageContent> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, chain);
+        // 旧版本归档的内容仍能解析
+        let old: MessageContent = serde_json::from_str(r#"{"type":"File","name":"a","size":1}"#).unwrap();
+        assert_eq!(old, MessageContent::File { name: "a".to_string(), size: 1, url: None, id: None });
+    }
+
+    #[test]
+    fn miraiConversion() {
+        let content: MessageContent = MiraiContent::At { target: 123, display: Some("@dong".to_string()) }
+            .try_into()
+            .unwrap();
+        assert_eq!(content, MessageContent::At { id: 123, name: "dong".to_string() });
+        let content: MessageContent = MiraiContent::Face { face_id: Some(14), name: None }.try_into().unwrap();
+        assert_eq!(content, MessageContent::Face { id: 14, name: String::new() });
+        let content: MessageContent = MiraiContent::File { id: "/f".to_string(), name: "a.zip".to_string(), size: 9 }
+            .try_into()
+            .unwrap();
+        assert!(matches!(content, MessageContent::File { size: 9, .. }));
+        assert!(MessageContent::try_from(MiraiContent::Source { id: 1, time: 0 }).is_err());
+
+        let styled = MessageContent::Styled {
+            style: TextStyle::Bold,
+            chain: vec![MessageContent::Plain { text: "b".to_string() }],
+        };
+        match MiraiContent::try_from(&styled).unwrap() {
+            MiraiContent::Plain { text } => assert_eq!(text, "b"),
+            other => panic!("{:?}", other),
+        }
+        let face = MiraiContent::try_from(&MessageContent::Face { id: 14, name: "微笑".to_string() }).unwrap();
+        assert!(matches!(face, MiraiContent::Face { face_id: Some(14), .. }));
+        assert!(MiraiContent::try_from(&MessageContent::Reply { message_id: "1".to_string() }).is_err());
+    }
+
+    #[test]
+    fn serenityConversion() {
+        let attachment = |name: &str, kind: &str| -> Attachment {
+            serde_json::from_value(serde_json::json!({
+                "id": "9", "filename": name, "size": 100, "url": format!("https://cdn/{}", name),
+                "proxy_url": "", "content_type": kind,
+            }))
+            .unwrap()
+        };
+        let content = MessageContent::from(&attachment("a.png", "image/png"));
+        assert_eq!(content, MessageContent::Image { url: Some("https://cdn/a.png".to_string()) });
+        let content = MessageContent::from(&attachment("a.ogg", "audio/ogg"));
+        assert!(matches!(content, MessageContent::Voice { .. }));
+        let content = MessageContent::from(&attachment("a.zip", "application/zip"));
+        assert!(matches!(&content, MessageContent::File { size: 100, url: Some(_), .. }));
+
+        let sticker = |format: u8| -> StickerItem {
+            serde_json::from_value(serde_json::json!({"id": "7", "name": "wave", "format_type": format})).unwrap()
+        };
+        assert!(matches!(MessageContent::from(&sticker(1)), MessageContent::Image { url: Some(u) } if u.ends_with("/stickers/7.png")));
+        assert_eq!(MessageContent::from(&sticker(3)), MessageContent::Plain { text: "[贴纸 wave]".to_string() });
+
+        let embed: Embed = serde_json::from_value(serde_json::json!({
+            "type": "rich", "url": "https://a.com", "title": "a", "fields": [],
+        }))
+        .unwrap();
+        let content = MessageContent::from(&embed);
+        assert!(matches!(&content, MessageContent::LinkPreview { title: Some(t), .. } if t == "a"));
+    }
+}
@@ -1,184 +1,820 @@
-
-use std::io::{Read, Write};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::fs::OpenOptions;
-use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
 use serde_json::{from_str, to_string};
+use tracing::{error, warn};
 
-const BIND_MAP_PATH: &str = "./data/BindMap.json";
+use crate::bridge::BridgeClientPlatform;
 
-/// 绑定映射
-pub mod bind_map {
-    use crate::bridge_data::*;
+const PERSONS_PATH: &str = "./data/Persons.json";
+const BIND_DB_PATH: &str = "./data/BindMap.db";
 
-    /// 尝试获取映射
-    /// - `user` 目标用户；桥内通用的 user name
-    pub fn get_bind(user: &str) -> Option<String> {
-        let map = load();
-        if let Some(u) = map.get(user) {
-            return Some(u.clone());
-        }
-        None
+/// 先写临时文件再重命名，写入中断不会损坏原文件
+pub fn write_atomic(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+/// 平台身份：平台 + 平台上稳定的用户 id
+/// - 文本形式为 `平台:用户id`，如 `QQ:123`、`Discord:456`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Identity {
+    pub platform: BridgeClientPlatform,
+    pub id: u64,
+}
+
+impl Identity {
+    pub fn new(platform: BridgeClientPlatform, id: u64) -> Self {
+        Identity { platform, id }
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.platform.as_str(), self.id)
+    }
+}
+
+impl FromStr for Identity {
+    type Err = String;
+
+    /// 平台名不区分大小写
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("无效的身份: {}, 格式应为 QQ:<qq号> 或 Discord:<用户id>", s);
+        let (platform, id) = s.split_once(':').ok_or_else(err)?;
+        let platform = [BridgeClientPlatform::QQ, BridgeClientPlatform::Discord]
+            .into_iter()
+            .find(|p| p.as_str().eq_ignore_ascii_case(platform))
+            .ok_or_else(err)?;
+        let id = id.parse().map_err(|_| err())?;
+        Ok(Identity { platform, id })
     }
+}
+
+/// 一个人及其拥有的全部平台身份
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Person {
+    pub id: String,
+    pub identities: BTreeSet<Identity>,
+}
 
-    /// 添加映射
-    /// - `user1`, `user2` 一对映射；桥内通用的 user name
-    pub fn add_bind(user1: &str, user2: &str) {
-        let mut map = load();
-        let len = map.len();
+/// 绑定关系：每个身份至多属于一个人，每个人至少拥有两个身份
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bindings {
+    persons: Vec<Person>,
+}
+
+impl Bindings {
+    pub fn from_persons(persons: Vec<Person>) -> Self {
+        Bindings { persons }
+    }
 
-        if !map.contains_key(user1) {
-            map.insert(user1.to_string(), user2.to_string());
+    /// 由旧版一对一映射转换；无法识别为身份的条目被丢弃
+    pub fn from_legacy(map: &HashMap<String, String>) -> Self {
+        let mut bindings = Bindings::default();
+        let mut pairs: Vec<(&String, &String)> = map.iter().collect();
+        pairs.sort();
+        for (u1, u2) in pairs {
+            match (u1.parse(), u2.parse()) {
+                (Ok(a), Ok(b)) => {
+                    bindings.merge(a, b);
+                }
+                _ => warn!("丢弃无法识别的绑定: {} -> {}", u1, u2),
+            }
         }
-        if !map.contains_key(user2) {
-            map.insert(user2.to_string(), user1.to_string());
+        bindings
+    }
+
+    pub fn persons(&self) -> &[Person] {
+        &self.persons
+    }
+
+    /// 查找身份所属的人
+    pub fn person_of(&self, identity: &Identity) -> Option<&Person> {
+        self.persons.iter().find(|p| p.identities.contains(identity))
+    }
+
+    /// 与身份属于同一个人的其他身份
+    pub fn bound(&self, identity: &Identity) -> Vec<Identity> {
+        match self.person_of(identity) {
+            Some(person) => person.identities.iter().filter(|i| *i != identity).copied().collect(),
+            None => vec![],
         }
+    }
 
-        if len < map.len() {
-            save(&map);
+    /// 将两个身份归为同一个人；二者已分属不同的人时合并这两个人
+    /// - 返回绑定关系是否有变化
+    pub fn merge(&mut self, a: Identity, b: Identity) -> bool {
+        if a == b {
+            return false;
         }
+        let pa = self.persons.iter().position(|p| p.identities.contains(&a));
+        let pb = self.persons.iter().position(|p| p.identities.contains(&b));
+        match (pa, pb) {
+            (Some(pa), Some(pb)) if pa == pb => return false,
+            (Some(pa), Some(pb)) => {
+                let other = self.persons.remove(pb);
+                let pa = if pb < pa { pa - 1 } else { pa };
+                self.persons[pa].identities.extend(other.identities);
+            }
+            (Some(pa), None) => {
+                self.persons[pa].identities.insert(b);
+            }
+            (None, Some(pb)) => {
+                self.persons[pb].identities.insert(a);
+            }
+            (None, None) => self.persons.push(Person {
+                id: uuid::Uuid::new_v4().to_string(),
+                identities: [a, b].into_iter().collect(),
+            }),
+        }
+        true
     }
 
-    /// 指定一对用户删除映射
-    /// - `user1`, `user2` 一对映射；移除映射需成对操作；桥内通用的 user name
-    pub fn rm_bind_pair(user1: &str, user2: &str) {
-        let mut map = load();
-        let len = map.len();
+    /// 并入另一份绑定关系
+    pub fn absorb(&mut self, other: &Bindings) {
+        for person in &other.persons {
+            let list: Vec<&Identity> = person.identities.iter().collect();
+            for pair in list.windows(2) {
+                self.merge(*pair[0], *pair[1]);
+            }
+        }
+    }
 
-        map.retain(|u1, u2|
-            !((u1 == user1 && u2 == user2) || (u1 == user2 && u2 == user1)));
-        if len > map.len() {
-            save(&map);
+    /// 将身份从所属的人中分离；剩余不足两个身份时移除这个人
+    /// - 返回绑定关系是否有变化
+    pub fn split(&mut self, identity: &Identity) -> bool {
+        let i = match self.persons.iter().position(|p| p.identities.contains(identity)) {
+            Some(i) => i,
+            None => return false,
+        };
+        self.persons[i].identities.remove(identity);
+        if self.persons[i].identities.len() < 2 {
+            self.persons.remove(i);
         }
+        true
     }
+}
+
+/// 绑定数据的存储方式
+pub mod store {
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    use fs2::FileExt;
+    use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+    use crate::bridge_data::*;
+    use crate::config::{BindStoreBackend, BindStoreConfig};
+
+    pub type StoreResult<T> = Result<T, Box<dyn std::error::Error>>;
 
-    /// 指定用户删除其所有关联映射
-    /// - `user` 目标用户；桥内通用的 user name
-    pub fn rm_user_all_bind(user: &str) {
-        let mut map = load();
-        let len = map.len();
+    /// 绑定数据的存储；每次保存完整的绑定关系
+    pub trait BindStore: Send + Sync {
+        fn load(&self) -> StoreResult<Vec<Person>>;
+        fn save(&self, persons: &[Person]) -> StoreResult<()>;
+        /// 加锁后读取最新数据、修改并写回，不会覆盖其他进程的修改
+        /// - f 返回是否有变化，没有变化时不写入
+        /// - 返回修改后的完整绑定关系
+        fn update(&self, f: &mut dyn FnMut(&mut Bindings) -> bool) -> StoreResult<Vec<Person>>;
+    }
 
-        map.retain(|u1, u2| !(u1 == user || u2 == user));
-        if len > map.len() {
-            save(&map);
+    /// 配置的数据文件；未配置时按存储方式使用默认路径
+    pub fn path(config: &BindStoreConfig) -> PathBuf {
+        let default = match config.backend {
+            BindStoreBackend::Json => PERSONS_PATH,
+            BindStoreBackend::Sqlite => BIND_DB_PATH,
+        };
+        PathBuf::from(config.path.as_deref().unwrap_or(default))
+    }
+
+    /// 按配置打开存储
+    pub fn open(config: &BindStoreConfig) -> StoreResult<Box<dyn BindStore>> {
+        let store: Box<dyn BindStore> = match config.backend {
+            BindStoreBackend::Json => Box::new(JsonStore::new(path(config))),
+            BindStoreBackend::Sqlite => Box::new(SqliteStore::open(path(config))?),
+        };
+        Ok(store)
+    }
+
+    /// JSON 文件存储
+    /// - 读写时对 `<文件>.lock` 加锁，避免多个进程同时写入
+    /// - 先写临时文件再重命名，写入中断不会损坏原文件
+    pub struct JsonStore {
+        path: PathBuf,
+    }
+
+    impl JsonStore {
+        pub fn new(path: impl AsRef<Path>) -> Self {
+            JsonStore {
+                path: path.as_ref().to_path_buf(),
+            }
+        }
+
+        fn lock_file(&self) -> StoreResult<File> {
+            if let Some(dir) = self.path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let mut name = self.path.clone().into_os_string();
+            name.push(".lock");
+            Ok(OpenOptions::new().create(true).truncate(false).write(true).open(name)?)
+        }
+
+        /// 读取文件；调用方需持有锁
+        fn read(&self) -> StoreResult<Vec<Person>> {
+            let json = match std::fs::read_to_string(&self.path) {
+                Ok(json) => json,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(e.into()),
+            };
+            if json.trim().is_empty() {
+                return Ok(vec![]);
+            }
+            Ok(from_str(&json)?)
+        }
+
+        /// 写入文件；调用方需持有锁
+        fn write(&self, persons: &[Person]) -> StoreResult<()> {
+            let json = to_string(persons)?;
+            Ok(write_atomic(&self.path, json.as_bytes())?)
         }
     }
 
-    /// 读取，加载本地数据
-    /// TODO 构建上下文，减少侵入
-    fn load() -> HashMap<String, String> {
-        let mut json = String::new();
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(BIND_MAP_PATH);
-        match file {
-            Ok(mut f) => {
-                if let Err(e) = f.read_to_string(&mut json) {
-                    println!("Can not read file({}); {:#?}", BIND_MAP_PATH, e);
+    impl BindStore for JsonStore {
+        fn load(&self) -> StoreResult<Vec<Person>> {
+            let lock = self.lock_file()?;
+            lock.lock_shared()?;
+            let result = self.read();
+            lock.unlock()?;
+            result
+        }
+
+        fn save(&self, persons: &[Person]) -> StoreResult<()> {
+            let lock = self.lock_file()?;
+            lock.lock_exclusive()?;
+            let result = self.write(persons);
+            lock.unlock()?;
+            result
+        }
+
+        fn update(&self, f: &mut dyn FnMut(&mut Bindings) -> bool) -> StoreResult<Vec<Person>> {
+            let lock = self.lock_file()?;
+            lock.lock_exclusive()?;
+            let result = self.read().and_then(|persons| {
+                let mut bindings = Bindings::from_persons(persons);
+                if f(&mut bindings) {
+                    self.write(bindings.persons())?;
                 }
+                Ok(bindings.persons)
+            });
+            lock.unlock()?;
+            result
+        }
+    }
+
+    /// SQLite 存储；保存在一个事务中完成
+    pub struct SqliteStore {
+        conn: Mutex<Connection>,
+    }
+
+    impl SqliteStore {
+        /// 打开数据库；旧版的一对一映射表会被转换后删除
+        pub fn open(path: impl AsRef<Path>) -> StoreResult<Self> {
+            if let Some(dir) = path.as_ref().parent() {
+                std::fs::create_dir_all(dir)?;
             }
-            Err(e) => println!("Can not open/create data file({}); {:#?}", BIND_MAP_PATH, e),
-        };
+            let conn = Connection::open(path)?;
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS identities (
+                    platform TEXT NOT NULL,
+                    id       INTEGER NOT NULL,
+                    person   TEXT NOT NULL,
+                    PRIMARY KEY (platform, id)
+                );",
+            )?;
+            let store = SqliteStore {
+                conn: Mutex::new(conn),
+            };
+            store.upgrade_legacy()?;
+            Ok(store)
+        }
 
-        if !json.is_empty() {
-            match from_str(&json.as_str()) {
-                Ok(map) => {
-                    return map;
+        fn upgrade_legacy(&self) -> StoreResult<()> {
+            let legacy = {
+                let conn = self.conn.lock().map_err(|_| "bind store lock poisoned")?;
+                let exists: Option<String> = conn
+                    .query_row(
+                        "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'binds'",
+                        [],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if exists.is_none() {
+                    return Ok(());
                 }
-                Err(e) => println!("BindMap load fail, data can not be parsed; {:#?}", e),
+                let mut stmt = conn.prepare("SELECT user, other FROM binds")?;
+                let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+                let mut map = HashMap::new();
+                for row in rows {
+                    let (user, other): (String, String) = row?;
+                    map.insert(user, other);
+                }
+                map
+            };
+            let mut bindings = Bindings::from_persons(self.load()?);
+            bindings.absorb(&Bindings::from_legacy(&legacy));
+            self.save(bindings.persons())?;
+            let conn = self.conn.lock().map_err(|_| "bind store lock poisoned")?;
+            conn.execute_batch("DROP TABLE binds")?;
+            Ok(())
+        }
+    }
+
+    /// 读取全部身份
+    fn read_identities(conn: &Connection) -> StoreResult<Vec<Person>> {
+        let mut stmt = conn.prepare("SELECT platform, id, person FROM identities ORDER BY person")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, String>(2)?))
+        })?;
+        let mut persons: Vec<Person> = vec![];
+        for row in rows {
+            let (platform, id, person) = row?;
+            let identity: Identity = format!("{}:{}", platform, id as u64).parse()?;
+            match persons.last_mut() {
+                Some(last) if last.id == person => {
+                    last.identities.insert(identity);
+                }
+                _ => persons.push(Person {
+                    id: person,
+                    identities: [identity].into_iter().collect(),
+                }),
             }
         }
-        HashMap::new()
+        Ok(persons)
     }
 
-    /// 数据写入本地
-    /// TODO 异步读写
-    fn save(data: &HashMap<String, String>) {
-        let raw: String;
-        match to_string(data) {
-            Ok(json) => {
-                raw = json;
+    /// 以新的绑定关系替换全部身份
+    fn write_identities(conn: &Connection, persons: &[Person]) -> StoreResult<()> {
+        conn.execute("DELETE FROM identities", [])?;
+        let mut stmt = conn.prepare("INSERT INTO identities (platform, id, person) VALUES (?1, ?2, ?3)")?;
+        for person in persons {
+            for identity in &person.identities {
+                stmt.execute(params![identity.platform.as_str(), identity.id as i64, person.id])?;
             }
-            Err(e) => {
-                println!("Fail to parse BindMap to JSON; {:#?}", e);
-                return;
+        }
+        Ok(())
+    }
+
+    impl BindStore for SqliteStore {
+        fn load(&self) -> StoreResult<Vec<Person>> {
+            let conn = self.conn.lock().map_err(|_| "bind store lock poisoned")?;
+            read_identities(&conn)
+        }
+
+        fn save(&self, persons: &[Person]) -> StoreResult<()> {
+            let mut conn = self.conn.lock().map_err(|_| "bind store lock poisoned")?;
+            let tx = conn.transaction()?;
+            write_identities(&tx, persons)?;
+            tx.commit()?;
+            Ok(())
+        }
+
+        fn update(&self, f: &mut dyn FnMut(&mut Bindings) -> bool) -> StoreResult<Vec<Person>> {
+            let mut conn = self.conn.lock().map_err(|_| "bind store lock poisoned")?;
+            // 立即取得写锁，读取与写回之间其他进程不能写入
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let mut bindings = Bindings::from_persons(read_identities(&tx)?);
+            if !f(&mut bindings) {
+                return Ok(bindings.persons);
             }
+            write_identities(&tx, bindings.persons())?;
+            // 与 load 的顺序一致
+            let persons = read_identities(&tx)?;
+            tx.commit()?;
+            Ok(persons)
         }
+    }
 
-        let file = OpenOptions::new()
-            .truncate(true)
-            .write(true)
-            .create(true)
-            .open(BIND_MAP_PATH);
-        match file {
-            Ok(mut f) => {
-                if let Err(e) = f.write_all(raw.as_bytes()) {
-                    println!("Can not write to file({}); {:#?}", BIND_MAP_PATH, e);
-                }
+    #[cfg(test)]
+    mod ts_store {
+        use crate::bridge_data::store::*;
+
+        fn round_trip(store: &dyn BindStore) {
+            assert!(store.load().unwrap().is_empty());
+            let mut bindings = Bindings::default();
+            let qq = Identity::new(BridgeClientPlatform::QQ, 1);
+            bindings.merge(qq, Identity::new(BridgeClientPlatform::Discord, 2));
+            bindings.merge(qq, Identity::new(BridgeClientPlatform::Discord, 3));
+            store.save(bindings.persons()).unwrap();
+            assert_eq!(store.load().unwrap(), bindings.persons());
+            bindings.split(&qq);
+            store.save(bindings.persons()).unwrap();
+            assert_eq!(store.load().unwrap(), bindings.persons());
+        }
+
+        #[test]
+        fn json() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("Persons.json");
+            round_trip(&JsonStore::new(&path));
+            assert!(!dir.path().join("Persons.json.tmp").exists());
+        }
+
+        #[test]
+        fn sqlite() {
+            let dir = tempfile::tempdir().unwrap();
+            round_trip(&SqliteStore::open(dir.path().join("BindMap.db")).unwrap());
+        }
+
+        /// 两个进程各自打开同一存储并修改，后写入的一方不覆盖先写入的
+        fn concurrent_update(a: &dyn BindStore, b: &dyn BindStore) {
+            let qq = |id| Identity::new(BridgeClientPlatform::QQ, id);
+            let dc = |id| Identity::new(BridgeClientPlatform::Discord, id);
+            a.update(&mut |bindings| bindings.merge(qq(1), dc(2))).unwrap();
+            let persons = b.update(&mut |bindings| bindings.merge(qq(3), dc(4))).unwrap();
+            assert_eq!(persons.len(), 2);
+            assert_eq!(a.load().unwrap(), persons);
+            // 没有变化时不写入
+            assert_eq!(b.update(&mut |_| false).unwrap(), persons);
+        }
+
+        #[test]
+        fn json_update() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("Persons.json");
+            concurrent_update(&JsonStore::new(&path), &JsonStore::new(&path));
+        }
+
+        #[test]
+        fn sqlite_update() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("BindMap.db");
+            concurrent_update(&SqliteStore::open(&path).unwrap(), &SqliteStore::open(&path).unwrap());
+        }
+
+        #[test]
+        fn sqlite_legacy() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("BindMap.db");
+            {
+                let conn = Connection::open(&path).unwrap();
+                conn.execute_batch(
+                    "CREATE TABLE binds (user TEXT PRIMARY KEY, other TEXT NOT NULL);
+                     INSERT INTO binds VALUES ('QQ:1', 'Discord:2'), ('Discord:2', 'QQ:1');",
+                )
+                .unwrap();
+            }
+            let store = SqliteStore::open(&path).unwrap();
+            let persons = store.load().unwrap();
+            assert_eq!(persons.len(), 1);
+            assert_eq!(persons[0].identities.len(), 2);
+            // 再次打开不重复转换
+            assert_eq!(SqliteStore::open(&path).unwrap().load().unwrap(), persons);
+        }
+    }
+}
+
+/// 绑定关系
+/// - 保存在内存中，修改后由后台线程写入存储
+pub mod bind_map {
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Mutex, RwLock};
+
+    use once_cell::sync::{Lazy, OnceCell};
+
+    use crate::bridge_data::store::{BindStore, JsonStore, StoreResult};
+    use crate::bridge_data::*;
+    use crate::bridge_metrics::METRICS;
+
+    static STORE: OnceCell<Box<dyn BindStore>> = OnceCell::new();
+    static INDEX: OnceCell<RwLock<Bindings>> = OnceCell::new();
+    static WRITER: Lazy<Mutex<Sender<Job>>> = Lazy::new(|| Mutex::new(writer()));
+
+    /// 后台写入请求
+    enum Job {
+        Update(Op),
+        Flush(Sender<()>),
+    }
+
+    /// 对绑定关系的一次修改；写入时在存储的最新数据上重放
+    #[derive(Clone, Copy)]
+    enum Op {
+        Merge(Identity, Identity),
+        Split(Identity),
+    }
+
+    impl Op {
+        fn apply(self, bindings: &mut Bindings) -> bool {
+            match self {
+                Op::Merge(a, b) => bindings.merge(a, b),
+                Op::Split(identity) => bindings.split(&identity),
+            }
+        }
+    }
+
+    /// 指定存储并加载绑定关系；需在首次读写绑定关系前调用
+    /// - 读取失败时返回错误，不以空数据启动，避免之后的写入覆盖原有数据
+    pub fn init(store: Box<dyn BindStore>) -> StoreResult<()> {
+        let persons = store.load()?;
+        STORE.set(store).map_err(|_| "bind store already initialized")?;
+        let bindings = Bindings::from_persons(persons);
+        report_size(&bindings);
+        INDEX
+            .set(RwLock::new(bindings))
+            .map_err(|_| "bind store already initialized")?;
+        Ok(())
+    }
+
+    /// 等待已修改的绑定关系写入存储
+    pub fn flush() {
+        let (tx, rx) = mpsc::channel();
+        if send(Job::Flush(tx)) {
+            let _ = rx.recv();
+        }
+    }
+
+    /// 查找身份所属的人
+    pub fn person_of(identity: &Identity) -> Option<Person> {
+        let bindings = index().read().ok()?;
+        bindings.person_of(identity).cloned()
+    }
+
+    /// 与身份属于同一个人的其他身份
+    pub fn bound(identity: &Identity) -> Vec<Identity> {
+        match index().read() {
+            Ok(bindings) => bindings.bound(identity),
+            Err(_) => vec![],
+        }
+    }
+
+    /// 取全部绑定关系
+    pub fn all() -> Vec<Person> {
+        match index().read() {
+            Ok(bindings) => bindings.persons().to_vec(),
+            Err(_) => vec![],
+        }
+    }
+
+    /// 将两个身份归为同一个人
+    /// - 返回绑定关系是否有变化
+    pub fn merge(a: Identity, b: Identity) -> bool {
+        modify(Op::Merge(a, b))
+    }
+
+    /// 将身份从所属的人中分离
+    /// - 返回绑定关系是否有变化
+    pub fn split(identity: &Identity) -> bool {
+        modify(Op::Split(*identity))
+    }
+
+    /// 修改绑定关系；有变化时交给后台写入
+    fn modify(op: Op) -> bool {
+        modify_in(index(), op, send)
+    }
+
+    /// 在索引上修改，有变化时在持有索引锁时提交写入请求
+    /// - 写入线程持有索引锁时，通道中的请求即为全部尚未写入的修改
+    fn modify_in(index: &RwLock<Bindings>, op: Op, send: impl FnOnce(Job) -> bool) -> bool {
+        let mut bindings = match index.write() {
+            Ok(bindings) => bindings,
+            Err(_) => {
+                error!("BindMap lock poisoned");
+                return false;
             }
-            Err(e) => println!("Can not open/create data file({}); {:#?}", BIND_MAP_PATH, e),
         };
+        let changed = op.apply(&mut bindings);
+        if changed {
+            report_size(&bindings);
+            send(Job::Update(op));
+        }
+        changed
+    }
+
+    fn store() -> &'static dyn BindStore {
+        STORE.get_or_init(|| Box::new(JsonStore::new(PERSONS_PATH))).as_ref()
+    }
+
+    /// 内存索引；未调用 init 时从默认存储加载
+    fn index() -> &'static RwLock<Bindings> {
+        INDEX.get_or_init(|| {
+            let bindings = match store().load() {
+                Ok(persons) => Bindings::from_persons(persons),
+                Err(e) => {
+                    error!("BindMap load fail; {:#?}", e);
+                    Bindings::default()
+                }
+            };
+            report_size(&bindings);
+            RwLock::new(bindings)
+        })
+    }
+
+    /// 更新内存索引的条目数指标
+    fn report_size(bindings: &Bindings) {
+        METRICS.cache_size.with_label_values(&["bind_map"]).set(bindings.persons().len() as i64);
+    }
+
+    fn send(job: Job) -> bool {
+        match WRITER.lock() {
+            Ok(tx) => tx.send(job).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// 后台写入线程；连续的修改合并为一次读改写
+    fn writer() -> Sender<Job> {
+        let (tx, rx) = mpsc::channel::<Job>();
+        std::thread::spawn(move || {
+            let mut pending = vec![];
+            loop {
+                if pending.is_empty() {
+                    match rx.recv() {
+                        Ok(job) => pending.push(job),
+                        Err(_) => break,
+                    }
+                }
+                write_batch(store(), index(), &rx, &mut pending);
+            }
+        });
+        tx
+    }
+
+    /// 写入一批修改：pending 与通道中已有的请求
+    /// - 在存储加锁后读到的最新数据上重放修改，不会覆盖其他进程（如 cli bind）的写入
+    /// - 写入后以存储中的结果刷新内存索引；写入期间的修改不在结果中，
+    ///   在刷新后的索引上重放，并留在 pending 中由下一批写入
+    fn write_batch(store: &dyn BindStore, index: &RwLock<Bindings>, rx: &Receiver<Job>, pending: &mut Vec<Job>) {
+        let mut ops = vec![];
+        let mut flushed = vec![];
+        for job in pending.drain(..).chain(rx.try_iter()) {
+            match job {
+                Job::Update(op) => ops.push(op),
+                Job::Flush(done) => flushed.push(done),
+            }
+        }
+        if !ops.is_empty() {
+            let result = store.update(&mut |bindings| {
+                ops.iter().fold(false, |changed, op| op.apply(bindings) | changed)
+            });
+            match result {
+                Ok(persons) => {
+                    if let Ok(mut index) = index.write() {
+                        *index = Bindings::from_persons(persons);
+                        pending.extend(rx.try_iter());
+                        for job in pending.iter() {
+                            if let Job::Update(op) = job {
+                                op.apply(&mut index);
+                            }
+                        }
+                        report_size(&index);
+                    }
+                }
+                Err(e) => error!("BindMap save fail; {:#?}", e),
+            }
+        }
+        for done in flushed {
+            let _ = done.send(());
+        }
     }
 
     #[cfg(test)]
     mod ts_bind_map {
-        use chrono::Local;
-        use serde_json::json;
+        use std::io::Read;
+        use std::sync::Arc;
         use crate::bridge_data::bind_map::*;
 
+        fn qq(id: u64) -> Identity {
+            Identity::new(BridgeClientPlatform::QQ, id)
+        }
+
+        fn dc(id: u64) -> Identity {
+            Identity::new(BridgeClientPlatform::Discord, id)
+        }
+
         #[test]
-        fn add() {
-            let st = Local::now().timestamp_millis();
-            add_bind("dong", "6uopdong");
-            add_bind("abc", "123");
-            add_bind("aaa", "bbb");
-            add_bind("ccc", "aaa");
-            add_bind("ddd", "bbb");
-            let et = Local::now().timestamp_millis();
-            println!("{} - {} = {}", et, st, et - st);
+        fn identity() {
+            assert_eq!(qq(123).to_string(), "QQ:123");
+            assert_eq!("discord:456".parse::<Identity>(), Ok(dc(456)));
+            assert!("QQ:abc".parse::<Identity>().is_err());
+            assert!("123".parse::<Identity>().is_err());
         }
 
         #[test]
-        fn get() {
-            let st = Local::now().timestamp_millis();
-            let u1 = "dong";
-            match get_bind(u1) {
-                None => println!("{} no mapping", u1),
-                Some(u2) => println!("{} map to {}", u1, u2),
-            }
+        fn merge_split() {
+            let mut bindings = Bindings::default();
+            assert!(bindings.merge(qq(1), dc(2)));
+            assert!(!bindings.merge(dc(2), qq(1)));
+            // 一个人可以拥有多个身份
+            assert!(bindings.merge(qq(1), dc(3)));
+            assert_eq!(bindings.bound(&qq(1)), vec![dc(2), dc(3)]);
 
-            let u1 = "111";
-            match get_bind(u1) {
-                None => println!("{} no mapping user", u1),
-                Some(u2) => println!("{} map to {}", u1, u2),
-            }
-            let et = Local::now().timestamp_millis();
-            println!("{} - {} = {}", et, st, et - st);
+            // 两个人合并为一个
+            bindings.merge(qq(4), dc(5));
+            assert_eq!(bindings.persons().len(), 2);
+            assert!(bindings.merge(dc(3), qq(4)));
+            assert_eq!(bindings.persons().len(), 1);
+            assert_eq!(bindings.bound(&dc(5)).len(), 4);
+
+            assert!(bindings.split(&qq(1)));
+            assert!(!bindings.split(&qq(1)));
+            assert!(bindings.person_of(&qq(1)).is_none());
+            assert_eq!(bindings.bound(&dc(2)).len(), 3);
+            bindings.split(&dc(2));
+            bindings.split(&dc(3));
+            bindings.split(&qq(4));
+            assert!(bindings.persons().is_empty());
         }
 
         #[test]
-        fn rm() {
-            rm_bind_pair("abc", "123");
-            rm_bind_pair("aaa", "bbb");
+        fn legacy() {
+            let mut map = HashMap::new();
+            map.insert("QQ:1".to_string(), "Discord:2".to_string());
+            map.insert("Discord:2".to_string(), "QQ:1".to_string());
+            map.insert("dong".to_string(), "6uopdong".to_string());
+            let bindings = Bindings::from_legacy(&map);
+            assert_eq!(bindings.persons().len(), 1);
+            assert_eq!(bindings.bound(&qq(1)), vec![dc(2)]);
+        }
+
+        /// 写入存储后、刷新索引前插入一次修改，模拟写入期间的并发绑定
+        struct RacingStore {
+            inner: store::JsonStore,
+            index: Arc<RwLock<Bindings>>,
+            tx: Mutex<Sender<Job>>,
+            op: Mutex<Option<Op>>,
+        }
+
+        impl BindStore for RacingStore {
+            fn load(&self) -> StoreResult<Vec<Person>> {
+                self.inner.load()
+            }
+
+            fn save(&self, persons: &[Person]) -> StoreResult<()> {
+                self.inner.save(persons)
+            }
+
+            fn update(&self, f: &mut dyn FnMut(&mut Bindings) -> bool) -> StoreResult<Vec<Person>> {
+                let persons = self.inner.update(f)?;
+                if let Some(op) = self.op.lock().unwrap().take() {
+                    let tx = self.tx.lock().unwrap().clone();
+                    assert!(modify_in(&self.index, op, |job| tx.send(job).is_ok()));
+                }
+                Ok(persons)
+            }
         }
 
         #[test]
-        fn rm_all() {
-            rm_user_all_bind("aaa");
+        fn concurrent_merge() {
+            let dir = tempfile::tempdir().unwrap();
+            let index = Arc::new(RwLock::new(Bindings::default()));
+            let (tx, rx) = mpsc::channel();
+            let store = RacingStore {
+                inner: store::JsonStore::new(dir.path().join("Persons.json")),
+                index: index.clone(),
+                tx: Mutex::new(tx.clone()),
+                op: Mutex::new(Some(Op::Merge(qq(3), dc(4)))),
+            };
+            assert!(modify_in(&index, Op::Merge(qq(1), dc(2)), |job| tx.send(job).is_ok()));
+            let mut pending = vec![rx.recv().unwrap()];
+
+            write_batch(&store, &index, &rx, &mut pending);
+            // 写入期间的绑定仍在索引中，并等待下一批写入
+            assert_eq!(index.read().unwrap().bound(&qq(1)), vec![dc(2)]);
+            assert_eq!(index.read().unwrap().bound(&qq(3)), vec![dc(4)]);
+            assert_eq!(pending.len(), 1);
+            assert_eq!(store.load().unwrap().len(), 1);
+
+            write_batch(&store, &index, &rx, &mut pending);
+            assert!(pending.is_empty());
+            assert_eq!(store.load().unwrap().len(), 2);
+            assert_eq!(index.read().unwrap().persons().len(), 2);
         }
 
         #[test]
         fn open_file() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("BindMap.json");
             {// truncate, but do not write
                 let file = OpenOptions::new()
                     .truncate(true)
                     .write(true)
                     .create(true)
-                    .open(BIND_MAP_PATH);
+                    .open(&path);
                 match file {
                     Ok(_) => println!("Open file success."),
-                    Err(e) => println!("Can not open/create data file({}); {:#?}", BIND_MAP_PATH, e),
+                    Err(e) => println!("Can not open/create data file({}); {:#?}", path.display(), e),
                 };
             }
             // try read context
@@ -186,18 +822,221 @@ pub mod bind_map {
                 .read(true)
                 .write(true)
                 .create(true)
-                .open(BIND_MAP_PATH);
+                .open(&path);
             match file {
                 Ok(mut f) => {
                     let mut json = String::new();
                     match f.read_to_string(&mut json) {
                         Ok(_) => println!("context: {}", json),
-                        Err(e) => println!("Can not read file({}); {:#?}", BIND_MAP_PATH, e),
+                        Err(e) => println!("Can not read file({}); {:#?}", path.display(), e),
                     }
                 }
-                Err(e) => println!("Can not open/create data file({}); {:#?}", BIND_MAP_PATH, e),
+                Err(e) => println!("Can not open/create data file({}); {:#?}", path.display(), e),
             };
         }
 
     }
 }
+
+/// 数据格式升级
+/// - 已完成的版本记录在绑定数据所在目录的 `version` 文件中，按版本顺序执行未完成的升级
+pub mod migrate {
+    use std::path::{Path, PathBuf};
+
+    use crate::bridge_data::*;
+    use crate::config::{BindStoreBackend, BindStoreConfig};
+
+    /// 升级步骤
+    pub struct Migration {
+        /// 升级后的版本
+        pub version: u32,
+        pub description: &'static str,
+        run: fn(&BindStoreConfig) -> Result<(), String>,
+    }
+
+    /// 全部升级步骤，按版本递增
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            description: "BindMap 补全双向映射, 移除无效映射",
+            run: v1_bind_map,
+        },
+        Migration {
+            version: 2,
+            description: "BindMap 一对一映射转换为多身份绑定",
+            run: v2_persons,
+        },
+    ];
+
+    /// 数据目录：配置的绑定数据文件所在目录
+    pub fn data_dir(config: &BindStoreConfig) -> PathBuf {
+        match store::path(config).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    }
+
+    /// 当前数据版本；没有记录时为 0
+    pub fn current(config: &BindStoreConfig) -> u32 {
+        match std::fs::read_to_string(data_dir(config).join("version")) {
+            Ok(v) => v.trim().parse().unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+
+    /// 尚未执行的升级
+    pub fn pending(config: &BindStoreConfig) -> Vec<&'static Migration> {
+        let current = current(config);
+        MIGRATIONS.iter().filter(|m| m.version > current).collect()
+    }
+
+    /// 对配置的存储执行未完成的升级；每完成一步记录一次版本
+    /// - 返回执行过的升级
+    pub fn run(config: &BindStoreConfig) -> Result<Vec<&'static Migration>, String> {
+        let pending = pending(config);
+        let dir = data_dir(config);
+        std::fs::create_dir_all(&dir).map_err(|e| format!("无法创建数据目录({}): {}", dir.display(), e))?;
+        for m in &pending {
+            (m.run)(config).map_err(|e| format!("升级到版本 {} 失败: {}", m.version, e))?;
+            std::fs::write(dir.join("version"), m.version.to_string())
+                .map_err(|e| format!("无法记录数据版本: {}", e))?;
+        }
+        Ok(pending)
+    }
+
+    /// 读取旧版一对一映射；文件不存在或为空时返回 None
+    fn read_legacy(path: &Path) -> Result<Option<HashMap<String, String>>, String> {
+        let json = match std::fs::read_to_string(path) {
+            Ok(json) if !json.trim().is_empty() => json,
+            _ => return Ok(None),
+        };
+        let map = from_str(&json).map_err(|e| format!("{} 无法解析: {}", path.display(), e))?;
+        Ok(Some(map))
+    }
+
+    /// v1: 映射需成对存在；补全单向映射，移除自身映射与空用户名
+    fn v1_bind_map(config: &BindStoreConfig) -> Result<(), String> {
+        let path = data_dir(config).join("BindMap.json");
+        let map = match read_legacy(&path)? {
+            Some(map) => map,
+            None => return Ok(()),
+        };
+        let json = to_string(&symmetric(map)).map_err(|e| e.to_string())?;
+        std::fs::write(&path, json).map_err(|e| format!("无法写入({}): {}", path.display(), e))
+    }
+
+    /// v2: 一对一映射转换为人与身份，写入配置的存储
+    /// - 来源为数据目录中的 BindMap.json，保留该文件以便回退
+    /// - JSON 存储的文件本身仍是旧格式时一并转换，原文件备份为 `<文件>.legacy`
+    fn v2_persons(config: &BindStoreConfig) -> Result<(), String> {
+        let mut legacy = Bindings::default();
+        if let Some(map) = read_legacy(&data_dir(config).join("BindMap.json"))? {
+            legacy.absorb(&Bindings::from_legacy(&map));
+        }
+        if config.backend == BindStoreBackend::Json {
+            let path = store::path(config);
+            if let Some(map) = read_legacy_in_place(&path) {
+                let mut backup = path.clone().into_os_string();
+                backup.push(".legacy");
+                std::fs::rename(&path, &backup).map_err(|e| format!("无法备份({}): {}", path.display(), e))?;
+                legacy.absorb(&Bindings::from_legacy(&map));
+            }
+        }
+        if legacy.persons().is_empty() {
+            return Ok(());
+        }
+        let store = store::open(config).map_err(|e| e.to_string())?;
+        store
+            .update(&mut |bindings| {
+                let before = bindings.persons().to_vec();
+                bindings.absorb(&legacy);
+                bindings.persons() != before.as_slice()
+            })
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// 存储文件是旧版映射（JSON 对象）时返回映射；新格式为数组，解析失败即不是旧格式
+    fn read_legacy_in_place(path: &Path) -> Option<HashMap<String, String>> {
+        let json = std::fs::read_to_string(path).ok()?;
+        from_str(&json).ok()
+    }
+
+    fn symmetric(map: HashMap<String, String>) -> HashMap<String, String> {
+        let mut next = HashMap::new();
+        for (u1, u2) in map.iter() {
+            if u1.is_empty() || u2.is_empty() || u1 == u2 {
+                continue;
+            }
+            next.entry(u1.clone()).or_insert_with(|| u2.clone());
+            next.entry(u2.clone()).or_insert_with(|| u1.clone());
+        }
+        next
+    }
+
+    #[cfg(test)]
+    mod ts_migrate {
+        use crate::bridge_data::migrate::*;
+        use crate::bridge_data::store::BindStore;
+
+        fn config(backend: BindStoreBackend, path: &Path) -> BindStoreConfig {
+            BindStoreConfig {
+                backend,
+                path: Some(path.to_string_lossy().into_owned()),
+            }
+        }
+
+        #[test]
+        fn upgrade() {
+            let dir = tempfile::tempdir().unwrap();
+            let config = config(BindStoreBackend::Json, &dir.path().join("Persons.json"));
+            std::fs::write(dir.path().join("BindMap.json"), r#"{"a":"b","c":"c","":"d","QQ:1":"Discord:2"}"#).unwrap();
+            assert_eq!(current(&config), 0);
+            assert_eq!(run(&config).unwrap().len(), MIGRATIONS.len());
+            assert_eq!(current(&config), MIGRATIONS.last().unwrap().version);
+            assert!(pending(&config).is_empty());
+
+            let json = std::fs::read_to_string(dir.path().join("BindMap.json")).unwrap();
+            let map: HashMap<String, String> = from_str(&json).unwrap();
+            assert_eq!(map.len(), 4);
+            assert_eq!(map["b"], "a");
+            let persons = store::JsonStore::new(dir.path().join("Persons.json")).load().unwrap();
+            assert_eq!(persons.len(), 1);
+            assert_eq!(persons[0].identities.len(), 2);
+            // 已是最新版本时不再执行
+            assert!(run(&config).unwrap().is_empty());
+        }
+
+        #[test]
+        fn legacy_in_place() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("binds.json");
+            std::fs::write(&path, r#"{"QQ:1":"Discord:2","Discord:2":"QQ:1"}"#).unwrap();
+            let config = config(BindStoreBackend::Json, &path);
+            run(&config).unwrap();
+            let persons = store::open(&config).unwrap().load().unwrap();
+            assert_eq!(persons.len(), 1);
+            assert_eq!(persons[0].identities.len(), 2);
+            assert!(dir.path().join("binds.json.legacy").exists());
+        }
+
+        #[test]
+        fn legacy_to_sqlite() {
+            let dir = tempfile::tempdir().unwrap();
+            std::fs::write(dir.path().join("BindMap.json"), r#"{"QQ:1":"Discord:2"}"#).unwrap();
+            let config = config(BindStoreBackend::Sqlite, &dir.path().join("BindMap.db"));
+            run(&config).unwrap();
+            let persons = store::open(&config).unwrap().load().unwrap();
+            assert_eq!(persons.len(), 1);
+            assert_eq!(persons[0].identities.len(), 2);
+        }
+
+        #[test]
+        fn init_load_error() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("Persons.json");
+            std::fs::write(&path, "not json").unwrap();
+            assert!(bind_map::init(Box::new(store::JsonStore::new(&path))).is_err());
+        }
+    }
+}
@@ -1,158 +1,326 @@
-use crate::bridge_log;
-use crate::{bridge, Config};
+use crate::bridge::{BridgeClientPlatform, BridgeMessage, Channel};
+use crate::bridge_admin;
+use crate::bridge_cmd::{self, Cmd, Permission};
+use crate::bridge_archive::Archive;
+use crate::bridge_face;
+use crate::bridge_file;
+use crate::bridge_format;
+use crate::config::{BridgeConfig, Config, DiscordBridgeConfig, DiscordChannelKind, ForwardMode};
+use crate::bridge_user;
+use crate::bridge_voice::{self, AudioFormat};
+use crate::config_watch;
+use crate::bridge_metrics::METRICS;
+use crate::{bridge, SharedConfig};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::ops::Add;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
 use serenity::async_trait;
-use serenity::http::Http;
-use serenity::model::channel::Message;
+use serenity::builder::ExecuteWebhook;
+use serenity::client::bridge::gateway::event::ShardStageUpdateEvent;
+use serenity::gateway::ConnectionStage;
+use serenity::http::{Http, HttpError};
+use reqwest::multipart::{Form, Part};
+use serenity::model::channel::{AttachmentType, Message};
 use serenity::model::gateway::Ready;
+use serenity::model::id::{ChannelId, GuildId, RoleId, UserId};
+use serenity::model::permissions::Permissions;
 use serenity::model::webhook::Webhook;
 use serenity::model::Timestamp;
 use serenity::prelude::*;
+use tracing::{debug, error, info, warn};
+
+/// 断线重连的等待时间
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// 桥创建与复用的 webhook 名称
+const WEBHOOK_NAME: &str = "message-bridge";
+
+/// 共用的 http 客户端；网关连接后替换为网关客户端的
+static HTTP: Lazy<ArcSwap<Http>> = Lazy::new(|| ArcSwap::from_pointee(Http::new("")));
+/// webhook 缓存，按桥的标识保存
+static WEBHOOKS: Lazy<Mutex<HashMap<String, Webhook>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// 桥创建或复用的 webhook id；写回配置失败时也能识别桥自己发出的消息
+static OWN_WEBHOOKS: Lazy<Mutex<HashSet<u64>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+/// 论坛频道中桥创建的帖子，按频道 id 缓存；写回配置失败时也不会重复创建
+static POSTS: Lazy<Mutex<HashMap<u64, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// 子区所在的频道，按子区 id 缓存；不是子区的频道为 0
+static THREAD_PARENTS: Lazy<Mutex<HashMap<u64, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// serenity 的 webhook 接口不支持指定子区，发送到子区时直接请求
+static WEBHOOK_CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+const API_BASE: &str = "https://discord.com/api/v10";
+/// 一条消息附件的总大小上限；未加成的服务器为 10MiB
+const DISCORD_UPLOAD_LIMIT: u64 = 10 * 1024 * 1024;
+/// 直接请求被限速时的最多重试次数
+const RATE_LIMIT_RETRIES: usize = 3;
+/// 服务器所有者与角色权限的缓存时间
+const GUILD_CACHE_TTL: Duration = Duration::from_secs(300);
+/// 服务器所有者与角色权限，按服务器 id 缓存；判断指令权限时不必每次请求服务器信息
+static GUILDS: Lazy<Mutex<HashMap<u64, (Instant, GuildPermissions)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+/// 消息已有子区的错误码
+const THREAD_ALREADY_CREATED: isize = 160004;
+/// 以回复创建子区时，子区名称取回复内容的前几个字
+const THREAD_NAME_LEN: usize = 40;
+
+/// webhook 消息在频道中发送的位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Destination {
+    Channel,
+    /// 频道下的子区或论坛帖子
+    Thread(u64),
+    /// 在论坛频道中以此标题创建帖子
+    NewPost(String),
+}
+
+/// 取共用的 http 客户端
+pub fn http() -> Arc<Http> {
+    HTTP.load_full()
+}
 
 /**
 *
 */
-pub async fn dc(bridge: Arc<bridge::BridgeClient>) {
+pub async fn dc(config: SharedConfig, bridge: Arc<bridge::BridgeClient>) {
+    let mut rx = bridge.sender.subscribe();
     loop {
-        let message = bridge.sender.subscribe().recv().await.unwrap();
-        println!("[bridge_dc] 收到桥的消息, 同步到discord上");
-        let http = Http::new("");
-        let webhook = Webhook::from_id_with_token(
-            &http,
-            message.bridge_config.discord.id,
-            message.bridge_config.discord.token.as_str(),
-        )
-        .await
-        .unwrap();
+        let mut message = match bridge.recv(&mut rx).await {
+            Some(message) => message,
+            None => break,
+        };
+        let bridge_id = message.bridge_config.id();
+        info!("收到桥的消息, 同步到discord上");
+        let bridge_channel = Channel::DiscordChannel {
+            channel: message.bridge_config.discord.channelId,
+        };
+        if let Some(target) = message.target.filter(|t| *t != bridge_channel) {
+            send_by_bot(&message, target).await;
+            continue;
+        }
+        // 未建桥的频道（如指令回复）没有 webhook，由机器人发送
+        if !message.bridge_config.is_linked() {
+            if message.bridge_config.discord.channelId != 0 {
+                send_by_bot(&message, bridge_channel).await;
+            }
+            continue;
+        }
+        let channel = message.bridge_config.discord.channelId;
+        let mut discord = current_webhook(&config, &message.bridge_config.discord);
+        if !discord.has_webhook() {
+            discord = match provision(&config, &discord).await {
+                Ok(discord) => discord,
+                Err(e) => {
+                    error!("频道 {} 没有可用的webhook: {}", channel, e);
+                    METRICS.delivery_failures.with_label_values(&["bridge_dc", &bridge_id]).inc();
+                    continue;
+                }
+            };
+        }
 
-        webhook
-            .execute(&http, false, |w| {
-                // 配置发送者头像
-                if let Some(url) = message.user.avatar_url {
-                    w.avatar_url(url.as_str());
+        let user = bridge_user::resolve(&message);
+        let destination = destination(&bridge, &message, &discord).await;
+        let original = message.clone();
+        let files = attachments(&config, &mut message).await;
+        let timer = METRICS.webhook_latency.start_timer();
+        let mut result = execute(&bridge_id, &discord, &message, &user, &destination, &files).await;
+        // webhook 被删除时重新创建并重试一次
+        if matches!(&result, Err(e) if is_not_found(e)) {
+            warn!("频道 {} 的webhook已失效, 重新创建", channel);
+            match provision(&config, &discord).await {
+                Ok(provisioned) => {
+                    discord = provisioned;
+                    result = execute(&bridge_id, &discord, &message, &user, &destination, &files).await;
                 }
-                // 配置发送者用户名
-                w.username(message.user.name);
-
-                let mut content: Vec<&str> = Vec::new();
-                for chain in &message.message_chain {
-                    match chain {
-                        bridge::MessageContent::Plain { text } => &content.push(text),
-                        _ => &content.push("{无法识别的MessageChain}"),
-                    };
+                Err(e) => error!("重新创建webhook失败: {}", e),
+            }
+        }
+        // 附件超过服务器的上传限制等原因失败时，不带附件重发，附件以链接显示
+        if !files.is_empty() && matches!(&result, Err(e) if !is_not_found(e)) {
+            if let Err(e) = &result {
+                warn!("带附件发送失败, 改为发送链接: {}", e);
+            }
+            result = execute(&bridge_id, &discord, &original, &user, &destination, &[]).await;
+        }
+        timer.observe_duration();
+        if result.is_ok() {
+            METRICS.sent.with_label_values(&["bridge_dc", &bridge_id]).inc();
+        }
+        match result {
+            Ok(Some(sent)) => {
+                if let Destination::NewPost(_) = destination {
+                    save_post(&config, channel, sent.channel_id.0);
                 }
-                if content.len() == 0 {
-                    content.push("{本次发送的消息没有内容}");
+                if let Some(archive) = bridge.archive() {
+                    let remote_id = sent.id.to_string();
+                    if let Err(e) = archive.record_delivery(&message.id, BridgeClientPlatform::Discord, &remote_id) {
+                        error!("记录消息id失败: {:?}", e);
+                    }
+                    if sent.channel_id.0 != channel {
+                        record_thread(&archive, &sent);
+                    }
                 }
-                w.content(content.join(""))
-            })
-            .await
-            .expect("Could not execute webhook.");
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Could not execute webhook: {:?}", e);
+                METRICS.delivery_failures.with_label_values(&["bridge_dc", &bridge_id]).inc();
+            }
+        }
     }
 }
 
-pub async fn start(config: Arc<Config>, bridge: Arc<bridge::BridgeClient>) {
-    let token = &config.discordConfig.botToken;
+pub async fn start(config: SharedConfig, bridge: Arc<bridge::BridgeClient>) {
+    // 网关连接前发送消息使用配置的 token
+    HTTP.store(Arc::new(Http::new(&config.load().discordConfig.botToken)));
+    provision_missing(&config).await;
+    tokio::select! {
+        _ = gateway(config.clone(), bridge.clone()) => {},
+        _ = dc(config.clone(), bridge.clone()) => {},
+    }
+}
+
+/// 连接 discord 网关；bot 凭据变更时重新连接
+async fn gateway(config: SharedConfig, bridge: Arc<bridge::BridgeClient>) {
     let intents = GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT;
-    println!("dc");
-
-    let mut client = Client::builder(&token, intents)
-        .event_handler(Handler {
-            config: config.clone(),
-            bridge: bridge.clone(),
-        })
-        .await
-        .expect("Err creating client");
+    loop {
+        let token = config.load().discordConfig.botToken.clone();
+        let client = Client::builder(&token, intents)
+            .event_handler(Handler {
+                config: config.clone(),
+                bridge: bridge.clone(),
+            })
+            .await;
+        let mut client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Err creating client: {:?}", e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        HTTP.store(client.cache_and_http.http.clone());
 
-    println!("dc2");
-    tokio::select! {
-        val = client.start() => {
-            println!("xxxxxx");
-        },
-        val = dc(bridge.clone()) => {},
+        tokio::select! {
+            val = client.start() => {
+                error!("discord 客户端已退出: {:?}", val);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            },
+            _ = config_watch::changed(&config, |c| (c.discordConfig.botId, c.discordConfig.botToken.clone())) => {
+                client.shard_manager.lock().await.shutdown_all().await;
+            },
+        }
     }
 }
 
 pub struct Handler {
-    pub config: Arc<Config>,
+    pub config: SharedConfig,
     pub bridge: Arc<bridge::BridgeClient>,
 }
 
+impl Handler {
+    /// 被回复的 discord 消息在桥内的 id
+    fn reply_to(&self, msg: &Message, bridge_config: &BridgeConfig) -> Option<String> {
+        let referenced = msg
+            .referenced_message
+            .as_ref()
+            .map(|m| m.id)
+            .or_else(|| msg.message_reference.as_ref().and_then(|r| r.message_id))?;
+        let archive = self.bridge.archive()?;
+        archive
+            .find_by_remote_id(
+                (bridge_config.qqGroup, bridge_config.discord.channelId),
+                BridgeClientPlatform::Discord,
+                &referenced.to_string(),
+            )
+            .unwrap_or_else(|e| {
+                error!("查找被回复的消息失败: {:?}", e);
+                None
+            })
+    }
+}
+
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, msg: Message) {
-        if msg.author.id == self.config.discordConfig.botId {
+        let config = self.config.load_full();
+        if msg.author.id == config.discordConfig.botId {
             // 收到自己bot的消息, 不要继续以免消息循环
             return;
         }
 
-        // 收到桥配置的webhook消息, 不要继续以免消息循环
-        if let Some(_) = self
-            .config
-            .bridges
-            .iter()
-            .find(|bridge| msg.author.id == bridge.discord.id)
-        {
+        // 收到桥的webhook消息, 不要继续以免消息循环
+        if msg.webhook_id.is_some_and(|id| is_own_webhook(&config, id.0)) {
             return;
-        };
-        let bridgeConfig = match self
-            .config
+        }
+        // 没有配置桥的频道只接收桥管理指令
+        let parent = thread_parent(&ctx, &msg, &config.bridges).await;
+        let bridgeConfig = config
             .bridges
             .iter()
-            .find(|bridge| msg.channel_id == bridge.discord.channelId && bridge.enable)
-        {
-            Some(bridgeConfig) => bridgeConfig,
-            None => {
-                // 该消息的频道没有配置桥, 忽略这个消息
-                return;
-            }
-        };
+            .find(|bridge| bridge.discord.accepts(msg.channel_id.0, parent))
+            .cloned()
+            .unwrap_or_else(|| BridgeConfig::unlinked(0, msg.channel_id.0));
         let mut user = bridge::User {
             name: format!("[DC] {}#{}", msg.author.name, msg.author.discriminator),
             avatar_url: None,
+            id: msg.author.id.0,
+            permission: Permission::Member,
         };
         if let Some(url) = msg.author.avatar_url() {
-            println!("[bridge_dc] avatar_url: {:?}", url);
+            debug!("avatar_url: {:?}", url);
             user.avatar_url = Some(url.replace(".webp?size=1024", ".png?size=40").to_string());
         }
+        bridge_user::seen(BridgeClientPlatform::Discord, user.id, &msg.author.name, user.avatar_url.clone());
         // println!(
         //     "msg.author.default_avatar_url(){:?}",
         //     msg.author.static_avatar_url()
         // );
 
-        bridge_log::BridgeLog::write_log(
-            format!(
-                r#"discord桥要发送的消息
-{}
-{}"#,
-                user.name, msg.content
-            )
-            .as_str(),
-        );
+        info!("discord桥要发送的消息 {}: {}", user.name, msg.content);
 
         let sender = self.bridge.sender.clone();
 
         let mut bridge_message = bridge::BridgeMessage {
+            id: uuid::Uuid::new_v4().to_string(),
+            origin_id: Some(msg.id.to_string()),
             bridge_config: bridgeConfig.clone(),
             message_chain: Vec::new(),
             user: user,
+            origin: Some(Channel::DiscordChannel {
+                channel: msg.channel_id.0,
+            }),
+            target: None,
+            reply_to: None,
         };
-        bridge_message
-            .message_chain
-            .push(bridge::MessageContent::Plain {
-                text: msg.content.clone(),
-            });
+        bridge_message.message_chain = parse_message(&ctx, &msg).await;
+        bridge_message.reply_to = self.reply_to(&msg, &bridgeConfig);
+        if parent != 0 {
+            if let Some(archive) = self.bridge.archive() {
+                record_thread(&archive, &msg);
+            }
+        }
         
-        // skip cmd
-        if msg.content.starts_with("!") {
-            self.bridge.send_to("bridge_cmd_adapter", &bridge_message);
-            // return;
+        // 指令交给指令频道；桥暂停或未建立时只接收桥管理指令
+        let cmd = bridge_cmd::lookup(&bridge_message.message_chain, &bridgeConfig.cmdPrefix);
+        if let Some(def) = cmd {
+            if bridgeConfig.enable || def.cmd == Cmd::Manage {
+                let mut cmd_message = bridge_message.clone();
+                cmd_message.user.permission = member_permission(&ctx, &msg, &config.discordConfig.managerRoles).await;
+                self.bridge.send_to(bridge::CMD_CLIENT, &cmd_message);
+            }
+        }
+        if !bridgeConfig.enable {
+            return;
+        }
+        METRICS.received.with_label_values(&["bridge_dc", &bridgeConfig.id()]).inc();
+        // 不同步的指令由指令频道决定何时转发
+        if cmd.map(|def| def.forward_mode(&bridgeConfig) != ForwardMode::Mirror).unwrap_or(false) {
+            return;
         }
-
         self.bridge.send(bridge_message);
         if msg.content == "!hello" {
             // The create message builder allows you to easily create embeds and messages
@@ -186,12 +354,735 @@ impl EventHandler for Handler {
                 .await;
 
             if let Err(why) = msg {
-                println!("Error sending message: {:?}", why);
+                error!("Error sending message: {:?}", why);
             }
         }
     }
 
     async fn ready(&self, _: Context, ready: Ready) {
-        println!("{} 已连接到discord!", ready.user.name);
+        info!("{} 已连接到discord!", ready.user.name);
+        bridge_admin::report(bridge_admin::DISCORD, true, format!("connected as {}", ready.user.name));
+    }
+
+    async fn shard_stage_update(&self, _: Context, event: ShardStageUpdateEvent) {
+        let connected = event.new == ConnectionStage::Connected;
+        bridge_admin::report(bridge_admin::DISCORD, connected, event.new.to_string());
+    }
+}
+
+/// 消息所在子区的上级频道；不在子区中时为 0
+async fn thread_parent(ctx: &Context, msg: &Message, bridges: &[BridgeConfig]) -> u64 {
+    let channel = msg.channel_id.0;
+    if msg.guild_id.is_none() || bridges.iter().any(|b| b.discord.channelId == channel) {
+        return 0;
+    }
+    if let Some(parent) = THREAD_PARENTS.lock().ok().and_then(|p| p.get(&channel).copied()) {
+        return parent;
+    }
+    let parent = match msg.channel_id.to_channel(ctx).await {
+        Ok(serenity::model::channel::Channel::Guild(c)) if c.thread_metadata.is_some() => {
+            c.parent_id.map(|p| p.0).unwrap_or(0)
+        }
+        Ok(_) => 0,
+        Err(e) => {
+            // 获取失败时不缓存，下条消息再试
+            warn!("获取频道 {} 失败: {}", channel, e);
+            return 0;
+        }
+    };
+    if let Ok(mut parents) = THREAD_PARENTS.lock() {
+        parents.insert(channel, parent);
+    }
+    parent
+}
+
+/// 取得频道中桥使用的 webhook：按名称复用已有的，没有时创建
+pub async fn ensure_webhook(http: &Http, channel: u64) -> Result<DiscordBridgeConfig, String> {
+    let channel_id = ChannelId(channel);
+    let webhooks = channel_id
+        .webhooks(http)
+        .await
+        .map_err(|e| format!("获取频道的webhook失败: {}", e))?;
+    let webhook = match reusable_webhook(webhooks) {
+        Some(webhook) => {
+            info!("复用频道 {} 的webhook {}", channel, webhook.id);
+            webhook
+        }
+        None => {
+            let webhook = channel_id
+                .create_webhook(http, WEBHOOK_NAME)
+                .await
+                .map_err(|e| format!("创建webhook失败: {}", e))?;
+            info!("已在频道 {} 创建webhook {}", channel, webhook.id);
+            webhook
+        }
+    };
+    let token = webhook.token.ok_or("webhook没有token")?;
+    Ok(DiscordBridgeConfig {
+        id: webhook.id.0,
+        token,
+        channelId: channel,
+        ..Default::default()
+    })
+}
+
+/// 频道中可复用的 webhook：桥创建的、带有 token 的
+fn reusable_webhook(webhooks: Vec<Webhook>) -> Option<Webhook> {
+    webhooks
+        .into_iter()
+        .find(|w| w.name.as_deref() == Some(WEBHOOK_NAME) && w.token.is_some())
+}
+
+/// 频道是否配置了桥
+fn is_bridged(config: &SharedConfig, channel: u64) -> bool {
+    config
+        .load()
+        .bridges
+        .iter()
+        .any(|b| b.is_linked() && b.discord.channelId == channel)
+}
+
+/// 为频道取得 webhook 并写回配置；只为配置了桥的频道创建
+async fn provision(config: &SharedConfig, discord: &DiscordBridgeConfig) -> Result<DiscordBridgeConfig, String> {
+    let channel = discord.channelId;
+    if !is_bridged(config, channel) {
+        return Err(format!("频道 {} 没有配置桥", channel));
+    }
+    let webhook = ensure_webhook(&http(), channel).await?;
+    Ok(adopt(config, discord, webhook))
+}
+
+/// 记录取得的 webhook 并写回配置
+fn adopt(config: &SharedConfig, discord: &DiscordBridgeConfig, webhook: DiscordBridgeConfig) -> DiscordBridgeConfig {
+    let channel = discord.channelId;
+    remember_webhook(webhook.id);
+    let discord = DiscordBridgeConfig {
+        id: webhook.id,
+        token: webhook.token,
+        ..discord.clone()
+    };
+    let result = config_watch::update(config, |c| {
+        for bridge in c.bridges.iter_mut().filter(|b| b.discord.channelId == channel) {
+            bridge.discord.id = discord.id;
+            bridge.discord.token = discord.token.clone();
+        }
+    });
+    // 保存失败时仍可使用本次取得的 webhook，下次发送会按名称复用
+    if let Err(e) = result {
+        error!("保存频道 {} 的webhook失败:\n{}", channel, e);
+    }
+    discord
+}
+
+fn remember_webhook(id: u64) {
+    if let Ok(mut webhooks) = OWN_WEBHOOKS.lock() {
+        webhooks.insert(id);
+    }
+}
+
+/// 是否是桥的 webhook：配置中的，或运行中创建、复用的
+fn is_own_webhook(config: &Config, id: u64) -> bool {
+    config.bridges.iter().any(|bridge| bridge.discord.id == id)
+        || OWN_WEBHOOKS.lock().is_ok_and(|webhooks| webhooks.contains(&id))
+}
+
+/// 启动时为未配置 webhook 的桥取得 webhook
+async fn provision_missing(config: &SharedConfig) {
+    let missing: Vec<DiscordBridgeConfig> = config
+        .load()
+        .bridges
+        .iter()
+        .filter(|b| b.is_linked() && !b.discord.has_webhook())
+        .map(|b| b.discord.clone())
+        .collect();
+    for discord in missing {
+        if let Err(e) = provision(config, &discord).await {
+            error!("频道 {} 没有可用的webhook: {}", discord.channelId, e);
+        }
+    }
+}
+
+/// 配置中频道当前的 webhook；运行中可能被重新创建
+fn current_webhook(config: &SharedConfig, discord: &DiscordBridgeConfig) -> DiscordBridgeConfig {
+    config
+        .load()
+        .bridges
+        .iter()
+        .find(|b| b.discord.channelId == discord.channelId)
+        .map(|b| b.discord.clone())
+        .unwrap_or_else(|| discord.clone())
+}
+
+/// 取缓存的 webhook；与配置的不一致时视为没有
+fn cached_webhook(bridge_id: &str, discord: &DiscordBridgeConfig) -> Option<Webhook> {
+    let webhooks = WEBHOOKS.lock().ok()?;
+    webhooks.get(bridge_id).filter(|w| w.id.0 == discord.id).cloned()
+}
+
+/// 取桥的 webhook，没有缓存时从 discord 获取
+async fn webhook(http: &Http, bridge_id: &str, discord: &DiscordBridgeConfig) -> serenity::Result<Webhook> {
+    if let Some(webhook) = cached_webhook(bridge_id, discord) {
+        return Ok(webhook);
+    }
+    let webhook = Webhook::from_id_with_token(http, discord.id, &discord.token).await?;
+    remember_webhook(webhook.id.0);
+    if let Ok(mut webhooks) = WEBHOOKS.lock() {
+        webhooks.insert(bridge_id.to_string(), webhook.clone());
+        METRICS.cache_size.with_label_values(&["webhook"]).set(webhooks.len() as i64);
+    }
+    Ok(webhook)
+}
+
+/// 通过 webhook 以发送者的名称与头像发送；失败时清除缓存，下次重新获取
+async fn execute(
+    bridge_id: &str,
+    discord: &DiscordBridgeConfig,
+    message: &BridgeMessage,
+    user: &bridge::User,
+    destination: &Destination,
+    files: &[AttachmentType<'static>],
+) -> serenity::Result<Option<Message>> {
+    let http = http();
+    let result = send_webhook(&http, bridge_id, discord, message, user, destination, files).await;
+    if result.is_err() {
+        if let Ok(mut webhooks) = WEBHOOKS.lock() {
+            webhooks.remove(bridge_id);
+            METRICS.cache_size.with_label_values(&["webhook"]).set(webhooks.len() as i64);
+        }
+    }
+    result
+}
+
+async fn send_webhook(
+    http: &Http,
+    bridge_id: &str,
+    discord: &DiscordBridgeConfig,
+    message: &BridgeMessage,
+    user: &bridge::User,
+    destination: &Destination,
+    files: &[AttachmentType<'static>],
+) -> serenity::Result<Option<Message>> {
+    let webhook = webhook(http, bridge_id, discord).await?;
+    let thread = match destination {
+        Destination::Channel => {
+            return webhook.execute(http, true, |w| fill_webhook(w, message, user, files)).await;
+        }
+        Destination::Thread(thread) => Some(*thread),
+        Destination::NewPost(_) => None,
+    };
+    let mut builder = ExecuteWebhook::default();
+    fill_webhook(&mut builder, message, user, files);
+    let mut body = serenity::json::hashmap_to_json_map(builder.0);
+    if let Destination::NewPost(title) = destination {
+        body.insert("thread_name".to_string(), title.as_str().into());
+    }
+    execute_in_thread(discord, &body, thread, &builder.1).await.map(Some)
+}
+
+/// 配置发送者的名称、头像、消息内容与附件
+fn fill_webhook<'a, 'b>(
+    w: &'b mut ExecuteWebhook<'a>,
+    message: &BridgeMessage,
+    user: &bridge::User,
+    files: &[AttachmentType<'static>],
+) -> &'b mut ExecuteWebhook<'a> {
+    // 配置发送者头像
+    if let Some(url) = &user.avatar_url {
+        w.avatar_url(url.as_str());
+    }
+    // 配置发送者用户名
+    w.username(&user.name);
+    // 转发的内容不提及任何人，避免 @everyone、@here 通知全员
+    w.allowed_mentions(|m| m.empty_parse());
+    w.add_files(files.iter().cloned());
+    // 只有附件时不需要文字
+    if files.is_empty() || !text(message).is_empty() {
+        w.content(content(message));
+    }
+    w
+}
+
+/// 执行 webhook 并发送到子区；不指定子区时用于创建论坛帖子
+/// - serenity 0.11 的路由不能附加 thread_id，这里直接请求，不经过共用 Http 的限速器；
+///   被限速时按 retry-after 等待后重试
+async fn execute_in_thread(
+    discord: &DiscordBridgeConfig,
+    body: &serenity::json::JsonMap,
+    thread: Option<u64>,
+    files: &[AttachmentType<'_>],
+) -> serenity::Result<Message> {
+    let mut query = vec![("wait", "true".to_string())];
+    if let Some(thread) = thread {
+        query.push(("thread_id", thread.to_string()));
+    }
+    let mut retries = 0;
+    loop {
+        let request = WEBHOOK_CLIENT
+            .post(format!("{}/webhooks/{}/{}", API_BASE, discord.id, discord.token))
+            .query(&query);
+        let request = if files.is_empty() {
+            request.json(body)
+        } else {
+            // 有附件时以 multipart 发送，消息内容放在 payload_json 中
+            let mut form = Form::new().text("payload_json", serde_json::Value::Object(body.clone()).to_string());
+            for (i, file) in files.iter().enumerate() {
+                if let AttachmentType::Bytes { data, filename } = file {
+                    form = form.part(format!("files[{}]", i), Part::bytes(data.to_vec()).file_name(filename.clone()));
+                }
+            }
+            request.multipart(form)
+        };
+        let response = request.send().await.map_err(HttpError::Request)?;
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS && retries < RATE_LIMIT_RETRIES {
+            retries += 1;
+            let wait = retry_after(response.headers());
+            warn!("webhook 请求被限速, {:?} 后重试", wait);
+            tokio::time::sleep(wait).await;
+            continue;
+        }
+        if !response.status().is_success() {
+            return Err(HttpError::from_response(response).await.into());
+        }
+        return Ok(response.json::<Message>().await.map_err(HttpError::Request)?);
+    }
+}
+
+/// 被限速时需等待的时间；取 retry-after 头（秒），没有时等待 1 秒，最多 60 秒
+fn retry_after(headers: &reqwest::header::HeaderMap) -> Duration {
+    let secs = headers
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| v.is_finite() && *v >= 0.0)
+        .unwrap_or(1.0);
+    Duration::from_secs_f64(secs.min(60.0))
+}
+
+/// 消息在桥的频道中发送的位置
+/// - 配置了子区时发送到子区
+/// - 论坛频道没有帖子时创建帖子
+/// - 开启回复子区时，回复发送到被回复消息的子区
+async fn destination(bridge: &bridge::BridgeClient, message: &BridgeMessage, discord: &DiscordBridgeConfig) -> Destination {
+    if discord.threadId != 0 {
+        return Destination::Thread(discord.threadId);
+    }
+    if discord.kind == DiscordChannelKind::Forum {
+        if let Some(post) = POSTS.lock().ok().and_then(|p| p.get(&discord.channelId).copied()) {
+            return Destination::Thread(post);
+        }
+        return Destination::NewPost(format!("QQ群 {}", message.bridge_config.qqGroup));
+    }
+    if discord.replyThreads {
+        if let Some(thread) = reply_thread(bridge, message, discord.channelId).await {
+            return Destination::Thread(thread);
+        }
+    }
+    Destination::Channel
+}
+
+/// 被回复消息在 discord 上的子区；消息还不在子区中时以它创建子区
+async fn reply_thread(bridge: &bridge::BridgeClient, message: &BridgeMessage, channel: u64) -> Option<u64> {
+    let archive = bridge.archive()?;
+    let reply_to = message.reply_to.as_ref()?;
+    let remote_id = archive.remote_id(reply_to, BridgeClientPlatform::Discord).ok()??;
+    if let Some(thread) = archive.thread_of(&remote_id).ok()? {
+        return thread.parse().ok();
+    }
+    let message_id: u64 = remote_id.parse().ok()?;
+    let name: String = content(message).chars().take(THREAD_NAME_LEN).collect();
+    // 以消息创建的子区与消息 id 相同
+    match ChannelId(channel).create_public_thread(http(), message_id, |t| t.name(name)).await {
+        Ok(_) => info!("已为消息 {} 创建子区", message_id),
+        Err(e) if discord_error_code(&e) == Some(THREAD_ALREADY_CREATED) => {}
+        Err(e) => {
+            warn!("为消息 {} 创建子区失败: {}", message_id, e);
+            return None;
+        }
+    }
+    if let Err(e) = archive.record_thread(&remote_id, &remote_id) {
+        error!("记录子区失败: {:?}", e);
+    }
+    Some(message_id)
+}
+
+/// 记录 discord 消息所在的子区，之后的回复同步到同一子区
+fn record_thread(archive: &Archive, message: &Message) {
+    if let Err(e) = archive.record_thread(&message.id.to_string(), &message.channel_id.to_string()) {
+        error!("记录子区失败: {:?}", e);
+    }
+}
+
+/// 论坛频道创建的帖子写回配置，之后的消息发送到这个帖子
+fn save_post(config: &SharedConfig, channel: u64, thread: u64) {
+    info!("已在论坛频道 {} 创建帖子 {}", channel, thread);
+    if let Ok(mut posts) = POSTS.lock() {
+        posts.insert(channel, thread);
+    }
+    let result = config_watch::update(config, |c| {
+        for bridge in c.bridges.iter_mut().filter(|b| b.discord.channelId == channel && b.discord.threadId == 0) {
+            bridge.discord.threadId = thread;
+        }
+    });
+    if let Err(e) = result {
+        error!("保存论坛频道 {} 的帖子失败:\n{}", channel, e);
+    }
+}
+
+/// discord 返回的错误码
+fn discord_error_code(e: &serenity::Error) -> Option<isize> {
+    match e {
+        serenity::Error::Http(e) => match e.as_ref() {
+            HttpError::UnsuccessfulRequest(response) => Some(response.error.code),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// 请求的资源不存在，如 webhook 已被删除
+fn is_not_found(e: &serenity::Error) -> bool {
+    match e {
+        serenity::Error::Http(e) => e.status_code().map(|c| c.as_u16()) == Some(404),
+        _ => false,
+    }
+}
+
+/// 消息内容转为 discord 文本
+fn content(message: &BridgeMessage) -> String {
+    let content = text(message);
+    if content.is_empty() {
+        return "{本次发送的消息没有内容}".to_string();
+    }
+    content
+}
+
+/// 消息内容转为 discord markdown，可能为空
+fn text(message: &BridgeMessage) -> String {
+    let mut chain = message.message_chain.clone();
+    bridge_face::faces().to_discord(&mut chain);
+    bridge_format::markdown(&chain)
+}
+
+/// 下载消息中的语音与文件作为附件；成功的内容从消息中移除，失败的仍以链接显示
+/// - 附件总大小不超过 discord 的上传限制，超过的以链接显示
+async fn attachments(config: &SharedConfig, message: &mut BridgeMessage) -> Vec<AttachmentType<'static>> {
+    let voice_config = config.load().voiceConfig.clone();
+    // discord 可以直接播放的格式不转码
+    let accept = [AudioFormat::Ogg, AudioFormat::Mp3, AudioFormat::Wav];
+    let mut files = vec![];
+    let mut total = 0;
+    let mut i = 0;
+    while i < message.message_chain.len() {
+        let remaining = DISCORD_UPLOAD_LIMIT.saturating_sub(total);
+        let max_file_size = message.bridge_config.maxFileSize.min(remaining);
+        let file = match &message.message_chain[i] {
+            bridge::MessageContent::Voice { url: Some(url), .. } if voice_config.enable => {
+                bridge_voice::prepare(&voice_config, url, voice_config.discordFormat, &accept)
+                    .await
+                    .map(|(data, format)| (data, format!("voice.{}", format.extension())))
+                    .map_err(|e| format!("转发语音失败: {}", e))
+            }
+            bridge::MessageContent::File { name, size, url: Some(url), .. }
+                if bridge_file::within_limit(*size, max_file_size) =>
+            {
+                bridge_file::download(url, max_file_size)
+                    .await
+                    .map(|data| (data, name.clone()))
+                    .map_err(|e| format!("转发文件 {} 失败: {}", name, e))
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        match file {
+            Ok((data, filename)) if data.len() as u64 > remaining => {
+                warn!("附件 {} 超过 discord 的上传限制, 以链接显示", filename);
+                i += 1;
+            }
+            Ok((data, filename)) => {
+                total += data.len() as u64;
+                files.push(AttachmentType::Bytes { data: Cow::Owned(data), filename });
+                message.message_chain.remove(i);
+            }
+            Err(e) => {
+                warn!("{}", e);
+                i += 1;
+            }
+        }
+    }
+    files
+}
+
+/// 以 bot 身份发送到指定的频道或私信；webhook 只能发送到桥的频道
+async fn send_by_bot(message: &BridgeMessage, target: Channel) {
+    let bridge_id = message.bridge_config.id();
+    let http = http();
+    let channel = match target {
+        Channel::DiscordChannel { channel } => ChannelId(channel),
+        Channel::DiscordDM { user } => match UserId(user).create_dm_channel(&http).await {
+            Ok(dm) => dm.id,
+            Err(e) => {
+                error!("创建私信失败: {:?}", e);
+                METRICS.delivery_failures.with_label_values(&["bridge_dc", &bridge_id]).inc();
+                return;
+            }
+        },
+        _ => {
+            warn!("消息的投递目标不是discord会话: {:?}", target);
+            return;
+        }
+    };
+    // 桥自身的消息不显示发送者
+    let text = if message.user.id == 0 {
+        content(message)
+    } else {
+        format!("{}: {}", bridge_user::resolve(message).name, content(message))
+    };
+    match channel.send_message(&http, |m| m.content(text).allowed_mentions(|m| m.empty_parse())).await {
+        Ok(_) => METRICS.sent.with_label_values(&["bridge_dc", &bridge_id]).inc(),
+        Err(e) => {
+            error!("发送到discord会话失败: {:?}", e);
+            METRICS.delivery_failures.with_label_values(&["bridge_dc", &bridge_id]).inc();
+        }
+    }
+}
+
+/// 取发送者在服务器中的权限
+/// - 服务器所有者、角色拥有管理员或管理服务器权限、拥有配置的管理角色的成员视为管理者
+async fn member_permission(ctx: &Context, msg: &Message, manager_roles: &[u64]) -> Permission {
+    let (guild_id, member) = match (msg.guild_id, &msg.member) {
+        (Some(guild_id), Some(member)) => (guild_id, member),
+        _ => return Permission::Member,
+    };
+    if member.roles.iter().any(|role| manager_roles.contains(&role.0)) {
+        return Permission::Manager;
+    }
+    let guild = match guild_permissions(ctx, guild_id).await {
+        Ok(guild) => guild,
+        Err(e) => {
+            warn!("获取服务器信息失败: {:?}", e);
+            return Permission::Member;
+        }
+    };
+    if guild.owner == msg.author.id {
+        return Permission::Manager;
+    }
+    // @everyone 角色的 id 与服务器 id 相同
+    let everyone = RoleId(guild_id.0);
+    let manage = Permissions::ADMINISTRATOR | Permissions::MANAGE_GUILD;
+    let granted = member
+        .roles
+        .iter()
+        .chain(std::iter::once(&everyone))
+        .filter_map(|role| guild.roles.get(role))
+        .any(|permissions| permissions.intersects(manage));
+    if granted {
+        Permission::Manager
+    } else {
+        Permission::Member
+    }
+}
+
+/// 判断权限所需的服务器信息
+#[derive(Debug, Clone)]
+struct GuildPermissions {
+    owner: UserId,
+    roles: HashMap<RoleId, Permissions>,
+}
+
+/// 取未过期的服务器权限缓存
+fn cached_guild(guild_id: u64, now: Instant) -> Option<GuildPermissions> {
+    let guilds = GUILDS.lock().ok()?;
+    match guilds.get(&guild_id) {
+        Some((at, guild)) if now.duration_since(*at) < GUILD_CACHE_TTL => Some(guild.clone()),
+        _ => None,
+    }
+}
+
+/// 取服务器所有者与角色权限；缓存过期后重新请求
+async fn guild_permissions(ctx: &Context, guild_id: GuildId) -> serenity::Result<GuildPermissions> {
+    if let Some(guild) = cached_guild(guild_id.0, Instant::now()) {
+        return Ok(guild);
+    }
+    let guild = guild_id.to_partial_guild(&ctx.http).await?;
+    let guild = GuildPermissions {
+        owner: guild.owner_id,
+        roles: guild.roles.iter().map(|(id, role)| (*id, role.permissions)).collect(),
+    };
+    if let Ok(mut guilds) = GUILDS.lock() {
+        guilds.insert(guild_id.0, (Instant::now(), guild.clone()));
+        METRICS.cache_size.with_label_values(&["guild"]).set(guilds.len() as i64);
+    }
+    Ok(guild)
+}
+
+/// 解析 discord 消息的 markdown，并把提及的用户、频道、身份组解析为名称
+async fn parse_message(ctx: &Context, msg: &Message) -> bridge::MessageChain {
+    let mut chain = bridge_format::parse_markdown(&msg.content);
+    let mut channels = vec![];
+    let mut roles = false;
+    bridge_format::visit_mut(&mut chain, &mut |c| match c {
+        bridge::MessageContent::ChannelMention { id, .. } => channels.push(*id),
+        bridge::MessageContent::RoleMention { .. } => roles = true,
+        _ => {}
+    });
+    let mut names: HashMap<u64, String> = msg.mentions.iter().map(|u| (u.id.0, u.name.clone())).collect();
+    for id in channels {
+        match ChannelId(id).to_channel(ctx).await {
+            Ok(channel) => {
+                if let Some(channel) = channel.guild() {
+                    names.insert(id, channel.name);
+                }
+            }
+            Err(e) => warn!("获取频道 {} 失败: {}", id, e),
+        }
+    }
+    if let (true, Some(guild)) = (roles, msg.guild_id) {
+        match guild.roles(ctx).await {
+            Ok(roles) => names.extend(roles.into_iter().map(|(id, role)| (id.0, role.name))),
+            Err(e) => warn!("获取服务器 {} 的身份组失败: {}", guild, e),
+        }
+    }
+    bridge_format::visit_mut(&mut chain, &mut |c| match c {
+        bridge::MessageContent::At { id, name }
+        | bridge::MessageContent::ChannelMention { id, name }
+        | bridge::MessageContent::RoleMention { id, name } => {
+            if let Some(known) = names.get(id) {
+                *name = known.clone();
+            }
+        }
+        _ => {}
+    });
+    // 附件与 bot 发送的 embed；链接自动生成的预览已在文本中
+    chain.extend(msg.attachments.iter().map(bridge::MessageContent::from));
+    chain.extend(msg.sticker_items.iter().map(bridge::MessageContent::from));
+    chain.extend(
+        msg.embeds
+            .iter()
+            .filter(|e| e.kind.as_deref() == Some("rich") && e.url.is_some())
+            .map(bridge::MessageContent::from),
+    );
+    if chain.is_empty() {
+        chain.push(bridge::MessageContent::Plain { text: String::new() });
+    }
+    chain
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cachedGuild() {
+        let guild = GuildPermissions { owner: UserId(1), roles: HashMap::new() };
+        let now = Instant::now();
+        assert!(cached_guild(77, now).is_none());
+        GUILDS.lock().unwrap().insert(77, (now, guild));
+        assert_eq!(cached_guild(77, now).unwrap().owner, UserId(1));
+        // 过期后需重新请求
+        assert!(cached_guild(77, now + GUILD_CACHE_TTL).is_none());
+    }
+
+    fn webhook(id: u64, name: &str, token: Option<&str>) -> Webhook {
+        serde_json::from_value(serde_json::json!({
+            "id": id.to_string(), "type": 1, "name": name, "token": token, "channel_id": "7",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn reusableWebhook() {
+        assert!(reusable_webhook(vec![]).is_none());
+        // 只复用桥创建的、带 token 的 webhook
+        let webhooks = vec![
+            webhook(1, "other", Some("t")),
+            webhook(2, WEBHOOK_NAME, None),
+            webhook(3, WEBHOOK_NAME, Some("t")),
+        ];
+        assert_eq!(reusable_webhook(webhooks).unwrap().id.0, 3);
+        assert!(reusable_webhook(vec![webhook(1, "other", Some("t"))]).is_none());
+    }
+
+    #[test]
+    fn notFound() {
+        let response = |status: u16| {
+            serenity::Error::Http(Box::new(HttpError::UnsuccessfulRequest(serenity::http::error::ErrorResponse {
+                status_code: reqwest::StatusCode::from_u16(status).unwrap(),
+                url: reqwest::Url::parse("https://discord.com/api/v10/webhooks/1/t").unwrap(),
+                error: serde_json::from_value(serde_json::json!({"code": 10015, "message": "Unknown Webhook"})).unwrap(),
+            })))
+        };
+        assert!(is_not_found(&response(404)));
+        assert!(!is_not_found(&response(403)));
+        assert!(!is_not_found(&serenity::Error::Other("404")));
+    }
+
+    #[test]
+    fn retryAfter() {
+        use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), Duration::from_secs(1));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("2.5"));
+        assert_eq!(retry_after(&headers), Duration::from_millis(2500));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("3600"));
+        assert_eq!(retry_after(&headers), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn noMentions() {
+        let message = BridgeMessage {
+            id: "1".to_string(),
+            origin_id: None,
+            bridge_config: crate::config::test_config().bridges[0].clone(),
+            message_chain: vec![bridge::MessageContent::Plain { text: "@everyone hi".to_string() }],
+            user: bridge::User {
+                name: "abc".to_string(),
+                avatar_url: None,
+                id: 1,
+                permission: Default::default(),
+            },
+            origin: None,
+            target: None,
+            reply_to: None,
+        };
+        let mut builder = ExecuteWebhook::default();
+        fill_webhook(&mut builder, &message, &message.user, &[]);
+        let body = serenity::json::hashmap_to_json_map(builder.0);
+        assert_eq!(body["allowed_mentions"], serde_json::json!({"parse": []}));
+    }
+
+    #[test]
+    fn cachedWebhook() {
+        let webhook: Webhook = serde_json::from_value(serde_json::json!({
+            "id": "42", "type": 1, "token": "t", "channel_id": "7",
+        }))
+        .unwrap();
+        let discord = DiscordBridgeConfig { id: 42, token: "t".to_string(), channelId: 7, ..Default::default() };
+        assert!(cached_webhook("test-cache", &discord).is_none());
+        WEBHOOKS.lock().unwrap().insert("test-cache".to_string(), webhook);
+        assert_eq!(cached_webhook("test-cache", &discord).unwrap().id.0, 42);
+        // 配置换了 webhook 后不再使用旧的缓存
+        let changed = DiscordBridgeConfig { id: 43, ..discord.clone() };
+        assert!(cached_webhook("test-cache", &changed).is_none());
+        assert!(cached_webhook("other", &discord).is_none());
+    }
+
+    #[test]
+    fn ownWebhookWhenSaveFails() {
+        let mut config = crate::config::test_config();
+        config.path = "./no-such-dir/config.json".into();
+        let config: SharedConfig = Arc::new(ArcSwap::from_pointee(config));
+        let discord = config.load().bridges[0].discord.clone();
+        let webhook = DiscordBridgeConfig { id: 4301, token: "new".to_string(), ..discord.clone() };
+        assert!(!is_own_webhook(&config.load(), 4301));
+        let adopted = adopt(&config, &discord, webhook);
+        assert_eq!(adopted.id, 4301);
+        // 写回失败，配置没有变化，但仍识别为桥自己的 webhook
+        assert_eq!(config.load().bridges[0].discord.id, discord.id);
+        assert!(is_own_webhook(&config.load(), 4301));
+        assert!(is_own_webhook(&config.load(), discord.id));
+        assert!(!is_own_webhook(&config.load(), 4302));
     }
 }
@@ -0,0 +1,328 @@
+//! qq 表情与 emoji 的对照
+//!
+//! 内置常用表情的对照，可以用 JSON 文件补充或覆盖：
+//! `[{"face": 14, "name": "微笑", "emoji": "🙂"}, {"face": 179, "name": "doge", "emoji": "<:doge:123>"}]`，
+//! emoji 为 unicode 表情或 discord 自定义表情
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::bridge::{MessageChain, MessageContent};
+use crate::bridge_format;
+use crate::config::SharedConfig;
+use crate::config_watch;
+
+/// 内置对照：表情 id、名称、emoji
+const BUILTIN: &[(u16, &str, &str)] = &[
+    (0, "惊讶", "😮"),
+    (1, "撇嘴", "😣"),
+    (2, "色", "😍"),
+    (3, "发呆", "😳"),
+    (4, "得意", "😎"),
+    (5, "流泪", "😢"),
+    (6, "害羞", "☺️"),
+    (7, "闭嘴", "🤐"),
+    (8, "睡", "😴"),
+    (9, "大哭", "😭"),
+    (10, "尴尬", "😅"),
+    (11, "发怒", "😡"),
+    (12, "调皮", "😜"),
+    (13, "呲牙", "😁"),
+    (14, "微笑", "🙂"),
+    (15, "难过", "🙁"),
+    (16, "酷", "😎"),
+    (18, "抓狂", "😫"),
+    (19, "吐", "🤮"),
+    (20, "偷笑", "🤭"),
+    (21, "可爱", "😊"),
+    (22, "白眼", "🙄"),
+    (23, "傲慢", "😤"),
+    (24, "饥饿", "🤤"),
+    (25, "困", "😪"),
+    (26, "惊恐", "😱"),
+    (27, "流汗", "😓"),
+    (28, "憨笑", "😄"),
+    (29, "悠闲", "😌"),
+    (30, "奋斗", "💪"),
+    (31, "咒骂", "🤬"),
+    (32, "疑问", "❓"),
+    (33, "嘘", "🤫"),
+    (34, "晕", "😵"),
+    (35, "折磨", "😖"),
+    (36, "衰", "😩"),
+    (37, "骷髅", "💀"),
+    (38, "敲打", "🔨"),
+    (39, "再见", "👋"),
+    (41, "发抖", "🥶"),
+    (42, "爱情", "💑"),
+    (46, "猪头", "🐷"),
+    (49, "拥抱", "🤗"),
+    (53, "蛋糕", "🎂"),
+    (54, "闪电", "⚡"),
+    (55, "炸弹", "💣"),
+    (56, "刀", "🔪"),
+    (57, "足球", "⚽"),
+    (59, "便便", "💩"),
+    (60, "咖啡", "☕"),
+    (61, "饭", "🍚"),
+    (63, "玫瑰", "🌹"),
+    (64, "凋谢", "🥀"),
+    (66, "爱心", "❤️"),
+    (67, "心碎", "💔"),
+    (69, "礼物", "🎁"),
+    (74, "太阳", "☀️"),
+    (75, "月亮", "🌙"),
+    (76, "赞", "👍"),
+    (77, "踩", "👎"),
+    (78, "握手", "🤝"),
+    (79, "胜利", "✌️"),
+    (85, "飞吻", "😘"),
+    (89, "西瓜", "🍉"),
+    (96, "冷汗", "😰"),
+    (97, "擦汗", "😥"),
+    (99, "鼓掌", "👏"),
+    (101, "坏笑", "😏"),
+    (104, "哈欠", "🥱"),
+    (106, "委屈", "🥺"),
+    (107, "快哭了", "😿"),
+    (108, "阴险", "😈"),
+    (109, "亲亲", "😚"),
+    (110, "吓", "😨"),
+    (112, "菜刀", "🔪"),
+    (114, "篮球", "🏀"),
+    (116, "示爱", "💋"),
+    (118, "抱拳", "🙏"),
+    (120, "拳头", "👊"),
+    (121, "差劲", "👎"),
+    (122, "爱你", "🤟"),
+    (123, "NO", "🙅"),
+    (124, "OK", "👌"),
+    (129, "挥手", "👋"),
+    (144, "喝彩", "🎉"),
+    (147, "棒棒糖", "🍭"),
+    (171, "茶", "🍵"),
+    (173, "泪奔", "😭"),
+    (174, "无奈", "😔"),
+    (175, "卖萌", "😝"),
+    (178, "斜眼笑", "😏"),
+    (179, "doge", "🐶"),
+    (180, "惊喜", "🤩"),
+    (182, "笑哭", "😂"),
+    (187, "幽灵", "👻"),
+    (201, "点赞", "👍"),
+    (212, "托腮", "🤔"),
+    (262, "脑阔疼", "🤕"),
+    (264, "捂脸", "🤦"),
+    (266, "哦哟", "😲"),
+    (268, "问号脸", "❓"),
+    (269, "暗中观察", "👀"),
+    (270, "emm", "😑"),
+    (271, "吃瓜", "🍉"),
+    (272, "呵呵哒", "🙃"),
+    (277, "汪汪", "🐶"),
+    (307, "喵喵", "🐱"),
+];
+
+static FACES: Lazy<ArcSwap<FaceMap>> = Lazy::new(|| ArcSwap::from_pointee(FaceMap::builtin()));
+
+/// 一个表情的对照
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FaceEntry {
+    /// qq 表情 id
+    pub face: u16,
+    pub name: String,
+    /// unicode 表情，或 discord 自定义表情 `<:name:id>`
+    pub emoji: String,
+}
+
+#[derive(Debug, Default)]
+pub struct FaceMap {
+    by_face: HashMap<u16, FaceEntry>,
+    /// discord 自定义表情 id 对应的 qq 表情
+    by_emoji: HashMap<u64, u16>,
+}
+
+impl FaceMap {
+    /// 内置对照
+    pub fn builtin() -> Self {
+        let mut map = FaceMap::default();
+        for (face, name, emoji) in BUILTIN {
+            map.insert(FaceEntry { face: *face, name: name.to_string(), emoji: emoji.to_string() });
+        }
+        map
+    }
+
+    /// 内置对照加上文件中的对照；文件中的优先
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: 无法读取表情对照: {}", path.display(), e))?;
+        let entries: Vec<FaceEntry> =
+            serde_json::from_str(&text).map_err(|e| format!("{}: 表情对照格式有误: {}", path.display(), e))?;
+        let mut map = Self::builtin();
+        for entry in entries {
+            map.insert(entry);
+        }
+        Ok(map)
+    }
+
+    fn insert(&mut self, entry: FaceEntry) {
+        if let Some(MessageContent::Emoji { id, .. }) = custom_emoji(&entry.emoji) {
+            self.by_emoji.insert(id, entry.face);
+        }
+        self.by_face.insert(entry.face, entry);
+    }
+
+    pub fn get(&self, face: u16) -> Option<&FaceEntry> {
+        self.by_face.get(&face)
+    }
+
+    /// 补全 qq 表情的名称；mirai 收到的表情可能没有名称
+    pub fn fill_names(&self, chain: &mut MessageChain) {
+        bridge_format::visit_mut(chain, &mut |c| {
+            if let MessageContent::Face { id, name } = c {
+                if let (true, Some(entry)) = (name.is_empty(), self.get(*id)) {
+                    *name = entry.name.clone();
+                }
+            }
+        });
+    }
+
+    /// 转为 discord 上显示的表情；没有对照的保持不变
+    pub fn to_discord(&self, chain: &mut MessageChain) {
+        bridge_format::visit_mut(chain, &mut |c| {
+            if let MessageContent::Face { id, .. } = c {
+                if let Some(entry) = self.get(*id) {
+                    *c = custom_emoji(&entry.emoji).unwrap_or_else(|| MessageContent::Plain { text: entry.emoji.clone() });
+                }
+            }
+        });
+    }
+
+    /// discord 自定义表情转为对照的 qq 表情；unicode 表情 qq 可以直接显示，不转换
+    pub fn to_qq(&self, chain: &mut MessageChain) {
+        bridge_format::visit_mut(chain, &mut |c| {
+            if let MessageContent::Emoji { id, .. } = c {
+                if let Some(entry) = self.by_emoji.get(id).and_then(|face| self.get(*face)) {
+                    *c = MessageContent::Face { id: entry.face, name: entry.name.clone() };
+                }
+            }
+        });
+    }
+}
+
+/// 解析 discord 自定义表情 `<:name:id>`
+fn custom_emoji(text: &str) -> Option<MessageContent> {
+    match bridge_format::parse_markdown(text.trim()).as_slice() {
+        [emoji @ MessageContent::Emoji { .. }] => Some(emoji.clone()),
+        _ => None,
+    }
+}
+
+/// 加载表情对照；不配置文件时使用内置对照
+pub fn init(path: Option<&str>) -> Result<(), String> {
+    let map = match path {
+        Some(path) => FaceMap::load(path)?,
+        None => FaceMap::builtin(),
+    };
+    FACES.store(Arc::new(map));
+    Ok(())
+}
+
+/// 配置的对照文件变化时重新加载；加载失败时继续使用当前对照
+pub async fn watch(config: SharedConfig) {
+    loop {
+        config_watch::changed(&config, |c| c.faceMapFile.clone()).await;
+        match init(config.load().faceMapFile.as_deref()) {
+            Ok(()) => info!("表情对照已重新加载"),
+            Err(e) => error!("表情对照加载失败, 继续使用当前对照: {}", e),
+        }
+    }
+}
+
+/// 当前的表情对照
+pub fn faces() -> Arc<FaceMap> {
+    FACES.load_full()
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+
+    fn face(id: u16) -> MessageContent {
+        MessageContent::Face { id, name: String::new() }
+    }
+
+    #[test]
+    fn builtinFaces() {
+        let faces = FaceMap::builtin();
+        assert_eq!(faces.get(14).unwrap().name, "微笑");
+        let mut chain = vec![face(14), face(9999)];
+        faces.fill_names(&mut chain);
+        assert_eq!(chain[0], MessageContent::Face { id: 14, name: "微笑".to_string() });
+        faces.to_discord(&mut chain);
+        assert_eq!(chain, vec![MessageContent::Plain { text: "🙂".to_string() }, face(9999)]);
+    }
+
+    /// 配置中的对照文件变化后重新加载
+    #[test]
+    fn reloadOnConfigChange() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("faces.json");
+        std::fs::write(&path, r#"[{"face": 9998, "name": "测试", "emoji": "🧪"}]"#).unwrap();
+        tokio_test::block_on(async {
+            let config: SharedConfig = Arc::new(ArcSwap::from_pointee(crate::config::test_config()));
+            let update = config.clone();
+            let modify = async move {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                let mut next = (**update.load()).clone();
+                next.faceMapFile = Some(path.to_string_lossy().into_owned());
+                config_watch::replace(&update, next);
+                for _ in 0..50 {
+                    if faces().get(9998).is_some() {
+                        return;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                }
+                panic!("表情对照没有重新加载");
+            };
+            tokio::select! {
+                _ = watch(config) => unreachable!(),
+                _ = modify => {}
+            }
+        });
+    }
+
+    #[test]
+    fn faceMapFile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("faces.json");
+        std::fs::write(
+            &path,
+            r#"[{"face": 14, "name": "微笑", "emoji": "😀"}, {"face": 179, "name": "doge", "emoji": "<:doge:123>"}]"#,
+        )
+        .unwrap();
+        let faces = FaceMap::load(&path).unwrap();
+        assert_eq!(faces.get(14).unwrap().emoji, "😀");
+        assert_eq!(faces.get(13).unwrap().name, "呲牙");
+
+        let doge = MessageContent::Emoji { id: 123, name: "doge".to_string(), animated: false };
+        let mut chain = vec![face(179)];
+        faces.to_discord(&mut chain);
+        assert_eq!(chain, vec![doge.clone()]);
+        faces.to_qq(&mut chain);
+        assert_eq!(chain, vec![MessageContent::Face { id: 179, name: "doge".to_string() }]);
+        // 没有对照的自定义表情保持不变
+        let mut chain = vec![MessageContent::Emoji { id: 5, name: "kek".to_string(), animated: false }];
+        faces.to_qq(&mut chain);
+        assert!(matches!(chain[0], MessageContent::Emoji { id: 5, .. }));
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(FaceMap::load(&path).unwrap_err().contains("格式有误"));
+    }
+}
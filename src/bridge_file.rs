@@ -0,0 +1,92 @@
+//! 文件转发：按大小限制下载附件，供 qq 群文件与 discord 附件互相转发
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+/// 下载的超时时间，包括读取内容；对方不响应时不会一直等待
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| client(DOWNLOAD_TIMEOUT));
+
+fn client(timeout: Duration) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .expect("无法创建 http 客户端")
+}
+
+/// 下载文件；超过大小限制时不下载
+pub async fn download(url: &str, max_size: u64) -> Result<Vec<u8>, String> {
+    fetch(&CLIENT, url, max_size).await
+}
+
+async fn fetch(client: &reqwest::Client, url: &str, max_size: u64) -> Result<Vec<u8>, String> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| format!("下载失败 {}: {}", url, e))?;
+    if response.content_length().map(|len| len > max_size).unwrap_or(false) {
+        return Err(format!("文件超过 {} 字节", max_size));
+    }
+    let data = response.bytes().await.map_err(|e| format!("下载失败 {}: {}", url, e))?;
+    if data.len() as u64 > max_size {
+        return Err(format!("文件超过 {} 字节", max_size));
+    }
+    Ok(data.to_vec())
+}
+
+/// 已知大小的文件是否可以转发；大小未知时下载时再检查
+pub fn within_limit(size: u64, max_size: u64) -> bool {
+    max_size > 0 && size <= max_size
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// 在本地启动只响应一次的 http 服务
+    async fn serve(body: &'static [u8]) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = socket.read(&mut buf).await;
+            let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            socket.write_all(header.as_bytes()).await.unwrap();
+            socket.write_all(body).await.unwrap();
+        });
+        format!("http://{}/a.zip", addr)
+    }
+
+    #[tokio::test]
+    async fn downloadLimit() {
+        let url = serve(b"0123456789").await;
+        assert_eq!(download(&url, 10).await.unwrap(), b"0123456789");
+        let url = serve(b"0123456789").await;
+        assert!(download(&url, 9).await.unwrap_err().contains("超过"));
+
+        assert!(within_limit(10, 10));
+        assert!(!within_limit(11, 10));
+        // 0 表示不转发文件本身
+        assert!(!within_limit(0, 0));
+    }
+
+    /// 对方接受连接但不响应时按超时结束
+    #[tokio::test]
+    async fn downloadTimeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+        let client = client(Duration::from_millis(200));
+        let err = fetch(&client, &format!("http://{}/a.zip", addr), 10).await.unwrap_err();
+        assert!(err.contains("下载失败"));
+    }
+}
@@ -0,0 +1,380 @@
+//! 消息格式转换：discord markdown 与桥消息、qq 纯文本之间的转换
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::bridge::{MessageChain, MessageContent, TextStyle};
+
+/// 可以用 `\` 转义的 markdown 字符
+const ESCAPABLE: &str = "\\*_~|`<>#:";
+
+/// 提及与自定义表情：`<@id>` `<@!id>` `<@&id>` `<#id>` `<:name:id>` `<a:name:id>`
+static TAG: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^<(?:@(!|&)?(\d+)|#(\d+)|(a?):(\w+):(\d+))>").unwrap());
+
+/// 解析 discord 消息文本；提及的名称先以 id 填充，由调用方解析
+pub fn parse_markdown(text: &str) -> MessageChain {
+    let mut chain = vec![];
+    let mut plain = String::new();
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        if let Some(c) = rest.strip_prefix('\\').and_then(|r| r.chars().next()).filter(|c| ESCAPABLE.contains(*c)) {
+            plain.push(c);
+            i += 1 + c.len_utf8();
+            continue;
+        }
+        // 链接中的 `_` `*` 不是格式
+        if rest.starts_with("http://") || rest.starts_with("https://") {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            plain.push_str(&rest[..end]);
+            i += end;
+            continue;
+        }
+        if let Some((content, len)) = token(text, i) {
+            if !plain.is_empty() {
+                chain.push(MessageContent::Plain { text: std::mem::take(&mut plain) });
+            }
+            chain.push(content);
+            i += len;
+            continue;
+        }
+        let c = rest.chars().next().unwrap();
+        plain.push(c);
+        i += c.len_utf8();
+    }
+    if !plain.is_empty() {
+        chain.push(MessageContent::Plain { text: plain });
+    }
+    chain
+}
+
+/// 解析 text[start..] 开头的格式，返回内容与占用的长度
+fn token(text: &str, start: usize) -> Option<(MessageContent, usize)> {
+    let rest = &text[start..];
+    if rest.starts_with('<') {
+        let cap = TAG.captures(rest)?;
+        let len = cap.get(0)?.end();
+        let content = if let Some(id) = cap.get(2) {
+            let id = id.as_str().parse().ok()?;
+            match cap.get(1).map(|m| m.as_str()) {
+                Some("&") => MessageContent::RoleMention { id, name: id.to_string() },
+                _ => MessageContent::At { id, name: id.to_string() },
+            }
+        } else if let Some(id) = cap.get(3) {
+            let id = id.as_str().parse().ok()?;
+            MessageContent::ChannelMention { id, name: id.to_string() }
+        } else {
+            MessageContent::Emoji {
+                id: cap[6].parse().ok()?,
+                name: cap[5].to_string(),
+                animated: &cap[4] == "a",
+            }
+        };
+        return Some((content, len));
+    }
+    if rest.starts_with("```") {
+        let (inner, len) = delimited(rest, "```")?;
+        let (language, code) = match inner.split_once('\n') {
+            Some((lang, code)) if is_language(lang) => (Some(lang.to_string()), code),
+            _ => (None, inner),
+        };
+        let code = code.strip_prefix('\n').unwrap_or(code);
+        let code = code.strip_suffix('\n').unwrap_or(code);
+        let content = MessageContent::Code { text: code.to_string(), language, block: true };
+        return Some((content, len));
+    }
+    if rest.starts_with('`') {
+        let marker = if rest.starts_with("``") { "``" } else { "`" };
+        let (inner, len) = delimited(rest, marker)?;
+        let content = MessageContent::Code { text: inner.trim().to_string(), language: None, block: false };
+        return Some((content, len));
+    }
+    const STYLES: [(&str, TextStyle); 4] = [
+        ("||", TextStyle::Spoiler),
+        ("**", TextStyle::Bold),
+        ("__", TextStyle::Underline),
+        ("~~", TextStyle::Strikethrough),
+    ];
+    for (marker, style) in STYLES {
+        if rest.starts_with(marker) {
+            if let Some((inner, len)) = delimited(rest, marker) {
+                return Some((MessageContent::Styled { style, chain: parse_markdown(inner) }, len));
+            }
+        }
+    }
+    let (inner, len) = if let Some(after) = rest.strip_prefix('*') {
+        // `* ` 开头的是列表，不是斜体
+        if after.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let (inner, len) = delimited(rest, "*")?;
+        if inner.ends_with(char::is_whitespace) {
+            return None;
+        }
+        (inner, len)
+    } else if rest.starts_with('_') {
+        // `_` 只在词的边界生效，如 snake_case 不是斜体
+        if text[..start].ends_with(char::is_alphanumeric) {
+            return None;
+        }
+        let (inner, len) = delimited(rest, "_")?;
+        if rest[len..].starts_with(char::is_alphanumeric) {
+            return None;
+        }
+        (inner, len)
+    } else {
+        return None;
+    };
+    Some((MessageContent::Styled { style: TextStyle::Italic, chain: parse_markdown(inner) }, len))
+}
+
+/// 取以 marker 开始与结束的内容，内容不能为空；结束标记取连续标记的最后一个，如 `***a***`
+fn delimited<'a>(text: &'a str, marker: &str) -> Option<(&'a str, usize)> {
+    let open = marker.len();
+    let first = text[open..].chars().next()?;
+    let from = open + first.len_utf8();
+    let mut close = from + text[from..].find(marker)?;
+    let repeat = marker.chars().next()?;
+    while text[close + marker.len()..].starts_with(repeat) {
+        close += repeat.len_utf8();
+    }
+    Some((&text[open..close], close + marker.len()))
+}
+
+/// 代码块第一行是否为语言名
+fn is_language(line: &str) -> bool {
+    !line.is_empty() && line.chars().all(|c| c.is_ascii_alphanumeric() || "+-#_.".contains(c))
+}
+
+/// 遍历消息内容，包括格式中嵌套的内容
+pub fn visit_mut(chain: &mut MessageChain, f: &mut impl FnMut(&mut MessageContent)) {
+    for content in chain.iter_mut() {
+        if let MessageContent::Styled { chain, .. } = content {
+            visit_mut(chain, f);
+        }
+        f(content);
+    }
+}
+
+/// 转为 qq 显示的纯文本
+pub fn plain_text(chain: &MessageChain) -> String {
+    let mut text = String::new();
+    for content in chain {
+        if let MessageContent::Code { block: true, .. } = content {
+            // 代码块单独成行
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+        }
+        text += &plain_content(content);
+    }
+    text
+}
+
+/// 单个消息内容转为纯文本
+pub fn plain_content(content: &MessageContent) -> String {
+    match content {
+        MessageContent::Plain { text } => text.clone(),
+        MessageContent::At { name, .. } | MessageContent::RoleMention { name, .. } => format!("@{}", name),
+        MessageContent::ChannelMention { name, .. } => format!("#{}", name),
+        MessageContent::Emoji { name, .. } => format!(":{}:", name),
+        MessageContent::Styled { style: TextStyle::Spoiler, chain } => format!("[剧透: {}]", plain_text(chain)),
+        MessageContent::Styled { chain, .. } => plain_text(chain),
+        MessageContent::Code { text, .. } => text.clone(),
+        MessageContent::Image { .. } => "[图片]".to_string(),
+        MessageContent::AtAll => "@全体成员".to_string(),
+        MessageContent::Face { name, .. } => format!("[{}]", name),
+        MessageContent::Reply { .. } => String::new(),
+        MessageContent::File { name, .. } => format!("[文件] {}", name),
+        MessageContent::Voice { .. } => "[语音]".to_string(),
+        MessageContent::Video { url, .. } => format!("[视频] {}", url),
+        MessageContent::LinkPreview { url, title, .. } => match title {
+            Some(title) => format!("[{}] {}", title, url),
+            None => url.clone(),
+        },
+        MessageContent::Forward { nodes } => {
+            let mut text = String::from("[合并转发]");
+            for node in nodes {
+                text += &format!("\n{}: {}", node.sender_name, plain_text(&node.chain));
+            }
+            text
+        }
+    }
+}
+
+/// 转为 discord markdown；纯文本中的格式字符会被转义
+pub fn markdown(chain: &MessageChain) -> String {
+    chain.iter().map(markdown_content).collect()
+}
+
+/// 单个消息内容转为 discord markdown
+pub fn markdown_content(content: &MessageContent) -> String {
+    match content {
+        MessageContent::Plain { text } => escape_markdown(text),
+        // 不提及 discord 上的用户与身份组，以免 qq 的消息打扰
+        MessageContent::At { name, .. } | MessageContent::RoleMention { name, .. } => {
+            format!("@{}", escape_markdown(name))
+        }
+        MessageContent::ChannelMention { id, .. } => format!("<#{}>", id),
+        MessageContent::Emoji { id, name, animated } => {
+            format!("<{}:{}:{}>", if *animated { "a" } else { "" }, name, id)
+        }
+        MessageContent::Styled { style, chain } => {
+            let marker = match style {
+                TextStyle::Bold => "**",
+                TextStyle::Italic => "*",
+                TextStyle::Underline => "__",
+                TextStyle::Strikethrough => "~~",
+                TextStyle::Spoiler => "||",
+            };
+            format!("{}{}{}", marker, markdown(chain), marker)
+        }
+        MessageContent::Code { text, language, block: true } => {
+            format!("```{}\n{}\n```", language.as_deref().unwrap_or(""), text)
+        }
+        MessageContent::Code { text, .. } if text.contains('`') => format!("`` {} ``", text),
+        MessageContent::Code { text, .. } => format!("`{}`", text),
+        MessageContent::Image { url } => url.clone().unwrap_or_default(),
+        // 不提及 discord 上的所有人
+        MessageContent::AtAll => "@全体成员".to_string(),
+        MessageContent::Face { name, .. } => format!("[{}]", escape_markdown(name)),
+        MessageContent::Reply { .. } => String::new(),
+        MessageContent::File { name, url, .. } => match url {
+            Some(url) => format!("[{}]({})", escape_markdown(name), url),
+            None => format!("[文件] {}", escape_markdown(name)),
+        },
+        MessageContent::Voice { url, .. } => url.clone().unwrap_or_else(|| "[语音]".to_string()),
+        MessageContent::Video { url, .. } | MessageContent::LinkPreview { url, .. } => url.clone(),
+        MessageContent::Forward { nodes } => {
+            let mut text = String::from("[合并转发]");
+            for node in nodes {
+                text += &format!("\n> **{}**: {}", escape_markdown(&node.sender_name), markdown(&node.chain));
+            }
+            text
+        }
+    }
+}
+
+/// 转义 qq 文本中会被 discord 当作格式的字符
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut line_start = true;
+    for c in text.chars() {
+        let special = matches!(c, '\\' | '*' | '_' | '~' | '|' | '`' | '<')
+            || (line_start && matches!(c, '>' | '#' | '-'));
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        line_start = c == '\n' || (line_start && c.is_whitespace());
+    }
+    escaped
+}
+
+/// discord 自定义表情的图片地址
+pub fn emoji_url(id: u64, animated: bool) -> String {
+    format!("https://cdn.discordapp.com/emojis/{}.{}", id, if animated { "gif" } else { "png" })
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+
+    fn plain(text: &str) -> MessageContent {
+        MessageContent::Plain { text: text.to_string() }
+    }
+
+    fn styled(style: TextStyle, chain: MessageChain) -> MessageContent {
+        MessageContent::Styled { style, chain }
+    }
+
+    #[test]
+    fn parseStyles() {
+        assert_eq!(
+            parse_markdown("a **b** ||c|| ~~d~~ __e__ *f* _g_"),
+            vec![
+                plain("a "),
+                styled(TextStyle::Bold, vec![plain("b")]),
+                plain(" "),
+                styled(TextStyle::Spoiler, vec![plain("c")]),
+                plain(" "),
+                styled(TextStyle::Strikethrough, vec![plain("d")]),
+                plain(" "),
+                styled(TextStyle::Underline, vec![plain("e")]),
+                plain(" "),
+                styled(TextStyle::Italic, vec![plain("f")]),
+                plain(" "),
+                styled(TextStyle::Italic, vec![plain("g")]),
+            ]
+        );
+        assert_eq!(
+            parse_markdown("***x***"),
+            vec![styled(TextStyle::Bold, vec![styled(TextStyle::Italic, vec![plain("x")])])]
+        );
+        // 不成对的标记、词中的下划线、链接、转义都保持原样
+        assert_eq!(parse_markdown("2 * 3 = 6"), vec![plain("2 * 3 = 6")]);
+        assert_eq!(parse_markdown("snake_case_name **"), vec![plain("snake_case_name **")]);
+        assert_eq!(parse_markdown("https://a.com/x_y_z"), vec![plain("https://a.com/x_y_z")]);
+        assert_eq!(parse_markdown(r"\*\*a\*\*"), vec![plain("**a**")]);
+    }
+
+    #[test]
+    fn parseCode() {
+        assert_eq!(
+            parse_markdown("run `a **b**` then\n```rust\nfn main() {}\n```"),
+            vec![
+                plain("run "),
+                MessageContent::Code { text: "a **b**".to_string(), language: None, block: false },
+                plain(" then\n"),
+                MessageContent::Code {
+                    text: "fn main() {}".to_string(),
+                    language: Some("rust".to_string()),
+                    block: true
+                },
+            ]
+        );
+        assert_eq!(
+            parse_markdown("```a b```"),
+            vec![MessageContent::Code { text: "a b".to_string(), language: None, block: true }]
+        );
+    }
+
+    #[test]
+    fn parseTags() {
+        assert_eq!(
+            parse_markdown("<@!1> <@&2> <#3> <:kek:4><a:party:5> <not a tag>"),
+            vec![
+                MessageContent::At { id: 1, name: "1".to_string() },
+                plain(" "),
+                MessageContent::RoleMention { id: 2, name: "2".to_string() },
+                plain(" "),
+                MessageContent::ChannelMention { id: 3, name: "3".to_string() },
+                plain(" "),
+                MessageContent::Emoji { id: 4, name: "kek".to_string(), animated: false },
+                MessageContent::Emoji { id: 5, name: "party".to_string(), animated: true },
+                plain(" <not a tag>"),
+            ]
+        );
+    }
+
+    #[test]
+    fn renderPlainText() {
+        let mut chain = parse_markdown("**hi** <#3> ||secret|| <:kek:4>\n```\ncode\n```");
+        visit_mut(&mut chain, &mut |c| {
+            if let MessageContent::ChannelMention { name, .. } = c {
+                *name = "general".to_string();
+            }
+        });
+        assert_eq!(plain_text(&chain), "hi #general [剧透: secret] :kek:\ncode");
+    }
+
+    #[test]
+    fn renderMarkdown() {
+        assert_eq!(escape_markdown("a*b_c ~~d~~ `e` |f| <@1>"), r"a\*b\_c \~\~d\~\~ \`e\` \|f\| \<@1>");
+        assert_eq!(escape_markdown("> quote\n# title\n - item"), "\\> quote\n\\# title\n \\- item");
+        let text = "**bold** *it* ||sp|| `c` <#3> <:kek:4>";
+        assert_eq!(markdown(&parse_markdown(text)), text);
+        assert_eq!(markdown(&vec![plain("1*2")]), r"1\*2");
+    }
+}
@@ -1,27 +1,271 @@
-use std::fs;
-use std::time::{SystemTime, UNIX_EPOCH};
-use chrono::{DateTime, Utc, Local};
-pub struct BridgeLog {
+//! 日志：分级、按模块过滤、JSON 输出、按大小/时间切割日志文件
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
+use chrono::{DateTime, Local};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
+
+use crate::config::{LogConfig, LogFormat, LogRotation};
+
+type BoxLayer = Box<dyn Layer<Registry> + Send + Sync>;
+/// 取当前时间，测试时可替换
+type Clock = Box<dyn Fn() -> DateTime<Local> + Send>;
+
+/// 初始化全局日志
+/// - 设置了环境变量 `RUST_LOG` 时以环境变量为准，否则使用配置中的级别
+pub fn init(config: &LogConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(directives(config))?,
+    };
+
+    let mut layers: Vec<BoxLayer> = Vec::new();
+    if config.console {
+        layers.push(format_layer(config.format, io::stdout, true));
+    }
+    if let Some(dir) = &config.dir {
+        let file = RollingFile::new(dir, &config.fileName, config.maxSize, config.rotation, config.maxFiles)?;
+        layers.push(format_layer(config.format, Mutex::new(file), false));
+    }
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .try_init()?;
+    Ok(())
+}
+
+/// 构建单个输出层
+fn format_layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxLayer
+where
+    W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
+
+/// 将配置转换为过滤指令
+/// - 模块名不带路径时（如 `bridge_qq`），同时匹配本程序内同名模块
+fn directives(config: &LogConfig) -> String {
+    let mut list = vec![config.level.clone()];
+    let mut targets: Vec<(&String, &String)> = config.targets.iter().collect();
+    targets.sort();
+    for (target, level) in targets {
+        list.push(format!("{target}={level}"));
+        if !target.contains("::") {
+            list.push(format!("{}::{target}={level}", env!("CARGO_CRATE_NAME")));
+        }
+    }
+    list.join(",")
+}
+
+/// 可切割的日志文件
+/// - 超过 `max_size` 字节，或跨越切割周期时，将当前文件重命名为 `<file_name>.<时间>` 并新建文件
+/// - 最多保留 `max_files` 个历史文件
+pub struct RollingFile {
+    dir: PathBuf,
+    file_name: String,
+    max_size: u64,
+    rotation: LogRotation,
+    max_files: usize,
+    file: File,
+    size: u64,
+    period: String,
+    clock: Clock,
+}
+
+impl RollingFile {
+    pub fn new(
+        dir: impl AsRef<Path>,
+        file_name: &str,
+        max_size: u64,
+        rotation: LogRotation,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(file_name);
+        let file = open_append(&path)?;
+        let meta = file.metadata()?;
+        // 沿用已有文件时，以其修改时间作为所属周期
+        let modified: DateTime<Local> = match meta.modified() {
+            Ok(t) => t.into(),
+            Err(_) => Local::now(),
+        };
+        Ok(RollingFile {
+            period: rotation.period(&modified),
+            dir,
+            file_name: file_name.to_string(),
+            max_size,
+            rotation,
+            max_files,
+            file,
+            size: meta.len(),
+            clock: Box::new(Local::now),
+        })
+    }
+
+    /// 替换取当前时间的方式
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: impl Fn() -> DateTime<Local> + Send + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// 写入前检查是否需要切割
+    fn rotate_if_needed(&mut self, now: &DateTime<Local>, incoming: usize) -> io::Result<()> {
+        let period = self.rotation.period(now);
+        let over_size = self.max_size > 0 && self.size > 0 && self.size + incoming as u64 > self.max_size;
+        if period == self.period && !over_size {
+            return Ok(());
+        }
+        self.file.flush()?;
+        let path = self.dir.join(&self.file_name);
+        if self.size > 0 {
+            let stamp = now.format("%Y%m%d-%H%M%S").to_string();
+            let mut archived = self.dir.join(format!("{}.{stamp}", self.file_name));
+            let mut seq = 1;
+            while archived.exists() {
+                archived = self.dir.join(format!("{}.{stamp}.{seq}", self.file_name));
+                seq += 1;
+            }
+            fs::rename(&path, &archived)?;
+        }
+        self.file = open_append(&path)?;
+        self.size = 0;
+        self.period = period;
+        self.prune()
+    }
+
+    /// 删除超出保留数量的历史文件
+    fn prune(&self) -> io::Result<()> {
+        let prefix = format!("{}.", self.file_name);
+        let mut archived: Vec<(std::time::SystemTime, PathBuf)> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .filter_map(|entry| {
+                let modified = entry.metadata().and_then(|m| m.modified()).ok()?;
+                Some((modified, entry.path()))
+            })
+            .collect();
+        if archived.len() <= self.max_files {
+            return Ok(());
+        }
+        archived.sort();
+        let remove = archived.len() - self.max_files;
+        for (_, path) in archived.into_iter().take(remove) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
 }
-impl BridgeLog {
-    pub fn write_log(content: &str) {
-        let mut log_content = fs::read_to_string("./bridge_log.log").unwrap();
-        let utc = Local::now().format("%Y-%m-%d %H:%M:%S");
-        let content = format!(r#"{log_content}
-===Start {utc}===
-{content}
-===End {utc}==="#);
-        fs::write("./bridge_log.log", content).unwrap();
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = (self.clock)();
+        self.rotate_if_needed(&now, buf.len())?;
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
     }
 }
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl LogRotation {
+    /// 取时间所属的切割周期
+    fn period(&self, time: &DateTime<Local>) -> String {
+        match self {
+            LogRotation::Never => String::new(),
+            LogRotation::Hourly => time.format("%Y-%m-%d %H").to_string(),
+            LogRotation::Daily => time.format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
 #[cfg(test)]
 #[allow(non_snake_case)]
 mod test {
     use super::*;
+    use chrono::{Duration, TimeZone};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn archived(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("bridge.log."))
+            .count()
+    }
+
+    #[test]
+    fn rotateBySize() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = RollingFile::new(dir.path(), "bridge.log", 16, LogRotation::Never, 10).unwrap();
+        file.write_all(b"0123456789").unwrap();
+        file.write_all(b"0123456789").unwrap();
+        file.write_all(b"0123456789").unwrap();
+        file.flush().unwrap();
+        assert_eq!(archived(dir.path()), 2);
+        assert_eq!(fs::read_to_string(dir.path().join("bridge.log")).unwrap(), "0123456789");
+    }
+
+    #[test]
+    fn rotateByTime() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = Arc::new(Mutex::new(Local.ymd(2022, 9, 1).and_hms(23, 59, 0)));
+        let clock = now.clone();
+        let mut file = RollingFile::new(dir.path(), "bridge.log", 0, LogRotation::Daily, 10)
+            .unwrap()
+            .with_clock(move || *clock.lock().unwrap());
+        file.write_all(b"day1").unwrap();
+        file.write_all(b"day1").unwrap();
+        assert_eq!(archived(dir.path()), 0);
+        *now.lock().unwrap() += Duration::minutes(30);
+        file.write_all(b"day2").unwrap();
+        file.flush().unwrap();
+        assert_eq!(archived(dir.path()), 1);
+        assert_eq!(fs::read_to_string(dir.path().join("bridge.log")).unwrap(), "day2");
+    }
+
+    #[test]
+    fn retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = RollingFile::new(dir.path(), "bridge.log", 4, LogRotation::Never, 2).unwrap();
+        for _ in 0..6 {
+            file.write_all(b"line").unwrap();
+        }
+        assert_eq!(archived(dir.path()), 2);
+    }
 
     #[test]
-    fn writeLog() {
-        BridgeLog::write_log("test");
+    fn filterDirectives() {
+        let mut targets = HashMap::new();
+        targets.insert("bridge_qq".to_string(), "debug".to_string());
+        targets.insert("mirai_rs".to_string(), "warn".to_string());
+        let config = LogConfig {
+            level: "info".to_string(),
+            targets,
+            ..LogConfig::default()
+        };
+        let directives = directives(&config);
+        assert_eq!(
+            directives,
+            "info,bridge_qq=debug,message_bridge_rs::bridge_qq=debug,mirai_rs=warn,message_bridge_rs::mirai_rs=warn"
+        );
+        assert!(EnvFilter::try_new(directives).is_ok());
     }
 }
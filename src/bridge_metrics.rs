@@ -0,0 +1,172 @@
+//! 运行指标：以 Prometheus 文本格式暴露在 `/metrics`
+use std::net::SocketAddr;
+
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tracing::{error, info};
+
+use crate::config::MetricsConfig;
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// 各端从平台收到、送入桥的消息数；标签: adapter, bridge
+    pub received: IntCounterVec,
+    /// 各端从桥收到、同步到平台的消息数；标签: adapter, bridge
+    pub sent: IntCounterVec,
+    /// 同步到平台失败次数；标签: adapter, bridge
+    pub delivery_failures: IntCounterVec,
+    /// 桥客户端待处理的消息数；标签: client
+    pub queue_depth: IntGaugeVec,
+    /// 因处理过慢被桥丢弃的消息数；标签: client
+    pub broadcast_lag: IntCounterVec,
+    /// mirai 拉取消息耗时；标签: result
+    pub mirai_poll_latency: HistogramVec,
+    /// discord webhook 调用耗时
+    pub webhook_latency: Histogram,
+    /// 各类缓存的条目数；标签: cache
+    pub cache_size: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("bridge".to_string()), None).unwrap();
+        let received = IntCounterVec::new(
+            Opts::new("messages_received_total", "Messages received from platforms"),
+            &["adapter", "bridge"],
+        )
+        .unwrap();
+        let sent = IntCounterVec::new(
+            Opts::new("messages_sent_total", "Messages delivered to platforms"),
+            &["adapter", "bridge"],
+        )
+        .unwrap();
+        let delivery_failures = IntCounterVec::new(
+            Opts::new("delivery_failures_total", "Messages failed to deliver"),
+            &["adapter", "bridge"],
+        )
+        .unwrap();
+        let queue_depth = IntGaugeVec::new(
+            Opts::new("queue_depth", "Messages waiting in a bridge client queue"),
+            &["client"],
+        )
+        .unwrap();
+        let broadcast_lag = IntCounterVec::new(
+            Opts::new("broadcast_lagged_total", "Messages dropped because a client lagged behind"),
+            &["client"],
+        )
+        .unwrap();
+        let mirai_poll_latency = HistogramVec::new(
+            HistogramOpts::new("mirai_poll_seconds", "Latency of mirai fetchMessage"),
+            &["result"],
+        )
+        .unwrap();
+        let webhook_latency = Histogram::with_opts(HistogramOpts::new(
+            "discord_webhook_seconds",
+            "Latency of discord webhook execution",
+        ))
+        .unwrap();
+        let cache_size = IntGaugeVec::new(Opts::new("cache_size", "Entries in caches"), &["cache"]).unwrap();
+
+        registry.register(Box::new(received.clone())).unwrap();
+        registry.register(Box::new(sent.clone())).unwrap();
+        registry.register(Box::new(delivery_failures.clone())).unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        registry.register(Box::new(broadcast_lag.clone())).unwrap();
+        registry.register(Box::new(mirai_poll_latency.clone())).unwrap();
+        registry.register(Box::new(webhook_latency.clone())).unwrap();
+        registry.register(Box::new(cache_size.clone())).unwrap();
+
+        Metrics {
+            registry,
+            received,
+            sent,
+            delivery_failures,
+            queue_depth,
+            broadcast_lag,
+            mirai_poll_latency,
+            webhook_latency,
+            cache_size,
+        }
+    }
+
+    /// 以文本格式导出全部指标
+    pub fn export(&self) -> String {
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            error!("导出指标失败: {:?}", e);
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.export(),
+    )
+}
+
+/// 开启指标服务；未开启或无法启动时一直挂起，不影响桥的运行
+pub async fn serve(config: MetricsConfig) {
+    if !config.enable {
+        return std::future::pending().await;
+    }
+    let addr: SocketAddr = match format!("{}:{}", config.host, config.port).parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            error!("指标服务地址错误: {:?}", e);
+            return std::future::pending().await;
+        }
+    };
+    info!("指标服务: http://{}/metrics", addr);
+    let app = Router::new().route("/metrics", get(metrics));
+    match axum::Server::try_bind(&addr) {
+        Ok(server) => {
+            if let Err(e) = server.serve(app.into_make_service()).await {
+                error!("指标服务退出: {:?}", e);
+            }
+        }
+        Err(e) => error!("指标服务无法监听 {}: {:?}", addr, e),
+    }
+    std::future::pending().await
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn export() {
+        METRICS.received.with_label_values(&["bridge_qq", "1-2"]).inc();
+        METRICS.cache_size.with_label_values(&["bind"]).set(3);
+        METRICS.webhook_latency.observe(0.2);
+        let text = METRICS.export();
+        assert!(text.contains(r#"bridge_messages_received_total{adapter="bridge_qq",bridge="1-2"} 1"#));
+        assert!(text.contains(r#"bridge_cache_size{cache="bind"} 3"#));
+        assert!(text.contains("bridge_discord_webhook_seconds_count 1"));
+    }
+
+    /// 端口被占用时不退出
+    #[tokio::test]
+    async fn portInUse() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = MetricsConfig {
+            enable: true,
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+        let served = tokio::time::timeout(std::time::Duration::from_millis(200), serve(config)).await;
+        assert!(served.is_err());
+    }
+}
@@ -1,96 +1,324 @@
-use crate::{bridge, Config};
+use crate::bridge::{BridgeClientPlatform, Channel};
+use crate::bridge_admin;
+use crate::bridge_cmd::{self, Cmd};
+use crate::bridge_face;
+use crate::bridge_file;
+use crate::bridge_format;
+use crate::bridge_user;
+use crate::bridge_voice::{self, AudioFormat};
+use crate::config::{BridgeConfig, ForwardMode, VoiceConfig};
+use crate::config_watch;
+use crate::bridge_metrics::METRICS;
+use crate::{bridge, SharedConfig};
 use mirai_rs::api::MessageEvent;
-use mirai_rs::message::{GroupMessage, MessageChain, MessageContent};
-use mirai_rs::EventHandler;
-use mirai_rs::Mirai;
+use mirai_rs::message::{GroupMessage, MessageChain, MessageContent, Permission};
+use mirai_rs::mirai_http::MiraiHttp;
+use mirai_rs::model::{SendGroupMessageResponse, UploadType};
+use mirai_rs::{EventHandler, HttpResult, Mirai};
+use once_cell::sync::OnceCell;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+/// 断线重连的等待时间
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// 连续连接失败时等待时间的上限
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+/// 桥使用的 qq 号
+pub const BOT_QQ: u32 = 3245538509;
+
 pub struct MiraiBridgeHandler {
-    pub config: Arc<Config>,
+    pub config: SharedConfig,
     pub bridge: Arc<bridge::BridgeClient>,
+    /// 认证后才能请求 mirai 接口，连接建立后设置
+    pub mirai: Arc<OnceCell<MiraiHttp>>,
 }
 
-pub async fn bridge_qq(bridge: Arc<bridge::BridgeClient>, mirai: mirai_rs::mirai_http::MiraiHttp) {
+pub async fn bridge_qq(config: SharedConfig, bridge: Arc<bridge::BridgeClient>, mirai: MiraiHttp) {
+    let mut rx = bridge.sender.subscribe();
     loop {
-        let message = bridge.sender.subscribe().recv().await.unwrap();
-        println!("[bridge_qq] 收到桥的消息, 同步到qq上");
-        println!("{:?}", message);
+        let message = match bridge.recv(&mut rx).await {
+            Some(message) => message,
+            None => break,
+        };
+        let bridge_id = message.bridge_config.id();
+        info!("收到桥的消息, 同步到qq上");
+        debug!("{:?}", message);
         let mut message_chain: MessageChain = vec![];
+        let user = bridge_user::resolve(&message);
 
         // 配置发送者头像
-        if let Some(_) = message.user.avatar_url {
+        if let Some(_) = user.avatar_url {
             message_chain.push(MessageContent::Image {
                 image_id: None,
-                url: message.user.avatar_url,
+                url: user.avatar_url,
                 path: None,
                 base64: None,
             });
         }
         // 配置发送者用户名
         message_chain.push(MessageContent::Plain {
-            text: format!("{}\n", message.user.name),
+            text: format!("{}\n", user.name),
         });
 
-        for chain in message.message_chain.iter() {
+        let voice_config = config.load().voiceConfig.clone();
+        // qq 的语音只能单独发送，文件上传到群文件
+        let mut voices = vec![];
+        let mut files = vec![];
+        let max_file_size = message.bridge_config.maxFileSize;
+        let mut contents = message.message_chain.clone();
+        bridge_face::faces().to_qq(&mut contents);
+        for chain in contents.iter() {
             match chain {
-                bridge::MessageContent::Plain { text } => {
-                    message_chain.push(MessageContent::Plain { text: text.clone() })
+                bridge::MessageContent::Voice { url: Some(url), .. } if voice_config.enable => {
+                    match upload_voice(&mirai, &voice_config, url, upload_type(&message)).await {
+                        Ok(voice) => voices.push(voice),
+                        Err(e) => {
+                            warn!("转发语音失败: {}", e);
+                            message_chain.push(MessageContent::Plain { text: bridge_format::plain_content(chain) });
+                        }
+                    }
+                }
+                bridge::MessageContent::Voice { .. } => {
+                    message_chain.push(MessageContent::Plain { text: bridge_format::plain_content(chain) })
+                }
+                bridge::MessageContent::File { name, size, url: Some(url), .. } => {
+                    message_chain.push(MessageContent::Plain { text: bridge_format::plain_content(chain) });
+                    match target_group(&message) {
+                        Some(group) if bridge_file::within_limit(*size, max_file_size) => {
+                            files.push((group, name.clone(), url.clone()))
+                        }
+                        // 私聊或超过大小限制时只发送链接
+                        _ => message_chain.push(MessageContent::Plain { text: format!(" {}", url) }),
+                    }
                 }
-                _ => message_chain.push(MessageContent::Plain {
-                    text: "{无法识别的MessageChain}".to_string(),
+                bridge::MessageContent::Emoji { id, animated, .. } if message.bridge_config.emojiImage => {
+                    message_chain.push(MessageContent::Image {
+                        image_id: None,
+                        url: Some(bridge_format::emoji_url(*id, *animated)),
+                        path: None,
+                        base64: None,
+                    })
+                }
+                // 提及的是另一平台的用户，以文本显示
+                bridge::MessageContent::At { .. } | bridge::MessageContent::AtAll => message_chain.push(MessageContent::Plain {
+                    text: bridge_format::plain_content(chain),
                 }),
+                other => message_chain.push(MessageContent::try_from(other).unwrap_or_else(|_| MessageContent::Plain {
+                    text: bridge_format::plain_content(other),
+                })),
+            }
+        }
+        let result = match send_chain(&mirai, &message, message_chain).await {
+            Some(result) => result,
+            None => {
+                warn!("消息的投递目标不是qq会话: {:?}", message.target);
+                continue;
+            }
+        };
+        for voice in voices {
+            if let Some(Err(err)) = send_chain(&mirai, &message, vec![voice]).await {
+                error!("发送语音失败: {:?}", err);
+            }
+        }
+        for (group, name, url) in files {
+            if let Err(e) = upload_file(&mirai, group, &name, &url, max_file_size).await {
+                warn!("转发文件失败: {}", e);
+                let text = format!("[文件] {} {}", name, url);
+                if let Some(Err(err)) = send_chain(&mirai, &message, vec![MessageContent::Plain { text }]).await {
+                    error!("发送文件链接失败: {:?}", err);
+                }
             }
         }
-        match mirai
-            .send_group_message(message_chain, message.bridge_config.qqGroup)
-            .await
-        {
-            Ok(_) => {
-                println!("[bridge_qq] 同步桥信息成功");
+        match result {
+            Ok(resp) => {
+                info!("同步桥信息成功");
+                METRICS.sent.with_label_values(&["bridge_qq", &bridge_id]).inc();
+                if let Some(archive) = bridge.archive() {
+                    let remote_id = resp.messageId.to_string();
+                    if let Err(e) = archive.record_delivery(&message.id, BridgeClientPlatform::QQ, &remote_id) {
+                        error!("记录消息id失败: {:?}", e);
+                    }
+                }
             }
             Err(err) => {
-                println!("[bridge_qq] 同步桥信息失败");
-                println!("[bridge_qq] {:?}", err);
+                error!("同步桥信息失败: {:?}", err);
+                METRICS.delivery_failures.with_label_values(&["bridge_qq", &bridge_id]).inc();
             }
         };
     }
 }
 
-pub async fn start(config: Arc<Config>, bridge: Arc<bridge::BridgeClient>) {
+/// 发送到消息指定的 qq 会话，默认为桥的群；目标不是 qq 会话时返回 None
+async fn send_chain(
+    mirai: &MiraiHttp,
+    message: &bridge::BridgeMessage,
+    chain: MessageChain,
+) -> Option<HttpResult<SendGroupMessageResponse>> {
+    let result = match message.target {
+        None => mirai.send_group_message(chain, message.bridge_config.qqGroup).await,
+        Some(Channel::QQGroup { group }) => mirai.send_group_message(chain, group).await,
+        Some(Channel::QQPrivate { qq, group: 0 }) => mirai.send_friend_message(chain, qq).await,
+        Some(Channel::QQPrivate { qq, group }) => mirai.send_temp_message(chain, qq, group).await,
+        Some(_) => return None,
+    };
+    Some(result)
+}
+
+/// 消息投递到的 qq 群；私聊没有群文件
+fn target_group(message: &bridge::BridgeMessage) -> Option<u64> {
+    match message.target {
+        None => Some(message.bridge_config.qqGroup),
+        Some(Channel::QQGroup { group }) => Some(group),
+        Some(_) => None,
+    }
+}
+
+/// 下载文件并上传到群文件，上传后 qq 会在群里显示文件消息
+async fn upload_file(mirai: &MiraiHttp, group: u64, name: &str, url: &str, max_size: u64) -> Result<(), String> {
+    let data = bridge_file::download(url, max_size).await?;
+    let resp = mirai
+        .upload_file(data, name, group)
+        .await
+        .map_err(|e| format!("上传文件失败: {}", e))?;
+    if resp.code != 0 {
+        return Err(format!("上传文件失败: {} {}", resp.code, resp.msg));
+    }
+    Ok(())
+}
+
+/// 查询 qq 群文件的下载地址
+async fn file_url(mirai: &MiraiHttp, id: &str, group: u64) -> Result<String, String> {
+    let resp = mirai
+        .file_info(id, group)
+        .await
+        .map_err(|e| format!("查询群文件失败: {}", e))?;
+    resp.data
+        .and_then(|file| file.download_info)
+        .map(|info| info.url)
+        .ok_or_else(|| format!("查询群文件失败: {} {}", resp.code, resp.msg))
+}
+
+/// 补全群文件的下载地址，discord 按大小限制转为附件或链接
+async fn resolve_files(mirai: &MiraiHttp, group: u64, chain: &mut bridge::MessageChain) {
+    for content in chain.iter_mut() {
+        if let bridge::MessageContent::File { id: Some(id), url: url @ None, .. } = content {
+            match file_url(mirai, id, group).await {
+                Ok(file_url) => *url = Some(file_url),
+                Err(e) => warn!("{}", e),
+            }
+        }
+    }
+}
+
+/// 消息投递到的 qq 会话类型，上传语音时需要指定
+fn upload_type(message: &bridge::BridgeMessage) -> UploadType {
+    match message.target {
+        Some(Channel::QQPrivate { group: 0, .. }) => UploadType::Friend,
+        Some(Channel::QQPrivate { .. }) => UploadType::Temp,
+        _ => UploadType::Group,
+    }
+}
+
+/// 下载语音，转为 qq 支持的格式后上传
+async fn upload_voice(mirai: &MiraiHttp, config: &VoiceConfig, url: &str, kind: UploadType) -> Result<MessageContent, String> {
+    let accept = [AudioFormat::Amr, AudioFormat::Silk];
+    let (data, format) = bridge_voice::prepare(config, url, config.qqFormat, &accept).await?;
+    let uploaded = mirai
+        .upload_voice(data, &format!("voice.{}", format.extension()), kind)
+        .await
+        .map_err(|e| format!("上传语音失败: {}", e))?;
+    Ok(MessageContent::Voice {
+        voice_id: Some(uploaded.voice_id),
+        url: None,
+        path: None,
+        base64: None,
+        length: None,
+    })
+}
+
+pub async fn start(config: SharedConfig, bridge: Arc<bridge::BridgeClient>) {
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        tokio::select! {
+            connected = connect(config.clone(), bridge.clone()) => {
+                let wait = match connected {
+                    Ok(()) => {
+                        delay = RECONNECT_DELAY;
+                        warn!("mirai 连接已断开, {}秒后重连", delay.as_secs());
+                        delay
+                    }
+                    Err(e) => {
+                        error!("无法连接 mirai: {}, {}秒后重试", e, delay.as_secs());
+                        bridge_admin::report(bridge_admin::MIRAI, false, e);
+                        let wait = delay;
+                        delay = next_delay(delay);
+                        wait
+                    }
+                };
+                // 等待期间配置变更时立即重连
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {},
+                    _ = config_watch::changed(&config, |c| c.miraiConfig.clone()) => delay = RECONNECT_DELAY,
+                }
+            },
+            // mirai 连接配置变更时重新连接
+            _ = config_watch::changed(&config, |c| c.miraiConfig.clone()) => delay = RECONNECT_DELAY,
+        }
+    }
+}
+
+/// 连续失败时加倍等待时间
+fn next_delay(delay: Duration) -> Duration {
+    (delay * 2).min(MAX_RECONNECT_DELAY)
+}
+
+/// 连接 mirai，接收并同步消息
+/// - 认证失败时返回错误；连接断开时返回 Ok
+async fn connect(config: SharedConfig, bridge: Arc<bridge::BridgeClient>) -> Result<(), String> {
+    let mirai_config = config.load().miraiConfig.clone();
+    let handler_http = Arc::new(OnceCell::new());
     let mut mirai = Mirai::builder(
-        &config.miraiConfig.host,
-        config.miraiConfig.port,
-        &config.miraiConfig.verifyKey,
+        &mirai_config.host,
+        mirai_config.port,
+        &mirai_config.verifyKey,
     )
-    .bind_qq(3245538509)
-    .event_handler(MiraiBridgeHandler {
+    .bind_qq(BOT_QQ)
+    .try_event_handler(MiraiBridgeHandler {
         config: config.clone(),
         bridge: bridge.clone(),
+        mirai: handler_http.clone(),
     })
-    .await;
+    .await
+    .map_err(|e| e.to_string())?;
     let http = mirai.get_http().await;
+    let _ = handler_http.set(http.clone());
     tokio::select! {
         _ = mirai.start() => {},
-        _ = bridge_qq(bridge.clone(), http) => {},
+        _ = bridge_qq(config.clone(), bridge.clone(), http) => {},
     }
+    Ok(())
 }
 
 #[mirai_rs::async_trait]
 impl EventHandler for MiraiBridgeHandler {
+    fn polled(&self, elapsed: Duration, success: bool) {
+        let result = if success { "ok" } else { "error" };
+        bridge_admin::report(bridge_admin::MIRAI, success, if success { "session valid" } else { "fetchMessage failed" });
+        METRICS.mirai_poll_latency.with_label_values(&[result]).observe(elapsed.as_secs_f64());
+    }
+
     async fn message(&self, msg: MessageEvent) {
         if let MessageEvent::GroupMessage(group_message) = msg {
-            // 查询这个频道是否需要通知到群
-            let bridge_config = match self
+            // 查询这个频道是否需要通知到群；没有配置桥的群只接收桥管理指令
+            let group = group_message.sender.group.id;
+            let bridge_config = self
                 .config
+                .load_full()
                 .bridges
                 .iter()
-                .find(|bridge| group_message.sender.group.id == bridge.qqGroup && bridge.enable)
-            {
-                Some(bridge_config) => bridge_config,
-                None => {
-                    // 该消息的频道没有配置桥, 忽略这个消息
-                    return;
-                }
-            };
+                .find(|bridge| group == bridge.qqGroup)
+                .cloned()
+                .unwrap_or_else(|| BridgeConfig::unlinked(group, 0));
 
             let user = bridge::User {
                 name: format!(
@@ -98,62 +326,78 @@ impl EventHandler for MiraiBridgeHandler {
                     group_message.sender.member_name.to_string(),
                     group_message.sender.id
                 ),
-                avatar_url: Some(format!(
-                    "https://q1.qlogo.cn/g?b=qq&nk={}&s=100",
-                    group_message.sender.id
-                )),
+                avatar_url: Some(bridge_user::qq_avatar_url(group_message.sender.id)),
+                id: group_message.sender.id,
+                permission: match group_message.sender.permission {
+                    Permission::Owner | Permission::Administrator => bridge_cmd::Permission::Manager,
+                    Permission::Member => bridge_cmd::Permission::Member,
+                },
             };
+            bridge_user::seen(
+                BridgeClientPlatform::QQ,
+                user.id,
+                &group_message.sender.member_name,
+                user.avatar_url.clone(),
+            );
 
             let mut bridge_message = bridge::BridgeMessage {
+                id: uuid::Uuid::new_v4().to_string(),
+                origin_id: None,
                 bridge_config: bridge_config.clone(),
                 message_chain: Vec::new(),
                 user,
+                origin: Some(Channel::QQGroup {
+                    group: group_message.sender.group.id,
+                }),
+                target: None,
+                reply_to: None,
             };
-            // skip cmd
-            if let Some(token) = group_message.message_chain.get(0) {
-                match token {
-                    MessageContent::Plain { text } => {
-                        if text.starts_with("!") {
-                            self.bridge.send_to("bridge_cmd_adapter", &bridge_message);
-                            // return;
-                        }
-                    }
-                    _ => {}
-                }
-            }
             for chain in &group_message.message_chain {
                 match chain {
-                        MessageContent::Plain { text } => {
-                            bridge_message.message_chain.push(bridge::MessageContent::Plain { text: text.to_string() })
+                        MessageContent::Source { id, .. } => {
+                            bridge_message.origin_id = Some(id.to_string());
                         }
-                        _ => {
-                            println!("消息的内容没有处理");
+                        MessageContent::Quote { id, .. } => {
+                            bridge_message.reply_to = self.bridge.archive().and_then(|archive| {
+                                archive
+                                    .find_by_remote_id(
+                                        (bridge_config.qqGroup, bridge_config.discord.channelId),
+                                        BridgeClientPlatform::QQ,
+                                        &id.to_string(),
+                                    )
+                                    .unwrap_or_else(|e| {
+                                        error!("查找被回复的消息失败: {:?}", e);
+                                        None
+                                    })
+                            });
                         }
-                        // MessageContent::Source { id, time } => todo!(),
-                        // MessageContent::Quote { id, group_id, sender_id, target_id, origin } => todo!(),
-                        // MessageContent::At { target, display } => todo!(),
-                        // MessageContent::AtAll {  } => todo!(),
-                        // MessageContent::Face { face_id, name } => todo!(),
-                        // MessageContent::Plain { text } => todo!(),
-                        // MessageContent::Image { image_id, url, path, base64 } => todo!(),
-                        // MessageContent::FlashImage { image_id, url, path, base64 } => todo!(),
-                        // MessageContent::Voice { voice_id, url, path, base64, length } => todo!(),
-                        // MessageContent::Xml { xml } => todo!(),
-                        // MessageContent::Json { json } => todo!(),
-                        // MessageContent::App { content } => todo!(),
-                        // MessageContent::Poke { name } => todo!(),
-                        // MessageContent::Dice { value } => todo!(),
-                        // MessageContent::MusicShare { kind, title, summary, jump_url, picture_url, music_url, brief } => todo!(),
-                        // MessageContent::ForwardMessage { sender_id, time, sender_name, message_chain, message_id } => todo!(),
-                        // MessageContent::File { id, name, size } => todo!(),
-                        // MessageContent::MiraiCode { code } => todo!(),
+                        other => match bridge::MessageContent::try_from(other.clone()) {
+                            Ok(content) => bridge_message.message_chain.push(content),
+                            Err(e) => debug!("消息的内容没有处理: {}", e),
+                        },
                     }
             }
+            bridge_face::faces().fill_names(&mut bridge_message.message_chain);
+            // 指令交给指令频道；桥暂停或未建立时只接收桥管理指令
+            let cmd = bridge_cmd::lookup(&bridge_message.message_chain, &bridge_config.cmdPrefix);
+            if let Some(def) = cmd {
+                if bridge_config.enable || def.cmd == Cmd::Manage {
+                    self.bridge.send_to(bridge::CMD_CLIENT, &bridge_message);
+                }
+            }
+            if !bridge_config.enable {
+                return;
+            }
+            METRICS.received.with_label_values(&["bridge_qq", &bridge_config.id()]).inc();
+            if let Some(mirai) = self.mirai.get() {
+                resolve_files(mirai, group, &mut bridge_message.message_chain).await;
+            }
+            // 不同步的指令由指令频道决定何时转发
+            if cmd.map(|def| def.forward_mode(&bridge_config) != ForwardMode::Mirror).unwrap_or(false) {
+                return;
+            }
             self.bridge.send(bridge_message);
-            println!("接收到群消息:");
-            println!("{:?}", group_message);
-            // println!("接收到群消息:");
-            // println!("{:?}", group_message);
+            debug!("接收到群消息: {:?}", group_message);
         }
     }
 }
@@ -0,0 +1,289 @@
+//! 用户信息：根据绑定关系与用户设置，决定消息在两端显示的名称与头像
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::bridge::{BridgeClientPlatform, BridgeMessage, User};
+use crate::bridge_data::{bind_map, write_atomic, Identity};
+
+const PROFILE_PATH: &str = "./data/UserProfile.json";
+
+static REGISTRY: Lazy<RwLock<Registry>> = Lazy::new(|| RwLock::new(Registry::load(PROFILE_PATH)));
+
+/// 头像来源
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AvatarSource {
+    /// 发送消息的账号的头像
+    #[default]
+    Auto,
+    /// 绑定的 qq 账号的头像
+    QQ,
+    /// 绑定的 discord 账号的头像
+    Discord,
+}
+
+impl AvatarSource {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "auto" | "默认" => Some(AvatarSource::Auto),
+            "qq" => Some(AvatarSource::QQ),
+            "discord" | "dc" => Some(AvatarSource::Discord),
+            _ => None,
+        }
+    }
+}
+
+/// 用户设置
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    /// 两端统一显示的名称
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub avatar: AvatarSource,
+}
+
+/// 最近一次在平台上看到的用户信息
+#[derive(Debug, Clone)]
+struct Seen {
+    name: String,
+    avatar_url: Option<String>,
+}
+
+/// 用户登记表
+/// - profiles 用户设置，按平台身份保存
+/// - seen 用户在各平台上的昵称与头像，仅保存在内存中
+pub struct Registry {
+    profiles: HashMap<String, Profile>,
+    seen: HashMap<Identity, Seen>,
+    path: PathBuf,
+}
+
+impl Registry {
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let profiles = match std::fs::read_to_string(&path) {
+            Ok(json) if !json.trim().is_empty() => match serde_json::from_str(&json) {
+                Ok(map) => map,
+                Err(e) => {
+                    error!("UserProfile load fail, data can not be parsed; {:#?}", e);
+                    HashMap::new()
+                }
+            },
+            _ => HashMap::new(),
+        };
+        Registry {
+            profiles,
+            seen: HashMap::new(),
+            path,
+        }
+    }
+
+    /// 记录用户在平台上的昵称与头像
+    pub fn seen(&mut self, platform: BridgeClientPlatform, id: u64, name: &str, avatar_url: Option<String>) {
+        let seen = Seen {
+            name: name.to_string(),
+            avatar_url,
+        };
+        self.seen.insert(Identity::new(platform, id), seen);
+    }
+
+    pub fn profile(&self, user: &Identity) -> Profile {
+        self.profiles.get(&user.to_string()).cloned().unwrap_or_default()
+    }
+
+    /// 修改用户设置并保存
+    pub fn update(&mut self, user: &Identity, f: impl FnOnce(&mut Profile)) {
+        let key = user.to_string();
+        let profile = self.profiles.entry(key.clone()).or_default();
+        f(profile);
+        if *profile == Profile::default() {
+            self.profiles.remove(&key);
+        }
+        self.save();
+    }
+
+    /// 决定消息发送者在另一端显示的名称与头像
+    /// - 未绑定且没有设置的用户保持原样
+    /// - 名称保留发送者的平台与 id，如 `[QQ] 名称(123)`，不能冒充其他用户或桥
+    /// - bound 与发送者属于同一个人的其他身份
+    pub fn resolve(&self, platform: BridgeClientPlatform, user: &User, bound: &[Identity]) -> User {
+        let own = Identity::new(platform, user.id);
+        let own_profile = self.profiles.get(&own.to_string());
+        if bound.is_empty() && own_profile.is_none() {
+            return user.clone();
+        }
+        // 自己的设置优先，其次是同一个人其他身份的设置
+        let profiles: Vec<&Profile> = own_profile
+            .into_iter()
+            .chain(bound.iter().filter_map(|i| self.profiles.get(&i.to_string())))
+            .collect();
+        let identities: Vec<Identity> = std::iter::once(own).chain(bound.iter().copied()).collect();
+        let first_of = |platform| identities.iter().find(|i| i.platform == platform);
+
+        // 名称：设置的名称 > qq 昵称 > 当前平台昵称
+        let name = profiles
+            .iter()
+            .find_map(|p| p.name.clone())
+            .or_else(|| first_of(BridgeClientPlatform::QQ).and_then(|i| self.seen.get(i)).map(|s| s.name.clone()))
+            .or_else(|| self.seen.get(&own).map(|s| s.name.clone()))
+            .map(|name| display_name(platform, &name, user.id))
+            .unwrap_or_else(|| user.name.clone());
+
+        let source = profiles
+            .iter()
+            .map(|p| p.avatar)
+            .find(|a| *a != AvatarSource::Auto)
+            .unwrap_or_default();
+        let avatar_url = match source {
+            AvatarSource::Auto => None,
+            AvatarSource::QQ => first_of(BridgeClientPlatform::QQ).map(|i| qq_avatar_url(i.id)),
+            AvatarSource::Discord => identities
+                .iter()
+                .filter(|i| i.platform == BridgeClientPlatform::Discord)
+                .find_map(|i| self.seen.get(i).and_then(|s| s.avatar_url.clone())),
+        }
+        .or_else(|| user.avatar_url.clone());
+
+        User {
+            name,
+            avatar_url,
+            id: user.id,
+            permission: user.permission,
+        }
+    }
+
+    fn save(&self) {
+        let json = match serde_json::to_string(&self.profiles) {
+            Ok(json) => json,
+            Err(e) => {
+                error!("Fail to parse UserProfile to JSON; {:#?}", e);
+                return;
+            }
+        };
+        if let Err(e) = write_atomic(&self.path, json.as_bytes()) {
+            error!("Can not write to file({}); {:#?}", self.path.display(), e);
+        }
+    }
+}
+
+/// 带有平台与 id 的显示名称
+fn display_name(platform: BridgeClientPlatform, name: &str, id: u64) -> String {
+    let tag = match platform {
+        BridgeClientPlatform::QQ => "QQ",
+        BridgeClientPlatform::Discord => "DC",
+    };
+    format!("[{}] {}({})", tag, name, id)
+}
+
+/// qq 头像地址
+pub fn qq_avatar_url(qq: u64) -> String {
+    format!("https://q1.qlogo.cn/g?b=qq&nk={}&s=100", qq)
+}
+
+/// 记录用户在平台上的昵称与头像
+pub fn seen(platform: BridgeClientPlatform, id: u64, name: &str, avatar_url: Option<String>) {
+    if let Ok(mut registry) = REGISTRY.write() {
+        registry.seen(platform, id, name, avatar_url);
+    }
+}
+
+/// 取用户设置
+pub fn profile(user: &Identity) -> Profile {
+    match REGISTRY.read() {
+        Ok(registry) => registry.profile(user),
+        Err(_) => Profile::default(),
+    }
+}
+
+/// 修改用户设置
+pub fn update(user: &Identity, f: impl FnOnce(&mut Profile)) {
+    if let Ok(mut registry) = REGISTRY.write() {
+        registry.update(user, f);
+    }
+}
+
+/// 决定消息发送者显示的名称与头像；非用户消息保持原样
+pub fn resolve(message: &BridgeMessage) -> User {
+    let platform = match message.from_platform() {
+        Some(p) if message.user.id != 0 => p,
+        _ => return message.user.clone(),
+    };
+    let bound = bind_map::bound(&Identity::new(platform, message.user.id));
+    match REGISTRY.read() {
+        Ok(registry) => registry.resolve(platform, &message.user, &bound),
+        Err(_) => message.user.clone(),
+    }
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+
+    fn user(name: &str, avatar: &str, id: u64) -> User {
+        User {
+            name: name.to_string(),
+            avatar_url: Some(avatar.to_string()),
+            id,
+            permission: Default::default(),
+        }
+    }
+
+    #[test]
+    fn resolveUser() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("UserProfile.json");
+        let mut registry = Registry::load(&path);
+        let qq = Identity::new(BridgeClientPlatform::QQ, 123);
+        let dc = Identity::new(BridgeClientPlatform::Discord, 456);
+        registry.seen(BridgeClientPlatform::QQ, 123, "dong", None);
+        registry.seen(BridgeClientPlatform::Discord, 456, "abc", Some("dc.png".to_string()));
+        let dc_user = user("[DC] abc#0001", "dc.png", 456);
+
+        // 未绑定、未设置
+        let resolved = registry.resolve(BridgeClientPlatform::Discord, &dc_user, &[]);
+        assert_eq!(resolved.name, "[DC] abc#0001");
+
+        // 已绑定，默认使用 qq 昵称
+        let resolved = registry.resolve(BridgeClientPlatform::Discord, &dc_user, &[qq]);
+        assert_eq!(resolved.name, "[DC] dong(456)");
+        assert_eq!(resolved.avatar_url.as_deref(), Some("dc.png"));
+
+        // 在 qq 端设置的名称与头像，discord 端同样生效
+        registry.update(&qq, |p| {
+            p.name = Some("Dong".to_string());
+            p.avatar = AvatarSource::QQ;
+        });
+        let resolved = registry.resolve(BridgeClientPlatform::Discord, &dc_user, &[qq]);
+        assert_eq!(resolved.name, "[DC] Dong(456)");
+        assert_eq!(resolved.avatar_url, Some(qq_avatar_url(123)));
+
+        // 自己的设置优先
+        let qq_user = user("[QQ] dong(123)", "qq.png", 123);
+        registry.update(&dc, |p| p.avatar = AvatarSource::Discord);
+        let resolved = registry.resolve(BridgeClientPlatform::QQ, &qq_user, &[dc]);
+        assert_eq!(resolved.name, "[QQ] Dong(123)");
+        assert_eq!(resolved.avatar_url, Some(qq_avatar_url(123)));
+        let resolved = registry.resolve(BridgeClientPlatform::Discord, &dc_user, &[qq]);
+        assert_eq!(resolved.avatar_url.as_deref(), Some("dc.png"));
+
+        // 未绑定的用户设置的名称也不能冒充其他人
+        let other = user("[QQ] li(789)", "li.png", 789);
+        let li = Identity::new(BridgeClientPlatform::QQ, 789);
+        registry.update(&li, |p| p.name = Some("[Bridge]".to_string()));
+        let resolved = registry.resolve(BridgeClientPlatform::QQ, &other, &[]);
+        assert_eq!(resolved.name, "[QQ] [Bridge](789)");
+
+        // 设置已保存
+        assert!(!path.with_extension("json.tmp").exists());
+        let reloaded = Registry::load(&path);
+        assert_eq!(reloaded.profile(&qq).name.as_deref(), Some("Dong"));
+        assert_eq!(reloaded.profile(&dc).avatar, AvatarSource::Discord);
+    }
+}
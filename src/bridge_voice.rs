@@ -0,0 +1,234 @@
+//! 语音转发：下载语音并在 qq 的 silk/amr 与 discord 可播放的 ogg/mp3 之间转码
+//!
+//! 转码由 [`VoiceCodec`] 完成；配置了转码命令时调用外部程序（如 ffmpeg），否则只转发无需转码的语音
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use tracing::debug;
+
+use crate::bridge_file;
+use crate::config::VoiceConfig;
+
+/// 音频格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    Silk,
+    Amr,
+    Ogg,
+    Mp3,
+    Wav,
+}
+
+impl AudioFormat {
+    /// 按文件头识别格式
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        // qq 的 silk 文件头前可能多一个 0x02
+        let silk = data.strip_prefix(&[0x02]).unwrap_or(data);
+        if silk.starts_with(b"#!SILK_V3") {
+            Some(AudioFormat::Silk)
+        } else if data.starts_with(b"#!AMR") {
+            Some(AudioFormat::Amr)
+        } else if data.starts_with(b"OggS") {
+            Some(AudioFormat::Ogg)
+        } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WAVE") {
+            Some(AudioFormat::Wav)
+        } else if data.starts_with(b"ID3") || (data.len() > 1 && data[0] == 0xFF && data[1] & 0xE0 == 0xE0) {
+            Some(AudioFormat::Mp3)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Silk => "silk",
+            AudioFormat::Amr => "amr",
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Wav => "wav",
+        }
+    }
+}
+
+/// 语音转码
+#[async_trait]
+pub trait VoiceCodec: Send + Sync {
+    async fn transcode(&self, data: Vec<u8>, from: AudioFormat, to: AudioFormat) -> Result<Vec<u8>, String>;
+}
+
+/// 不转码，只接受格式相同的语音
+pub struct Passthrough;
+
+#[async_trait]
+impl VoiceCodec for Passthrough {
+    async fn transcode(&self, data: Vec<u8>, from: AudioFormat, to: AudioFormat) -> Result<Vec<u8>, String> {
+        if from == to {
+            Ok(data)
+        } else {
+            Err(format!("没有配置转码命令, 无法将 {} 转为 {}", from.extension(), to.extension()))
+        }
+    }
+}
+
+/// 调用外部命令转码；参数中的 `{input}` `{output}` 替换为临时文件路径
+pub struct CommandCodec {
+    pub command: Vec<String>,
+}
+
+#[async_trait]
+impl VoiceCodec for CommandCodec {
+    async fn transcode(&self, data: Vec<u8>, from: AudioFormat, to: AudioFormat) -> Result<Vec<u8>, String> {
+        let (program, args) = self.command.split_first().ok_or("转码命令为空")?;
+        let id = uuid::Uuid::new_v4();
+        let input = temp_file(&format!("{}.{}", id, from.extension()));
+        let output = temp_file(&format!("{}-out.{}", id, to.extension()));
+        let args: Vec<String> = args
+            .iter()
+            .map(|a| {
+                a.replace("{input}", &input.to_string_lossy())
+                    .replace("{output}", &output.to_string_lossy())
+            })
+            .collect();
+        let result = async {
+            tokio::fs::write(&input, data).await.map_err(|e| format!("写入临时文件失败: {}", e))?;
+            debug!("转码语音: {} {:?}", program, args);
+            let status = tokio::process::Command::new(program)
+                .args(&args)
+                .stdin(std::process::Stdio::null())
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status()
+                .await
+                .map_err(|e| format!("无法执行转码命令 {}: {}", program, e))?;
+            if !status.success() {
+                return Err(format!("转码命令执行失败: {}", status));
+            }
+            tokio::fs::read(&output).await.map_err(|e| format!("读取转码结果失败: {}", e))
+        }
+        .await;
+        let _ = tokio::fs::remove_file(&input).await;
+        let _ = tokio::fs::remove_file(&output).await;
+        result
+    }
+}
+
+fn temp_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("message-bridge-{}", name))
+}
+
+/// 按配置选择转码方式
+pub fn codec(config: &VoiceConfig) -> Box<dyn VoiceCodec> {
+    if config.command.is_empty() {
+        Box::new(Passthrough)
+    } else {
+        Box::new(CommandCodec { command: config.command.clone() })
+    }
+}
+
+/// 转为目标格式；格式相同时不转码
+/// - accept 中的格式也可以直接使用
+pub async fn transcode(
+    codec: &dyn VoiceCodec,
+    data: Vec<u8>,
+    to: AudioFormat,
+    accept: &[AudioFormat],
+) -> Result<(Vec<u8>, AudioFormat), String> {
+    let from = AudioFormat::detect(&data).ok_or("无法识别的语音格式")?;
+    if from == to || accept.contains(&from) {
+        return Ok((data, from));
+    }
+    Ok((codec.transcode(data, from, to).await?, to))
+}
+
+/// 下载并转为目标格式
+pub async fn prepare(
+    config: &VoiceConfig,
+    url: &str,
+    to: AudioFormat,
+    accept: &[AudioFormat],
+) -> Result<(Vec<u8>, AudioFormat), String> {
+    let data = bridge_file::download(url, config.maxSize).await?;
+    transcode(codec(config).as_ref(), data, to, accept).await
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+
+    fn sample(name: &str) -> Vec<u8> {
+        std::fs::read(format!("{}/testdata/voice/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    #[test]
+    fn detectFormat() {
+        assert_eq!(AudioFormat::detect(&sample("sample.silk")), Some(AudioFormat::Silk));
+        assert_eq!(AudioFormat::detect(&sample("sample.amr")), Some(AudioFormat::Amr));
+        assert_eq!(AudioFormat::detect(&sample("sample.ogg")), Some(AudioFormat::Ogg));
+        assert_eq!(AudioFormat::detect(&sample("sample.mp3")), Some(AudioFormat::Mp3));
+        assert_eq!(AudioFormat::detect(&sample("sample.wav")), Some(AudioFormat::Wav));
+        assert_eq!(AudioFormat::detect(b"hello"), None);
+    }
+
+    #[tokio::test]
+    async fn passthrough() {
+        let amr = sample("sample.amr");
+        let (data, format) = transcode(&Passthrough, amr.clone(), AudioFormat::Amr, &[]).await.unwrap();
+        assert_eq!((data, format), (amr, AudioFormat::Amr));
+        // 可以直接播放的格式不转码
+        let (_, format) = transcode(&Passthrough, sample("sample.ogg"), AudioFormat::Mp3, &[AudioFormat::Ogg])
+            .await
+            .unwrap();
+        assert_eq!(format, AudioFormat::Ogg);
+        let err = transcode(&Passthrough, sample("sample.silk"), AudioFormat::Mp3, &[]).await.unwrap_err();
+        assert!(err.contains("silk"));
+    }
+
+    fn command(args: &[&str]) -> CommandCodec {
+        CommandCodec { command: args.iter().map(|s| s.to_string()).collect() }
+    }
+
+    /// 用 ffmpeg 转码样本，检查样本与命令转码可用
+    #[tokio::test]
+    #[ignore = "需要 ffmpeg，使用 cargo test -- --ignored 运行"]
+    async fn ffmpegCodec() {
+        let codec = command(&["ffmpeg", "-y", "-loglevel", "error", "-i", "{input}", "{output}"]);
+        for (name, from) in [("sample.amr", AudioFormat::Amr), ("sample.mp3", AudioFormat::Mp3), ("sample.ogg", AudioFormat::Ogg)] {
+            let wav = codec.transcode(sample(name), from, AudioFormat::Wav).await.unwrap();
+            assert_eq!(AudioFormat::detect(&wav), Some(AudioFormat::Wav), "{}", name);
+        }
+        let mp3 = codec.transcode(sample("sample.wav"), AudioFormat::Wav, AudioFormat::Mp3).await;
+        // 部分 ffmpeg 没有 mp3 编码器
+        if let Ok(mp3) = mp3 {
+            assert_eq!(AudioFormat::detect(&mp3), Some(AudioFormat::Mp3));
+        }
+    }
+
+    /// 按 voiceConfig.command 文档中的 silk 命令编码再解码，检查 silk 转码可用
+    /// - sample.silk 只有文件头，不能解码；这里由 sample.wav 编码得到真实的 silk
+    #[tokio::test]
+    #[ignore = "需要 ffmpeg 与 silk_v3_encoder/silk_v3_decoder，使用 cargo test -- --ignored 运行"]
+    async fn silkCodec() {
+        let codec = command(&["sh", "-c", crate::config::SILK_COMMAND]);
+        let silk = codec.transcode(sample("sample.wav"), AudioFormat::Wav, AudioFormat::Silk).await.unwrap();
+        assert_eq!(AudioFormat::detect(&silk), Some(AudioFormat::Silk));
+        let ogg = codec.transcode(silk, AudioFormat::Silk, AudioFormat::Ogg).await.unwrap();
+        assert_eq!(AudioFormat::detect(&ogg), Some(AudioFormat::Ogg));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn commandCodec() {
+        // 用 cp 代替转码程序，检查临时文件与参数替换
+        let codec = command(&["cp", "{input}", "{output}"]);
+        let silk = sample("sample.silk");
+        let (data, format) = transcode(&codec, silk.clone(), AudioFormat::Ogg, &[]).await.unwrap();
+        assert_eq!((data, format), (silk, AudioFormat::Ogg));
+
+        let codec = command(&["false"]);
+        let err = codec.transcode(sample("sample.amr"), AudioFormat::Amr, AudioFormat::Ogg).await.unwrap_err();
+        assert!(err.contains("执行失败"));
+    }
+}
@@ -0,0 +1,237 @@
+//! 命令行：启动桥，以及无需修改 JSON 的运维操作
+use std::error::Error;
+
+use clap::{Parser, Subcommand};
+use mirai_rs::api::MessageEvent;
+use mirai_rs::message::MessageContent;
+use mirai_rs::{EventHandler, Mirai};
+use serenity::http::Http;
+use serenity::model::webhook::Webhook;
+
+use crate::bridge_data::{bind_map, migrate, store, Identity};
+use crate::bridge_dc;
+use crate::bridge_qq::BOT_QQ;
+use crate::config::{BridgeConfig, Config, CONFIG_PATH};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "QQ 与 Discord 的消息桥")]
+pub struct Cli {
+    /// 配置文件路径，支持 .json / .toml / .yaml
+    #[arg(short, long, global = true, default_value = CONFIG_PATH)]
+    pub config: String,
+
+    /// 不指定时等同于 run
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// 启动桥
+    Run,
+    /// 校验配置文件
+    CheckConfig,
+    /// 列出配置的桥
+    ListBridges,
+    /// 向桥的两端发送一条消息
+    Send {
+        /// 桥 id，格式为 `qq群号-频道id`
+        #[arg(long)]
+        bridge: String,
+        /// 消息内容
+        text: String,
+    },
+    /// 管理用户绑定
+    Bind {
+        #[command(subcommand)]
+        action: BindAction,
+    },
+    /// 升级本地数据格式
+    Migrate {
+        /// 只列出需要执行的升级
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BindAction {
+    /// 列出全部绑定
+    List,
+    /// 将两个身份归为同一个人；身份格式为 `QQ:<qq号>` 或 `Discord:<用户id>`
+    Add { user1: Identity, user2: Identity },
+    /// 将身份从所属的人中分离
+    Remove { user: Identity },
+}
+
+/// 执行 run 以外的子命令
+pub async fn exec(command: Command, path: &str) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Run => return Err("run 需要由 main 启动桥".into()),
+        Command::CheckConfig => {
+            let config = load(path)?;
+            println!("配置有效: {} ({} 个桥)", path, config.bridges.len());
+        }
+        Command::ListBridges => {
+            let config = load(path)?;
+            println!("{:<24} {:<12} {:<20} 状态", "ID", "QQ群", "频道");
+            for bridge in &config.bridges {
+                let state = if bridge.enable { "开启" } else { "关闭" };
+                println!(
+                    "{:<24} {:<12} {:<20} {}",
+                    bridge.id(),
+                    bridge.qqGroup,
+                    bridge.discord.channelId,
+                    state
+                );
+            }
+        }
+        Command::Send { bridge, text } => {
+            let config = load(path)?;
+            let bridge_config = config
+                .find_bridge(&bridge)
+                .ok_or_else(|| format!("没有找到桥: {}", bridge))?
+                .clone();
+            send(&config, &bridge_config, &text).await?;
+            println!("已发送到 {}", bridge);
+        }
+        Command::Bind { action } => {
+            let config = load(path)?;
+            bind_map::init(store::open(&config.bindStoreConfig)?)?;
+            bind(action);
+            bind_map::flush();
+        }
+        Command::Migrate { dry_run } => {
+            let config = load(path)?;
+            let store = &config.bindStoreConfig;
+            println!("当前数据版本: {}", migrate::current(store));
+            let done = if dry_run {
+                migrate::pending(store)
+            } else {
+                migrate::run(store)?
+            };
+            if done.is_empty() {
+                println!("数据已是最新版本");
+            }
+            for m in done {
+                let state = if dry_run { "待升级" } else { "已升级" };
+                println!("{} v{}: {}", state, m.version, m.description);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 管理用户绑定
+fn bind(action: BindAction) {
+    match action {
+        BindAction::List => {
+            for person in bind_map::all() {
+                let list: Vec<String> = person.identities.iter().map(|i| i.to_string()).collect();
+                println!("{}: {}", person.id, list.join(", "));
+            }
+        }
+        BindAction::Add { user1, user2 } => {
+            if bind_map::merge(user1, user2) {
+                println!("已绑定 {} <-> {}", user1, user2);
+            } else {
+                println!("{} 与 {} 已经绑定", user1, user2);
+            }
+        }
+        BindAction::Remove { user } => {
+            if bind_map::split(&user) {
+                println!("已解除 {} 的绑定", user);
+            } else {
+                println!("{} 没有绑定", user);
+            }
+        }
+    }
+}
+
+fn load(path: &str) -> Result<Config, Box<dyn Error>> {
+    Config::load(path).map_err(|e| format!("配置文件有误:\n{}", e).into())
+}
+
+/// 不经过桥，直接发送到 qq 群与 discord 频道
+async fn send(config: &Config, bridge: &BridgeConfig, text: &str) -> Result<(), Box<dyn Error>> {
+    let mirai = Mirai::builder(
+        &config.miraiConfig.host,
+        config.miraiConfig.port,
+        &config.miraiConfig.verifyKey,
+    )
+    .bind_qq(BOT_QQ)
+    .try_event_handler(Ignore)
+    .await
+    .map_err(|e| format!("无法连接 mirai: {}", e))?;
+    let chain = vec![MessageContent::Plain {
+        text: text.to_string(),
+    }];
+    mirai.get_http().await.send_group_message(chain, bridge.qqGroup).await?;
+
+    // 未配置 webhook 时按名称复用桥创建的 webhook
+    let http = Http::new(&config.discordConfig.botToken);
+    let discord = if bridge.discord.has_webhook() {
+        bridge.discord.clone()
+    } else {
+        bridge_dc::ensure_webhook(&http, bridge.discord.channelId).await?
+    };
+    let webhook = Webhook::from_id_with_token(&http, discord.id, &discord.token).await?;
+    webhook
+        .execute(&http, true, |w| w.username("[Bridge]").content(text).allowed_mentions(|m| m.empty_parse()))
+        .await?;
+    Ok(())
+}
+
+/// 只发送消息，不处理事件
+struct Ignore;
+
+#[mirai_rs::async_trait]
+impl EventHandler for Ignore {
+    async fn message(&self, _msg: MessageEvent) {}
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parseArgs() {
+        let cli = Cli::parse_from(["bridge"]);
+        assert_eq!(cli.config, CONFIG_PATH);
+        assert!(cli.command.is_none());
+        let cli = Cli::parse_from(["bridge", "--config=b.yaml", "run"]);
+        assert_eq!(cli.config, "b.yaml");
+
+        let cli = Cli::parse_from(["bridge", "send", "--bridge", "1-2", "hello", "-c", "a.toml"]);
+        assert_eq!(cli.config, "a.toml");
+        match cli.command {
+            Some(Command::Send { bridge, text }) => {
+                assert_eq!(bridge, "1-2");
+                assert_eq!(text, "hello");
+            }
+            _ => panic!("expected send"),
+        }
+
+        let cli = Cli::parse_from(["bridge", "bind", "remove", "qq:123"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Bind { action: BindAction::Remove { user } }) if user.to_string() == "QQ:123"
+        ));
+        assert!(Cli::try_parse_from(["bridge", "bind", "remove", "dong"]).is_err());
+        assert!(Cli::try_parse_from(["bridge", "send", "hello"]).is_err());
+    }
+
+    /// run 与连接失败都返回错误，不会 panic
+    #[tokio::test]
+    async fn execErrors() {
+        assert!(exec(Command::Run, CONFIG_PATH).await.is_err());
+
+        let mut config = crate::config::test_config();
+        config.miraiConfig.host = "127.0.0.1".to_string();
+        config.miraiConfig.port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port() as u32;
+        let bridge = config.bridges[0].clone();
+        let err = send(&config, &bridge, "hello").await.unwrap_err();
+        assert!(err.to_string().contains("mirai"));
+    }
+}
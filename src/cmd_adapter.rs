@@ -1,103 +1,469 @@
 ///! 接收，处理用户指令
 use std::sync::Arc;
 
-use chrono::Local;
+use chrono::{Local, TimeZone};
+use tracing::{error, info};
 
-use crate::{bridge, Config};
-use crate::bridge::{BridgeMessage, MessageChain, MessageContent};
+use crate::{bridge, SharedConfig};
+use crate::bridge::{BridgeClientPlatform, BridgeMessage, Channel, MessageChain, MessageContent, User};
+use crate::bridge_archive::SearchQuery;
 use crate::bridge_cmd::Cmd::*;
-use crate::bridge_cmd::{kind, CmdMeta};
+use crate::bridge_data::{bind_map, Identity};
+use crate::bridge_user::{self, AvatarSource};
+use crate::bridge_metrics::METRICS;
+use crate::bridge_cmd::{
+    self, parse, permission, search_query, BindCodes, BindRequest, BindStep, CmdMeta, Invocation, Permission, Reply, BIND_TIMEOUT,
+};
+use crate::config::{BridgeConfig, Config, ForwardMode};
+use crate::{bridge_dc, config_watch};
 
-type CacheBind = Vec<(i64, CmdMeta)>;
-
-/// 缓存超时（毫秒）
-const CACHE_TIMEOUT: i64 = 30_000;
+/// 检查绑定码是否过期的间隔
+const EXPIRE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
 
 /// 持续接收指令消息
-pub async fn cmd(bridge: Arc<bridge::BridgeClient>) {
-    // cache token - bind cmd
-    let mut cache_bind: CacheBind = Vec::with_capacity(1024);
+pub async fn cmd(config: SharedConfig, bridge: Arc<bridge::BridgeClient>) {
+    let mut bind_codes = BindCodes::default();
     let mut rx = bridge.sender.subscribe();
+    let mut expire = tokio::time::interval(EXPIRE_INTERVAL);
 
     loop {
-        let sign = rx.recv().await.unwrap();
-        // match cmd
-        if let Some(cmd) = kind(&sign.message_chain) {
-            match cmd {
-                Bind => check_bind(&sign, &mut cache_bind),
-            } // match cmd kind
+        let sign = tokio::select! {
+            sign = bridge.recv(&mut rx) => match sign {
+                Some(sign) => sign,
+                None => break,
+            },
+            _ = expire.tick() => {
+                for request in bind_codes.expire(Local::now().timestamp_millis()) {
+                    bind_expired(&bridge, &request);
+                }
+                METRICS.cache_size.with_label_values(&["bind"]).set(bind_codes.len() as i64);
+                continue;
+            },
+        };
+        let platform = match sign.from_platform() {
+            Some(p) => p,
+            None => continue,
+        };
+        let def = match bridge_cmd::lookup(&sign.message_chain, &sign.bridge_config.cmdPrefix) {
+            Some(def) => def,
+            None => continue,
+        };
+        let level = permission(&config.load(), platform, &sign.user);
+        let mut replies = vec![];
+        match parse(&sign.message_chain, &sign.bridge_config.cmdPrefix, platform, level) {
+            Some(Ok(inv)) => match inv.cmd {
+                Bind => {
+                    bind(&bridge, &sign, &inv, &mut bind_codes, &mut replies);
+                    METRICS.cache_size.with_label_values(&["bind"]).set(bind_codes.len() as i64);
+                }
+                Search => search(&bridge, &sign, &inv, &mut replies),
+                Unbind => unbind(&sign, &inv, &mut replies),
+                MyBind => my_bind(&sign, &inv, &mut replies),
+                Whois => whois(&inv, &mut replies),
+                Nickname => nickname(&sign, &inv, &mut replies),
+                Avatar => avatar(&sign, &inv, &mut replies),
+                Help => help(&inv, &mut replies),
+                Manage => manage(&config, &sign, &inv, &mut replies).await,
+            }, // match cmd kind
+            Some(Err(usage)) => replies.push(Reply::failed(usage)),
+            None => continue,
         }
+        respond(&bridge, &sign, def.forward_mode(&sign.bridge_config), replies);
     } // loop
 }
 
 /// 开启频道
-pub async fn start(_config: Arc<Config>, bridge: Arc<bridge::BridgeClient>) {
+pub async fn start(config: SharedConfig, bridge: Arc<bridge::BridgeClient>) {
     tokio::select! {
-        _ = cmd(bridge.clone()) => {},
+        _ = cmd(config.clone(), bridge.clone()) => {},
     }
 }
 
-/// 检查绑定指令，尝试缓存
-/// - sign 指令内容
-/// - cache 缓存集合
-fn check_bind(input: &BridgeMessage, caches: &mut CacheBind) {
-    // TODO 检查权限
-    let in_platform = match input.from_platform() {
-        Some(p) => p,
-        _ => { return; }
+/// 处理绑定指令：发放绑定码，或确认后建立映射
+/// - input 指令消息
+/// - inv 解析后的指令
+/// - codes 等待确认的绑定请求
+fn bind(
+    bridge: &bridge::BridgeClient,
+    input: &BridgeMessage,
+    inv: &Invocation,
+    codes: &mut BindCodes,
+    out: &mut Vec<Reply>,
+) {
+    let platform = inv.platform;
+    let meta = CmdMeta {
+        operator: input.user.clone(),
+        platform,
     };
-
-    let in_plain = plain_token(&input.message_chain);
     let now = Local::now().timestamp_millis();
-    let mut add_cache = true;
-    caches.retain(|(t, m)| {
-        // 剔除超时缓存
-        if now - *t > CACHE_TIMEOUT {
-            return false;
+    let arg = inv.text("arg").unwrap_or_default();
+    match codes.handle(&meta, arg, &input.bridge_config, now) {
+        BindStep::Issued(request) => {
+            let target = Identity::new(other_platform(platform), request.target);
+            if bind_map::bound(&Identity::new(platform, input.user.id)).contains(&target) {
+                codes.cancel(&request.code);
+                out.push(Reply::failed(format!("{} 已绑定 {}", input.user.name, target)));
+                return;
+            }
+            let minutes = BIND_TIMEOUT / 60_000;
+            out.push(Reply::from(format!(
+                "{} 的绑定码: {}\n请在 {} 分钟内用 {} 账号 {} 发送: {}绑定 {}",
+                input.user.name,
+                request.code,
+                minutes,
+                other_platform(platform).as_str(),
+                request.target,
+                inv.prefix,
+                request.code,
+            )));
+        }
+        BindStep::Confirmed(request) => {
+            let user1 = Identity::new(request.platform, request.operator.id);
+            let user2 = Identity::new(platform, input.user.id);
+            let text = if bind_map::merge(user1, user2) {
+                info!("{} 绑定 {}", user1, user2);
+                format!("绑定成功: {} <-> {}", request.operator.name, input.user.name)
+            } else {
+                format!("{} 与 {} 已经绑定", request.operator.name, input.user.name)
+            };
+            notify(bridge, request.platform, &request.bridge_config, text.clone());
+            notify(bridge, platform, &input.bridge_config, text);
         }
+        BindStep::Rejected(text) => out.push(Reply::failed(format!("{}\n{}", text, inv.usage()))),
+    }
+}
 
-        let cache_plain = plain_token(&m.token_chain);
-        // TODO 检查指令格式
-        // 检查重复
-        if in_plain == cache_plain {
-            add_cache = false;
-            return true;
+/// 解除绑定：将身份从所属的人中分离
+/// - 不带参数时解除自己的绑定
+/// - 指定其他用户时仅桥管理员可用
+fn unbind(input: &BridgeMessage, inv: &Invocation, out: &mut Vec<Reply>) {
+    let platform = inv.platform;
+    let own = Identity::new(platform, input.user.id);
+    let user = if let Some(target) = inv.user("user") {
+        if target != own && inv.permission < Permission::Admin {
+            out.push(Reply::failed(format!("权限不足: 解除其他用户的绑定需要{}权限", Permission::Admin.as_str())));
+            return;
         }
+        target
+    } else {
+        own
+    };
+    let bound = bind_map::bound(&user);
+    if bind_map::split(&user) {
+        info!("{} 解除 {} 的绑定", input.user.name, user);
+        out.push(Reply::from(format!("已解除 {} 与 {} 的绑定", user, join(&bound))));
+    } else {
+        out.push(Reply::failed(format!("{} 没有绑定", user)));
+    }
+}
+
+/// 查看自己的绑定；私聊回复
+fn my_bind(input: &BridgeMessage, inv: &Invocation, out: &mut Vec<Reply>) {
+    let platform = inv.platform;
+    let own = Identity::new(platform, input.user.id);
+    let bound = bind_map::bound(&own);
+    let mut text = if bound.is_empty() {
+        format!("{} 没有绑定, 可使用 {}绑定 <另一平台的用户id> 发起绑定", input.user.name, inv.prefix)
+    } else {
+        format!("{} 已绑定 {}", input.user.name, join(&bound))
+    };
+    let profile = bridge_user::profile(&own);
+    if let Some(name) = profile.name {
+        text += &format!("\n显示名称: {}", name);
+    }
+    if profile.avatar != AvatarSource::Auto {
+        text += &format!("\n头像来源: {:?}", profile.avatar);
+    }
+    out.push(Reply::private(text));
+}
 
-        // TODO 检查映射
-        // 尝试匹配
-        if cache_plain.contains(&input.user.name) && in_platform != m.platform {
-            // TODO 建立映射
-            println!("\t\t{}\n\t\t\t匹配\n\t\t{}", in_plain, cache_plain);
-            add_cache = false;
-            return false;
+/// 查看指定用户的绑定
+fn whois(inv: &Invocation, out: &mut Vec<Reply>) {
+    let target = match inv.user("user") {
+        Some(target) => target,
+        None => return,
+    };
+    let bound = bind_map::bound(&target);
+    let text = if bound.is_empty() {
+        format!("{} 没有绑定", target)
+    } else {
+        format!("{} 绑定了 {}", target, join(&bound))
+    };
+    out.push(Reply::from(text));
+}
+
+/// 设置两端统一显示的名称；不带参数时清除
+fn nickname(input: &BridgeMessage, inv: &Invocation, out: &mut Vec<Reply>) {
+    let platform = inv.platform;
+    let name = inv.text("name").map(|n| n.to_string());
+    if name.as_ref().map(|n| n.chars().count() > 32).unwrap_or(false) {
+        out.push(Reply::failed("名称不能超过 32 个字".to_string()));
+        return;
+    }
+    bridge_user::update(&Identity::new(platform, input.user.id), |p| p.name = name.clone());
+    let text = match name {
+        Some(name) => format!("{} 将显示为 {}", input.user.name, name),
+        None => format!("{} 已清除显示名称", input.user.name),
+    };
+    out.push(Reply::from(text));
+}
+
+/// 设置头像来源
+fn avatar(input: &BridgeMessage, inv: &Invocation, out: &mut Vec<Reply>) {
+    let platform = inv.platform;
+    let source = match inv.text("source").and_then(AvatarSource::parse) {
+        Some(source) => source,
+        None => {
+            out.push(Reply::failed(inv.usage()));
+            return;
         }
+    };
+    bridge_user::update(&Identity::new(platform, input.user.id), |p| p.avatar = source);
+    out.push(Reply::from(format!("{} 的头像来源已设置为 {:?}", input.user.name, source)));
+}
 
-        true
-    });
-    if add_cache {
-        let meta = CmdMeta {
-            token_chain: input.message_chain.clone(),
-            operator: input.user.clone(),
-            platform: in_platform,
-        };
-        println!("cache bind-cmd {:?}", meta);
-        caches.push((now, meta));
-        println!("cache count {}", caches.len());
+fn join(identities: &[Identity]) -> String {
+    identities.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// 绑定码过期，通知双方
+fn bind_expired(bridge: &bridge::BridgeClient, request: &BindRequest) {
+    let text = format!("{} 的绑定码 {} 已过期", request.operator.name, request.code);
+    notify(bridge, request.platform, &request.bridge_config, text.clone());
+    notify(bridge, other_platform(request.platform), &request.bridge_config, text);
+}
+
+fn other_platform(platform: BridgeClientPlatform) -> BridgeClientPlatform {
+    match platform {
+        BridgeClientPlatform::QQ => BridgeClientPlatform::Discord,
+        BridgeClientPlatform::Discord => BridgeClientPlatform::QQ,
     }
 }
 
-/// 取指令文本
-/// - token_chain 指令内容
-fn plain_token(token_chain: &MessageChain) -> String {
-    let mut plain = String::new();
-    for token in token_chain {
-        match token {
-            MessageContent::Plain { text } => {
-                plain += text
+/// 检索历史消息；只检索指令所在的桥
+fn search(bridge: &bridge::BridgeClient, input: &BridgeMessage, inv: &Invocation, out: &mut Vec<Reply>) {
+    let query = match search_query(inv.text("query").unwrap_or_default()) {
+        Ok(q) => SearchQuery {
+            bridge: Some((input.bridge_config.qqGroup, input.bridge_config.discord.channelId)),
+            ..q
+        },
+        Err(e) => {
+            out.push(Reply::failed(format!("{}\n{}", e, inv.usage())));
+            return;
+        }
+    };
+    let archive = match bridge.archive() {
+        Some(a) => a,
+        None => {
+            out.push(Reply::failed("未开启消息归档".to_string()));
+            return;
+        }
+    };
+    let found = match archive.search(&query) {
+        Ok(list) => list,
+        Err(e) => {
+            error!("检索历史消息失败: {:?}", e);
+            out.push(Reply::failed("检索失败".to_string()));
+            return;
+        }
+    };
+    if found.is_empty() {
+        out.push(Reply::from("没有找到相关消息".to_string()));
+        return;
+    }
+    let mut lines = vec![format!("找到 {} 条消息:", found.len())];
+    for msg in found {
+        let time = Local.timestamp_millis(msg.created_at).format("%Y-%m-%d %H:%M");
+        lines.push(format!("[{}] {}: {}", time, msg.user_name, msg.text));
+    }
+    out.push(Reply::from(lines.join("\n")));
+}
+
+/// 列出指令，或回复指定指令的用法
+fn help(inv: &Invocation, out: &mut Vec<Reply>) {
+    match bridge_cmd::help(&inv.prefix, inv.text("cmd"), inv.permission) {
+        Ok(text) => out.push(Reply::from(text)),
+        Err(e) => out.push(Reply::failed(e)),
+    }
+}
+
+/// 桥管理：查看、暂停、恢复桥，或建立新的桥
+/// - 修改会写回配置文件
+async fn manage(config: &SharedConfig, input: &BridgeMessage, inv: &Invocation, out: &mut Vec<Reply>) {
+    let current = &input.bridge_config;
+    let action = inv.text("action").unwrap_or_default().to_lowercase();
+    match action.as_str() {
+        "status" | "状态" => out.push(Reply::from(status(&config.load(), current, &inv.prefix))),
+        "pause" | "暂停" | "resume" | "恢复" => {
+            let enable = matches!(action.as_str(), "resume" | "恢复");
+            let state = if enable { "恢复" } else { "暂停" };
+            if !current.is_linked() {
+                out.push(Reply::failed("所在的群或频道没有建立桥".to_string()));
+                return;
             }
-            _ => {},
+            if current.enable == enable {
+                out.push(Reply::failed(format!("桥 {} 已经{}", current.id(), state)));
+                return;
+            }
+            let id = current.id();
+            let result = config_watch::update(config, |c| {
+                if let Some(bridge) = c.bridges.iter_mut().find(|b| b.id() == id) {
+                    bridge.enable = enable;
+                }
+            });
+            match result {
+                Ok(()) => {
+                    info!("{} {}了桥 {}", input.user.name, state, id);
+                    out.push(Reply::from(format!("桥 {} 已{}", id, state)));
+                }
+                Err(e) => out.push(Reply::failed(format!("修改配置失败:\n{}", e))),
+            }
+        }
+        "link" | "连接" => link(config, input, inv, out).await,
+        _ => out.push(Reply::failed(inv.usage())),
+    }
+}
+
+/// 桥的状态
+fn status(config: &Config, bridge_config: &BridgeConfig, prefix: &str) -> String {
+    let running = config.bridges.iter().filter(|b| b.enable).count();
+    let summary = format!("共 {} 个桥, {} 个运行中", config.bridges.len(), running);
+    if !bridge_config.is_linked() {
+        return format!(
+            "所在的群或频道没有建立桥, 可使用 {}bridge link <另一平台的群号或频道id> 建立\n{}",
+            prefix, summary
+        );
+    }
+    let state = if bridge_config.enable { "运行中" } else { "已暂停" };
+    format!(
+        "桥 {}: {}\nQQ群: {}\nDiscord频道: {}\n指令前缀: {}\n{}",
+        bridge_config.id(),
+        state,
+        bridge_config.qqGroup,
+        bridge_config.discord.channelId,
+        bridge_config.cmdPrefix,
+        summary
+    )
+}
+
+/// 将所在的群或频道与另一平台建立桥，并为 discord 频道创建 webhook
+/// - 仅桥管理员可用
+async fn link(config: &SharedConfig, input: &BridgeMessage, inv: &Invocation, out: &mut Vec<Reply>) {
+    if inv.permission < Permission::Admin {
+        out.push(Reply::failed(format!("权限不足: 建立桥需要{}权限", Permission::Admin.as_str())));
+        return;
+    }
+    let current = &input.bridge_config;
+    if current.is_linked() {
+        out.push(Reply::failed(format!("所在的群或频道已建立桥 {}", current.id())));
+        return;
+    }
+    let target: u64 = match inv.text("target").and_then(|t| t.parse().ok()) {
+        Some(target) => target,
+        None => {
+            out.push(Reply::failed(inv.usage()));
+            return;
+        }
+    };
+    let (group, channel) = match inv.platform {
+        BridgeClientPlatform::QQ => (current.qqGroup, target),
+        BridgeClientPlatform::Discord => (target, current.discord.channelId),
+    };
+    let snapshot = config.load_full();
+    if let Some(used) = snapshot.bridges.iter().find(|b| b.qqGroup == group || b.discord.channelId == channel) {
+        out.push(Reply::failed(format!("{} 已被桥 {} 使用", target, used.id())));
+        return;
+    }
+    let discord = match bridge_dc::ensure_webhook(&bridge_dc::http(), channel).await {
+        Ok(discord) => discord,
+        Err(e) => {
+            out.push(Reply::failed(e));
+            return;
         }
+    };
+    let bridge_config = BridgeConfig {
+        discord,
+        enable: true,
+        ..BridgeConfig::unlinked(group, channel)
+    };
+    let id = bridge_config.id();
+    match config_watch::update(config, |c| c.bridges.push(bridge_config)) {
+        Ok(()) => {
+            info!("{} 建立了桥 {}", input.user.name, id);
+            out.push(Reply::from(format!("已建立桥 {}", id)));
+        }
+        Err(e) => out.push(Reply::failed(format!("修改配置失败:\n{}", e))),
+    }
+}
+
+/// 发送指令的回复，并按同步方式转发指令与回复
+/// - mode 指令在桥上的同步方式；Mirror 时指令消息已由来源客户端转发
+fn respond(bridge: &bridge::BridgeClient, input: &BridgeMessage, mode: ForwardMode, replies: Vec<Reply>) {
+    let platform = match input.from_platform() {
+        Some(p) => p,
+        None => return,
+    };
+    let ok = !replies.iter().any(|r| r.failed);
+    let mirror = match mode {
+        ForwardMode::Mirror => true,
+        ForwardMode::Hide => false,
+        ForwardMode::OnSuccess => ok,
+    };
+    if mode == ForwardMode::OnSuccess && ok {
+        bridge.send_from(platform.client_name(), input.clone());
+    }
+    for r in replies {
+        if mirror && !r.private {
+            send(bridge, other_platform(platform), &input.bridge_config, None, r.chain.clone());
+        }
+        reply(bridge, input, r);
     }
-    plain
+}
+
+/// 回复到指令来源的会话，或私聊操作者
+/// - input 指令消息
+/// - reply 回复内容
+fn reply(bridge: &bridge::BridgeClient, input: &BridgeMessage, reply: impl Into<Reply>) {
+    let reply = reply.into();
+    let target = if reply.private { input.private_channel() } else { input.origin };
+    let platform = match target.map(|t| t.platform()).or_else(|| input.from_platform()) {
+        Some(p) => p,
+        None => return,
+    };
+    send(bridge, platform, &input.bridge_config, target, reply.chain);
+}
+
+/// 以桥的名义发送到指定平台
+/// - platform 目标平台
+/// - bridge_config 目标桥
+/// - text 内容
+fn notify(bridge: &bridge::BridgeClient, platform: BridgeClientPlatform, bridge_config: &BridgeConfig, text: String) {
+    send(bridge, platform, bridge_config, None, vec![MessageContent::Plain { text }]);
+}
+
+/// 以桥的名义发送
+/// - target 投递的会话；为空时发往桥配置的群与频道
+fn send(
+    bridge: &bridge::BridgeClient,
+    platform: BridgeClientPlatform,
+    bridge_config: &BridgeConfig,
+    target: Option<Channel>,
+    message_chain: MessageChain,
+) {
+    let msg = BridgeMessage {
+        id: uuid::Uuid::new_v4().to_string(),
+        origin_id: None,
+        bridge_config: bridge_config.clone(),
+        message_chain,
+        user: User {
+            name: "[Bridge]".to_string(),
+            avatar_url: None,
+            id: 0,
+            permission: Permission::Member,
+        },
+        origin: None,
+        target,
+        reply_to: None,
+    };
+    bridge.send_to(platform.client_name(), &msg);
 }
@@ -1,46 +1,189 @@
-use std::fs;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use arc_swap::ArcSwap;
 use serde::Deserialize;
 use serde::Serialize;
 
-#[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
+use crate::bridge_cmd::CmdDef;
+use crate::bridge_data::Identity;
+use crate::bridge_voice::AudioFormat;
+use crate::config_loader::{self, ConfigError, ConfigErrors};
+
+/// 运行时共享的配置；可整体替换
+pub type SharedConfig = Arc<ArcSwap<Config>>;
+
+/// 默认配置文件
+pub const CONFIG_PATH: &str = "./config.json";
+
+#[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
 pub struct Config {
     pub miraiConfig: MiraiConfig,
     pub discordConfig: DiscordConfig,
     pub bridges: Vec<BridgeConfig>,
     pub bridgesUsers: Vec<BridgeUser>,
+    #[serde(default)]
+    pub logConfig: LogConfig,
+    #[serde(default)]
+    pub archiveConfig: ArchiveConfig,
+    #[serde(default)]
+    pub bindStoreConfig: BindStoreConfig,
+    #[serde(default)]
+    pub metricsConfig: MetricsConfig,
+    #[serde(default)]
+    pub adminConfig: AdminConfig,
+    /// 桥指令的管理员；平台身份，如 `QQ:123`、`Discord:456`
+    #[serde(default)]
+    pub admins: Vec<String>,
+    /// qq 表情与 emoji 的对照文件（JSON）；不配置时使用内置对照
+    #[serde(default)]
+    pub faceMapFile: Option<String>,
+    #[serde(default)]
+    pub voiceConfig: VoiceConfig,
+    /// 配置文件路径
+    #[serde(skip)]
+    pub path: PathBuf,
 }
 
 impl Config {
+    /// 读取默认路径的配置，失败时 panic；只用于测试
+    #[cfg(test)]
     pub fn new() -> Self {
-        let file = fs::read_to_string("./config.json").unwrap();
-        // println!("{file}");
-        let config: Config = serde_json::from_str(file.as_str()).unwrap();
+        match Self::load(CONFIG_PATH) {
+            Ok(config) => config,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// 读取并校验配置文件
+    /// - 按扩展名支持 JSON/TOML/YAML
+    /// - 字符串中的 `${ENV}` 替换为环境变量
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigErrors> {
+        let path = path.as_ref();
+        let mut config = config_loader::load(path)?;
+        config.path = path.to_path_buf();
+        config.validate()?;
+        Ok(config)
+    }
 
-        config
+    /// 写回配置文件
+    pub fn save(&self) -> Result<(), ConfigErrors> {
+        config_loader::save(&self.path, self)
     }
 
-    pub fn add_user(&mut self, qq: u64, discord_id: u64) {
-        self.bridgesUsers.push(BridgeUser {
-            id: uuid::Uuid::new_v4().to_string(),
-            qq,
-            discordId: discord_id
-        });
-        let content = serde_json::to_string(&self).unwrap();
-        fs::write("./config.json", content).unwrap();
+    /// 校验配置内容，返回全部错误
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut errors = vec![];
+        if self.miraiConfig.host.is_empty() {
+            errors.push(ConfigError::new("miraiConfig.host", "不能为空"));
+        }
+        if self.miraiConfig.port == 0 {
+            errors.push(ConfigError::new("miraiConfig.port", "不能为 0"));
+        }
+        if self.discordConfig.botToken.is_empty() {
+            errors.push(ConfigError::new("discordConfig.botToken", "不能为空"));
+        }
+        if self.adminConfig.enable && self.adminConfig.token.is_empty() && !self.adminConfig.is_loopback() {
+            errors.push(ConfigError::new("adminConfig.token", "监听非本机地址时不能为空"));
+        }
+        for (i, bridge) in self.bridges.iter().enumerate() {
+            let location = format!("bridges[{}]", i);
+            if bridge.qqGroup == 0 {
+                errors.push(ConfigError::new(format!("{}.qqGroup", location), "不能为空"));
+            }
+            if bridge.discord.channelId == 0 {
+                errors.push(ConfigError::new(format!("{}.discord.channelId", location), "不能为空"));
+            }
+            if bridge.cmdPrefix.trim().is_empty() || bridge.cmdPrefix.contains(char::is_whitespace) {
+                errors.push(ConfigError::new(format!("{}.cmdPrefix", location), "不能为空或包含空白"));
+            }
+            let mut names: Vec<&String> = bridge.cmdForward.commands.keys().collect();
+            names.sort();
+            let mut configured: HashMap<&str, &str> = HashMap::new();
+            for name in names {
+                match CmdDef::find(name) {
+                    None => {
+                        errors.push(ConfigError::new(format!("{}.cmdForward.commands.{}", location, name), "未知指令"));
+                    }
+                    Some(def) => {
+                        // 同一指令的名称与别名只能配置一项
+                        if let Some(other) = configured.insert(def.names[0], name) {
+                            errors.push(ConfigError::new(
+                                format!("{}.cmdForward.commands.{}", location, name),
+                                format!("与 {} 是同一指令", other),
+                            ));
+                        }
+                    }
+                }
+            }
+            if (bridge.discord.id == 0) != bridge.discord.token.is_empty() {
+                errors.push(ConfigError::new(format!("{}.discord", location), "webhook 的 id 与 token 需同时配置"));
+            }
+            // 子区中不能再创建子区
+            if bridge.discord.replyThreads
+                && (bridge.discord.threadId != 0 || bridge.discord.kind == DiscordChannelKind::Forum)
+            {
+                errors.push(ConfigError::new(
+                    format!("{}.discord.replyThreads", location),
+                    "只能用于同步整个文字频道的桥",
+                ));
+            }
+            // 与之前的桥使用了相同的频道
+            for (j, other) in self.bridges.iter().enumerate().take(i) {
+                let same_channel = other.discord.channelId == bridge.discord.channelId;
+                let same_group = other.qqGroup == bridge.qqGroup;
+                if !same_channel && !same_group {
+                    continue;
+                }
+                let field = if same_channel { "discord.channelId" } else { "qqGroup" };
+                let message = if bridge.enable && other.enable {
+                    format!("与 bridges[{}] 重复", j)
+                } else {
+                    format!("桥已禁用, 但其频道仍被 bridges[{}] 使用", j)
+                };
+                errors.push(ConfigError::new(format!("{}.{}", location, field), message));
+            }
+        }
+        for (i, admin) in self.admins.iter().enumerate() {
+            if let Err(message) = admin.parse::<Identity>() {
+                errors.push(ConfigError::new(format!("admins[{}]", i), message));
+            }
+        }
+        if !matches!(self.voiceConfig.qqFormat, AudioFormat::Amr | AudioFormat::Silk) {
+            errors.push(ConfigError::new("voiceConfig.qqFormat", "qq 只支持 amr 与 silk"));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrors(errors))
+        }
+    }
+
+    /// 是否为桥指令的管理员
+    pub fn is_admin(&self, user: &Identity) -> bool {
+        self.admins.iter().any(|a| a.parse::<Identity>().as_ref() == Ok(user))
+    }
+
+    /// 按标识查找桥
+    pub fn find_bridge(&self, id: &str) -> Option<&BridgeConfig> {
+        self.bridges.iter().find(|b| b.id() == id)
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
 pub struct MiraiConfig {
     pub verifyKey: String,
     pub host: String,
     pub port: u32,
 }
 
-#[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
 pub struct DiscordConfig {
     pub botId: u64,
     pub botToken: String,
+    /// 拥有这些角色的成员视为管理者，可使用需要管理权限的指令
+    #[serde(default)]
+    pub managerRoles: Vec<u64>,
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
@@ -48,13 +191,313 @@ pub struct BridgeConfig {
     pub discord: DiscordBridgeConfig,
     pub qqGroup: u64,
     pub enable: bool,
+    /// 指令前缀，默认为 `!`
+    #[serde(default = "default_cmd_prefix")]
+    pub cmdPrefix: String,
+    /// 指令消息及其回复是否同步到另一端
+    #[serde(default)]
+    pub cmdForward: CmdForwardConfig,
+    /// discord 自定义表情以图片发送到 qq；否则显示为 `:name:`
+    #[serde(default)]
+    pub emojiImage: bool,
+    /// 转发文件的最大字节数，默认 8MiB；超过时只转发文件链接，0 表示不转发文件本身
+    #[serde(default = "default_max_file_size")]
+    pub maxFileSize: u64,
 }
 
-#[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
+fn default_cmd_prefix() -> String {
+    "!".to_string()
+}
+
+fn default_max_file_size() -> u64 {
+    8 * 1024 * 1024
+}
+
+impl BridgeConfig {
+    /// 桥的标识：`qq群号-discord频道id`
+    pub fn id(&self) -> String {
+        format!("{}-{}", self.qqGroup, self.discord.channelId)
+    }
+
+    /// 尚未建立桥的群或频道；另一端为 0，只用于接收桥管理指令
+    pub fn unlinked(qq_group: u64, channel_id: u64) -> Self {
+        BridgeConfig {
+            discord: DiscordBridgeConfig {
+                channelId: channel_id,
+                ..Default::default()
+            },
+            qqGroup: qq_group,
+            enable: false,
+            cmdPrefix: default_cmd_prefix(),
+            cmdForward: Default::default(),
+            emojiImage: false,
+            maxFileSize: default_max_file_size(),
+        }
+    }
+
+    /// 是否已建立桥
+    pub fn is_linked(&self) -> bool {
+        self.qqGroup != 0 && self.discord.channelId != 0
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize, Debug, Eq, PartialEq)]
 pub struct DiscordBridgeConfig {
+    /// webhook 的 id 与 token；都不配置时启动后自动创建
+    #[serde(default)]
     pub id: u64,
+    #[serde(default)]
     pub token: String,
     pub channelId: u64,
+    /// 频道类型
+    #[serde(default)]
+    pub kind: DiscordChannelKind,
+    /// 只同步频道下的这个子区或论坛帖子；0 时同步频道及其所有子区。
+    /// 论坛频道未配置时，首条消息会创建帖子并写回配置
+    #[serde(default)]
+    pub threadId: u64,
+    /// 将 qq 的回复同步到被回复消息在 discord 上的子区
+    #[serde(default)]
+    pub replyThreads: bool,
+}
+
+impl DiscordBridgeConfig {
+    /// 是否已配置 webhook
+    pub fn has_webhook(&self) -> bool {
+        self.id != 0 && !self.token.is_empty()
+    }
+
+    /// 消息是否来自桥同步的位置
+    /// - parent 子区所在的频道；不是子区时为 0
+    pub fn accepts(&self, channel: u64, parent: u64) -> bool {
+        if self.threadId != 0 {
+            return channel == self.threadId;
+        }
+        channel == self.channelId || (parent != 0 && parent == self.channelId)
+    }
+}
+
+/// discord 频道类型
+#[derive(Clone, Copy, Default, Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum DiscordChannelKind {
+    /// 文字频道，消息直接发送到频道
+    #[default]
+    Text,
+    /// 论坛频道，消息发送到帖子中
+    Forum,
+}
+
+/// 日志配置
+#[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[serde(default)]
+pub struct LogConfig {
+    /// 默认日志级别: trace, debug, info, warn, error
+    pub level: String,
+    /// 按模块指定级别，如 `{"bridge_qq": "debug", "mirai_rs": "warn"}`
+    pub targets: HashMap<String, String>,
+    /// 输出格式
+    pub format: LogFormat,
+    /// 是否输出到控制台
+    pub console: bool,
+    /// 日志文件目录；不配置则不写文件
+    pub dir: Option<String>,
+    /// 日志文件名
+    pub fileName: String,
+    /// 单个文件最大字节数；0 表示不按大小切割
+    pub maxSize: u64,
+    /// 按时间切割
+    pub rotation: LogRotation,
+    /// 保留的历史文件个数
+    pub maxFiles: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_string(),
+            targets: HashMap::new(),
+            format: LogFormat::Text,
+            console: true,
+            dir: None,
+            fileName: "bridge.log".to_string(),
+            maxSize: 10 * 1024 * 1024,
+            rotation: LogRotation::Daily,
+            maxFiles: 7,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+/// 消息归档配置
+#[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    pub enable: bool,
+    /// SQLite 数据库文件
+    pub path: String,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        ArchiveConfig {
+            enable: true,
+            path: "./data/archive.db".to_string(),
+        }
+    }
+}
+
+/// 支持 silk 的转码命令，以 `["sh", "-c", SILK_COMMAND]` 配置
+/// - ffmpeg 不能编解码 silk，silk 与 pcm 之间由 silk-v3-decoder 编译的
+///   silk_v3_decoder/silk_v3_encoder 转换，其余格式由 ffmpeg 转换
+#[allow(dead_code)]
+pub const SILK_COMMAND: &str = r#"
+case "{input}" in *.silk)
+    silk_v3_decoder "{input}" "{output}.pcm" -Fs_API 24000 -quiet &&
+        ffmpeg -y -loglevel error -f s16le -ar 24000 -ac 1 -i "{output}.pcm" "{output}"
+    status=$?; rm -f "{output}.pcm"; exit $status;;
+esac
+case "{output}" in *.silk)
+    ffmpeg -y -loglevel error -i "{input}" -f s16le -ar 24000 -ac 1 "{output}.pcm" &&
+        silk_v3_encoder "{output}.pcm" "{output}" -Fs_API 24000 -tencent -quiet
+    status=$?; rm -f "{output}.pcm"; exit $status;;
+esac
+ffmpeg -y -loglevel error -i "{input}" "{output}"
+"#;
+
+/// 语音转发配置
+#[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[serde(default)]
+pub struct VoiceConfig {
+    pub enable: bool,
+    /// 转码命令，`{input}` `{output}` 替换为文件路径，如 `["ffmpeg", "-y", "-i", "{input}", "{output}"]`；
+    /// 为空时不转码，只转发对方可以直接使用的语音；需要转码 silk 时见 [`SILK_COMMAND`]
+    pub command: Vec<String>,
+    /// 发送到 qq 的格式，qq 只支持 amr 与 silk
+    pub qqFormat: AudioFormat,
+    /// 发送到 discord 的格式
+    pub discordFormat: AudioFormat,
+    /// 语音文件最大字节数
+    pub maxSize: u64,
+}
+
+impl Default for VoiceConfig {
+    fn default() -> Self {
+        VoiceConfig {
+            enable: true,
+            command: vec![],
+            qqFormat: AudioFormat::Amr,
+            discordFormat: AudioFormat::Ogg,
+            maxSize: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// 绑定数据存储配置
+#[derive(Clone, Deserialize, Serialize, Debug, Default, Eq, PartialEq)]
+#[serde(default)]
+pub struct BindStoreConfig {
+    pub backend: BindStoreBackend,
+    /// 数据文件；为空时按存储方式使用默认路径
+    pub path: Option<String>,
+}
+
+/// 指令的同步方式
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ForwardMode {
+    /// 指令与回复都同步到另一端
+    Mirror,
+    /// 不同步
+    Hide,
+    /// 执行成功后再同步
+    OnSuccess,
+}
+
+/// 指令同步配置；未配置时使用指令自身的默认方式
+#[derive(Clone, Deserialize, Serialize, Debug, Default, Eq, PartialEq)]
+pub struct CmdForwardConfig {
+    /// 桥内全部指令的同步方式
+    #[serde(default)]
+    pub default: Option<ForwardMode>,
+    /// 按指令名或别名指定，优先于 default
+    #[serde(default)]
+    pub commands: HashMap<String, ForwardMode>,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BindStoreBackend {
+    /// JSON 文件
+    #[default]
+    Json,
+    /// SQLite 数据库
+    Sqlite,
+}
+
+/// 指标服务配置
+#[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enable: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enable: false,
+            host: "127.0.0.1".to_string(),
+            port: 9100,
+        }
+    }
+}
+
+/// 管理接口配置
+#[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub enable: bool,
+    pub host: String,
+    pub port: u16,
+    /// 访问管理接口所需的 Bearer token；只监听本机地址时可以为空，不校验（`/health` 始终不校验）
+    pub token: String,
+}
+
+impl AdminConfig {
+    /// 是否只监听本机地址
+    pub fn is_loopback(&self) -> bool {
+        match self.host.parse::<std::net::IpAddr>() {
+            Ok(ip) => ip.is_loopback(),
+            Err(_) => self.host == "localhost",
+        }
+    }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        AdminConfig {
+            enable: false,
+            host: "127.0.0.1".to_string(),
+            port: 9200,
+            token: String::new(),
+        }
+    }
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug, Eq, PartialEq)]
@@ -66,6 +509,20 @@ pub struct BridgeUser {
 
 
 
+/// 测试用的最小配置
+#[cfg(test)]
+pub fn test_config() -> Config {
+    serde_json::from_value(serde_json::json!({
+        "miraiConfig": { "verifyKey": "key", "host": "127.0.0.1", "port": 8080 },
+        "discordConfig": { "botId": 1, "botToken": "token" },
+        "bridges": [
+            { "discord": { "id": 10, "token": "hook", "channelId": 20 }, "qqGroup": 30, "enable": true }
+        ],
+        "bridgesUsers": []
+    }))
+    .unwrap()
+}
+
 #[cfg(test)]
 #[allow(non_snake_case)]
 mod test {
@@ -79,9 +536,119 @@ mod test {
     }
 
     #[test]
-    fn addUser() {
-        let mut config = Config::new();
+    fn validateErrors() {
+        let mut config = test_config();
+        assert!(config.validate().is_ok());
 
-        config.add_user(000321, 111111)
+        let mut dup = config.bridges[0].clone();
+        dup.qqGroup = 31;
+        config.bridges.push(dup.clone());
+        dup.discord.channelId = 21;
+        dup.discord.token = String::new();
+        dup.enable = false;
+        config.bridges.push(dup);
+        let errors = config.validate().unwrap_err().0;
+        let locations: Vec<&str> = errors.iter().map(|e| e.location.as_str()).collect();
+        assert_eq!(
+            locations,
+            vec!["bridges[1].discord.channelId", "bridges[2].discord", "bridges[2].qqGroup"]
+        );
+        assert!(errors[2].message.contains("已禁用"));
+
+        // 未配置 webhook 时自动创建
+        let mut config = test_config();
+        config.bridges[0].discord.id = 0;
+        config.bridges[0].discord.token = String::new();
+        assert!(config.validate().is_ok());
+        assert!(!config.bridges[0].discord.has_webhook());
+
+        // 管理接口对外开放时必须配置 token
+        let mut config = test_config();
+        config.adminConfig.enable = true;
+        assert!(config.validate().is_ok());
+        config.adminConfig.host = "0.0.0.0".to_string();
+        let errors = config.validate().unwrap_err().0;
+        assert_eq!(errors[0].location, "adminConfig.token");
+        config.adminConfig.token = "secret".to_string();
+        assert!(config.validate().is_ok());
+
+        // 同一指令的名称与别名不能同时配置
+        let mut config = test_config();
+        let commands = &mut config.bridges[0].cmdForward.commands;
+        commands.insert("search".to_string(), ForwardMode::Hide);
+        commands.insert("搜索".to_string(), ForwardMode::Mirror);
+        let errors = config.validate().unwrap_err().0;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].location, "bridges[0].cmdForward.commands.搜索");
+    }
+
+    #[test]
+    fn admins() {
+        let mut config = test_config();
+        config.admins = vec!["qq:123".to_string(), "Discord:abc".to_string()];
+        assert!(config.is_admin(&"QQ:123".parse().unwrap()));
+        assert!(!config.is_admin(&"Discord:123".parse().unwrap()));
+        let errors = config.validate().unwrap_err().0;
+        assert_eq!(errors[0].location, "admins[1]");
+    }
+
+    #[test]
+    fn cmdForward() {
+        let mut config = test_config();
+        assert_eq!(config.bridges[0].cmdForward, CmdForwardConfig::default());
+        config.bridges[0].cmdForward.commands.insert("绑定".to_string(), ForwardMode::Mirror);
+        assert!(config.validate().is_ok());
+        config.bridges[0].cmdForward.commands.insert("hello".to_string(), ForwardMode::Hide);
+        let errors = config.validate().unwrap_err().0;
+        assert_eq!(errors[0].location, "bridges[0].cmdForward.commands.hello");
+
+        let forward: CmdForwardConfig =
+            serde_json::from_value(serde_json::json!({ "default": "onSuccess", "commands": { "search": "hide" } })).unwrap();
+        assert_eq!(forward.default, Some(ForwardMode::OnSuccess));
+        assert_eq!(forward.commands["search"], ForwardMode::Hide);
+    }
+
+    #[test]
+    fn discordThreads() {
+        let discord: DiscordBridgeConfig =
+            serde_json::from_value(serde_json::json!({ "channelId": 2, "kind": "forum" })).unwrap();
+        assert_eq!(discord.kind, DiscordChannelKind::Forum);
+        // 同步频道及其子区
+        assert!(discord.accepts(2, 0));
+        assert!(discord.accepts(5, 2));
+        assert!(!discord.accepts(5, 0));
+        let thread = DiscordBridgeConfig { threadId: 5, ..discord };
+        assert!(thread.accepts(5, 2));
+        assert!(!thread.accepts(2, 0));
+        assert!(!thread.accepts(6, 2));
+
+        let mut config = test_config();
+        config.bridges[0].discord.replyThreads = true;
+        assert!(config.validate().is_ok());
+        config.bridges[0].discord.kind = DiscordChannelKind::Forum;
+        let errors = config.validate().unwrap_err().0;
+        assert_eq!(errors[0].location, "bridges[0].discord.replyThreads");
+    }
+
+    #[test]
+    fn voiceConfig() {
+        let mut config = test_config();
+        assert_eq!(config.voiceConfig.qqFormat, AudioFormat::Amr);
+        config.voiceConfig = serde_json::from_value(serde_json::json!({ "qqFormat": "mp3" })).unwrap();
+        assert_eq!(config.voiceConfig.discordFormat, AudioFormat::Ogg);
+        let errors = config.validate().unwrap_err().0;
+        assert_eq!(errors[0].location, "voiceConfig.qqFormat");
+    }
+
+    #[test]
+    fn maxFileSize() {
+        let bridge: BridgeConfig = serde_json::from_value(serde_json::json!({
+            "discord": { "id": 1, "token": "t", "channelId": 2 },
+            "qqGroup": 3,
+            "enable": true,
+        }))
+        .unwrap();
+        assert_eq!(bridge.maxFileSize, 8 * 1024 * 1024);
+        assert_eq!(BridgeConfig::unlinked(3, 0).maxFileSize, 8 * 1024 * 1024);
     }
 }
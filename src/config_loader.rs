@@ -0,0 +1,316 @@
+//! 配置文件读写：支持 JSON/TOML/YAML，字符串中的 `${ENV}` 引用环境变量
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::config::Config;
+
+/// 一条配置错误及其位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// 出错位置，如 `bridges[1].discord.token` 或 `config.toml:3:5`
+    pub location: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(location: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigError {
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// 全部配置错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines: Vec<String> = self.0.iter().map(|e| e.to_string()).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl From<ConfigError> for ConfigErrors {
+    fn from(e: ConfigError) -> Self {
+        ConfigErrors(vec![e])
+    }
+}
+
+/// 配置文件格式，按扩展名识别；默认 JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+}
+
+/// 读取配置文件，替换环境变量并解析；不做内容校验
+pub fn load(path: &Path) -> Result<Config, ConfigErrors> {
+    let mut value = read_document(path)?;
+    let mut errors = vec![];
+    substitute_env(&mut value, "", &mut errors);
+    if !errors.is_empty() {
+        return Err(ConfigErrors(errors));
+    }
+    let config: Config = serde_path_to_error::deserialize(value).map_err(|e| {
+        let location = match e.path().to_string().as_str() {
+            "." => path.display().to_string(),
+            p => p.to_string(),
+        };
+        ConfigError::new(location, e.inner().to_string())
+    })?;
+    Ok(config)
+}
+
+/// 写回配置文件
+/// - 原文件中以 `${ENV}` 引用的值未被修改时保留引用，避免把密钥写入文件
+pub fn save(path: &Path, config: &Config) -> Result<(), ConfigErrors> {
+    let mut value = serde_json::to_value(config)
+        .map_err(|e| ConfigError::new(path.display().to_string(), e.to_string()))?;
+    if let Ok(raw) = read_document(path) {
+        keep_env_refs(&mut value, &raw);
+    }
+    let format = ConfigFormat::from_path(path);
+    let content = match format {
+        ConfigFormat::Json => serde_json::to_string_pretty(&value).map_err(|e| e.to_string()),
+        ConfigFormat::Toml => {
+            strip_null(&mut value);
+            // 经 toml::Value 转换，保证普通字段写在子表之前
+            toml::Value::try_from(&value)
+                .and_then(|v| toml::to_string_pretty(&v))
+                .map_err(|e| e.to_string())
+        }
+        ConfigFormat::Yaml => serde_yaml::to_string(&value).map_err(|e| e.to_string()),
+    };
+    let content = content.map_err(|e| ConfigError::new(path.display().to_string(), e))?;
+    // 先写临时文件再替换，避免写入中途出错损坏配置
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| ConfigError::new(path.display().to_string(), e.to_string()))?;
+    Ok(())
+}
+
+/// 按格式读取为通用文档
+fn read_document(path: &Path) -> Result<Value, ConfigErrors> {
+    let file = path.display().to_string();
+    let raw = fs::read_to_string(path).map_err(|e| ConfigError::new(&file, format!("无法读取配置文件: {}", e)))?;
+    let value = match ConfigFormat::from_path(path) {
+        ConfigFormat::Json => serde_json::from_str(&raw)
+            .map_err(|e| ConfigError::new(format!("{}:{}:{}", file, e.line(), e.column()), e.to_string()))?,
+        ConfigFormat::Toml => toml::from_str(&raw).map_err(|e| {
+            let location = match e.line_col() {
+                Some((line, col)) => format!("{}:{}:{}", file, line + 1, col + 1),
+                None => file.clone(),
+            };
+            ConfigError::new(location, e.to_string())
+        })?,
+        ConfigFormat::Yaml => serde_yaml::from_str(&raw).map_err(|e| {
+            let location = match e.location() {
+                Some(l) => format!("{}:{}:{}", file, l.line(), l.column()),
+                None => file.clone(),
+            };
+            ConfigError::new(location, e.to_string())
+        })?,
+    };
+    Ok(value)
+}
+
+/// 替换字符串中的 `${NAME}` / `${NAME:-默认值}`
+fn substitute_env(value: &mut Value, location: &str, errors: &mut Vec<ConfigError>) {
+    match value {
+        Value::String(s) if s.contains("${") => match expand(s, |name| std::env::var(name).ok()) {
+            Ok(expanded) => *s = expanded,
+            Err(message) => errors.push(ConfigError::new(location, message)),
+        },
+        Value::Array(list) => {
+            for (i, item) in list.iter_mut().enumerate() {
+                substitute_env(item, &format!("{}[{}]", location, i), errors);
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                let location = if location.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", location, key)
+                };
+                substitute_env(item, &location, errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// 展开字符串中的变量引用
+fn expand(s: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        out += &rest[..start];
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(format!("变量引用缺少 '}}': {}", s)),
+        };
+        let expr = &rest[start + 2..end];
+        let (name, default) = match expr.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expr, None),
+        };
+        match (lookup(name), default) {
+            (Some(v), _) => out += &v,
+            (None, Some(d)) => out += d,
+            (None, None) => return Err(format!("环境变量 {} 未设置", name)),
+        }
+        rest = &rest[end + 1..];
+    }
+    out += rest;
+    Ok(out)
+}
+
+/// 新值与原文件中引用展开后的值相同时，保留原引用
+fn keep_env_refs(value: &mut Value, raw: &Value) {
+    match (value, raw) {
+        (Value::String(s), Value::String(r)) if r.contains("${") => {
+            if let Ok(expanded) = expand(r, |name| std::env::var(name).ok()) {
+                if &expanded == s {
+                    *s = r.clone();
+                }
+            }
+        }
+        (Value::Array(list), Value::Array(raw_list)) => {
+            for (item, raw_item) in list.iter_mut().zip(raw_list) {
+                keep_env_refs(item, raw_item);
+            }
+        }
+        (Value::Object(map), Value::Object(raw_map)) => {
+            for (key, item) in map.iter_mut() {
+                if let Some(raw_item) = raw_map.get(key) {
+                    keep_env_refs(item, raw_item);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// TOML 不支持空值，去掉值为 null 的字段
+fn strip_null(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            let keys: Vec<String> = map.iter().filter(|(_, v)| v.is_null()).map(|(k, _)| k.clone()).collect();
+            for key in keys {
+                map.remove(&key);
+            }
+            for item in map.values_mut() {
+                strip_null(item);
+            }
+        }
+        Value::Array(list) => list.iter_mut().for_each(strip_null),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+    use crate::config::test_config;
+
+    #[test]
+    fn expandEnv() {
+        let lookup = |name: &str| match name {
+            "TOKEN" => Some("abc".to_string()),
+            _ => None,
+        };
+        assert_eq!(expand("${TOKEN}", lookup), Ok("abc".to_string()));
+        assert_eq!(expand("Bot ${TOKEN}!", lookup), Ok("Bot abc!".to_string()));
+        assert_eq!(expand("${MISSING:-def}", lookup), Ok("def".to_string()));
+        assert!(expand("${MISSING}", lookup).is_err());
+        assert!(expand("${TOKEN", lookup).is_err());
+    }
+
+    #[test]
+    fn loadFormats() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config();
+        std::env::set_var("BRIDGE_TEST_BOT_TOKEN", "secret");
+        let mut value = serde_json::to_value(&config).unwrap();
+        value["discordConfig"]["botToken"] = Value::String("${BRIDGE_TEST_BOT_TOKEN}".to_string());
+        strip_null(&mut value);
+
+        let json = dir.path().join("config.json");
+        fs::write(&json, serde_json::to_string(&value).unwrap()).unwrap();
+        let toml_path = dir.path().join("config.toml");
+        fs::write(&toml_path, toml::to_string(&toml::Value::try_from(&value).unwrap()).unwrap()).unwrap();
+        let yaml = dir.path().join("config.yaml");
+        fs::write(&yaml, serde_yaml::to_string(&value).unwrap()).unwrap();
+
+        for path in [json, toml_path, yaml] {
+            let loaded = load(&path).unwrap();
+            assert_eq!(loaded.discordConfig.botToken, "secret");
+            assert_eq!(loaded.bridges, config.bridges);
+        }
+    }
+
+    #[test]
+    fn errorLocation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let mut value = serde_json::to_value(test_config()).unwrap();
+        value["bridges"][0]["discord"]["token"] = Value::String("${BRIDGE_TEST_MISSING}".to_string());
+        fs::write(&path, serde_json::to_string(&value).unwrap()).unwrap();
+        let errors = load(&path).unwrap_err();
+        assert_eq!(errors.0[0].location, "bridges[0].discord.token");
+
+        value["bridges"][0]["qqGroup"] = Value::String("abc".to_string());
+        value["bridges"][0]["discord"]["token"] = Value::String("t".to_string());
+        fs::write(&path, serde_json::to_string(&value).unwrap()).unwrap();
+        let errors = load(&path).unwrap_err();
+        assert_eq!(errors.0[0].location, "bridges[0].qqGroup");
+
+        fs::write(&path, "{\n  \"a\": }").unwrap();
+        let errors = load(&path).unwrap_err();
+        assert!(errors.0[0].location.ends_with("config.json:2:8"));
+    }
+
+    #[test]
+    fn saveKeepsEnvRefs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        std::env::set_var("BRIDGE_TEST_VERIFY_KEY", "key");
+        let mut value = serde_json::to_value(test_config()).unwrap();
+        value["miraiConfig"]["verifyKey"] = Value::String("${BRIDGE_TEST_VERIFY_KEY}".to_string());
+        fs::write(&path, serde_yaml::to_string(&value).unwrap()).unwrap();
+
+        let mut config = load(&path).unwrap();
+        config.bridges[0].enable = false;
+        save(&path, &config).unwrap();
+        let raw = fs::read_to_string(&path).unwrap();
+        assert!(raw.contains("${BRIDGE_TEST_VERIFY_KEY}"));
+        assert!(!load(&path).unwrap().bridges[0].enable);
+    }
+}
@@ -0,0 +1,181 @@
+//! 配置热更新：监听配置文件，校验通过后整体替换运行时配置
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify::{RecursiveMode, Watcher};
+use once_cell::sync::Lazy;
+use tokio::sync::{mpsc, watch as signal};
+use tracing::{error, info, warn};
+
+use crate::config::{Config, SharedConfig};
+use crate::config_loader::ConfigErrors;
+
+/// 文件变更后等待的时间，合并编辑器的连续写入
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// 运行时配置被替换时通知等待变更的任务
+static REPLACED: Lazy<signal::Sender<()>> = Lazy::new(|| signal::channel(()).0);
+
+/// 监听配置文件变更
+pub async fn watch(path: PathBuf, config: SharedConfig) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let file_name = path.file_name().map(|n| n.to_os_string());
+    let mut watcher = match notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if let Ok(event) = res {
+            let hit = event.paths.iter().any(|p| p.file_name().map(|n| n.to_os_string()) == file_name);
+            if hit && (event.kind.is_modify() || event.kind.is_create()) {
+                let _ = tx.send(());
+            }
+        }
+    }) {
+        Ok(w) => w,
+        Err(e) => {
+            error!("无法监听配置文件: {:?}", e);
+            return std::future::pending().await;
+        }
+    };
+    // 监听所在目录，编辑器保存时可能以重命名的方式替换文件
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+        error!("无法监听配置目录({}): {:?}", dir.display(), e);
+        return std::future::pending().await;
+    }
+    info!("监听配置文件: {}", path.display());
+
+    while rx.recv().await.is_some() {
+        tokio::time::sleep(DEBOUNCE).await;
+        while rx.try_recv().is_ok() {}
+        match reload(&path, &config) {
+            Ok(true) => info!("配置已更新"),
+            Ok(false) => {}
+            Err(e) => error!("配置无效, 继续使用当前配置:\n{}", e),
+        }
+    }
+}
+
+/// 重新读取配置；内容有变化时替换
+/// - 返回是否替换了配置
+pub fn reload(path: &Path, config: &SharedConfig) -> Result<bool, ConfigErrors> {
+    let next = Config::load(path)?;
+    if **config.load() == next {
+        return Ok(false);
+    }
+    replace(config, next);
+    Ok(true)
+}
+
+/// 替换运行时配置，并通知等待变更的任务
+pub fn replace(config: &SharedConfig, next: Config) {
+    config.store(std::sync::Arc::new(next));
+    REPLACED.send_replace(());
+}
+
+/// 修改运行时配置：校验通过后写回配置文件并替换
+/// - 写入触发的重新读取内容相同，不会再次替换
+pub fn update<F>(config: &SharedConfig, modify: F) -> Result<(), ConfigErrors>
+where
+    F: FnOnce(&mut Config),
+{
+    let mut next = (**config.load()).clone();
+    modify(&mut next);
+    next.validate()?;
+    next.save()?;
+    replace(config, next);
+    Ok(())
+}
+
+/// 等待配置中的某一部分发生变化
+/// - select 取出需要关注的部分，如连接凭据
+pub async fn changed<T, F>(config: &SharedConfig, select: F)
+where
+    T: PartialEq,
+    F: Fn(&Config) -> T,
+{
+    // 先订阅再读取，不会错过读取之后的替换
+    let mut replaced = REPLACED.subscribe();
+    let current = select(&config.load());
+    loop {
+        if replaced.changed().await.is_err() {
+            return std::future::pending().await;
+        }
+        if select(&config.load()) != current {
+            warn!("配置已变更");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(non_snake_case)]
+mod test {
+    use super::*;
+    use crate::config::test_config;
+    use arc_swap::ArcSwap;
+    use std::sync::Arc;
+
+    #[test]
+    fn reloadConfig() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let mut next = test_config();
+        next.path = path.clone();
+        let config: SharedConfig = Arc::new(ArcSwap::from_pointee(next.clone()));
+
+        std::fs::write(&path, serde_json::to_string(&next).unwrap()).unwrap();
+        assert_eq!(reload(&path, &config), Ok(false));
+
+        next.bridges[0].enable = false;
+        std::fs::write(&path, serde_json::to_string(&next).unwrap()).unwrap();
+        assert_eq!(reload(&path, &config), Ok(true));
+        assert!(!config.load().bridges[0].enable);
+
+        // 无效配置不替换
+        next.bridges.push(next.bridges[0].clone());
+        std::fs::write(&path, serde_json::to_string(&next).unwrap()).unwrap();
+        assert!(reload(&path, &config).is_err());
+        assert_eq!(config.load().bridges.len(), 1);
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(reload(&path, &config).is_err());
+    }
+
+    #[test]
+    fn updateConfig() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let mut current = test_config();
+        current.path = path.clone();
+        let config: SharedConfig = Arc::new(ArcSwap::from_pointee(current));
+
+        update(&config, |c| c.bridges[0].enable = false).unwrap();
+        assert!(!config.load().bridges[0].enable);
+        assert!(!Config::load(&path).unwrap().bridges[0].enable);
+        assert_eq!(reload(&path, &config), Ok(false));
+
+        // 无效的修改不写入
+        assert!(update(&config, |c| c.bridges.push(c.bridges[0].clone())).is_err());
+        assert_eq!(config.load().bridges.len(), 1);
+        assert_eq!(Config::load(&path).unwrap().bridges.len(), 1);
+    }
+
+    #[test]
+    fn credentialsChanged() {
+        tokio_test::block_on(async {
+            let config: SharedConfig = Arc::new(ArcSwap::from_pointee(test_config()));
+            let update = config.clone();
+            let waiting = changed(&config, |c| c.miraiConfig.clone());
+            let modify = async move {
+                let mut next = (**update.load()).clone();
+                next.bridges[0].enable = false;
+                replace(&update, next.clone());
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                next.miraiConfig.verifyKey = "new-key".to_string();
+                replace(&update, next);
+            };
+            tokio::join!(waiting, modify);
+        });
+    }
+}
@@ -1,13 +1,27 @@
 mod bridge;
+mod bridge_admin;
+mod bridge_archive;
 mod bridge_cmd;
+mod bridge_content;
 mod bridge_dc;
+mod bridge_face;
+mod bridge_file;
+mod bridge_format;
 mod bridge_log;
+mod bridge_metrics;
 mod bridge_qq;
+mod bridge_user;
+mod bridge_voice;
+mod cli;
 mod cmd_adapter;
 mod config;
+mod config_loader;
+mod config_watch;
 
 mod bridge_data;
 
+use arc_swap::ArcSwap;
+use clap::Parser;
 use config::*;
 use std::sync::{Arc, Mutex};
 
@@ -15,22 +29,63 @@ pub type HttpResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config = Arc::new(Config::new());
+    let args = cli::Cli::parse();
+    match args.command {
+        None | Some(cli::Command::Run) => run(args.config).await,
+        Some(command) => {
+            if let Err(e) = cli::exec(command, &args.config).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// 启动桥
+async fn run(path: String) -> Result<(), Box<dyn std::error::Error>> {
+    let config = match Config::load(&path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("配置文件有误:\n{}", e);
+            std::process::exit(1);
+        }
+    };
+    let config: SharedConfig = Arc::new(ArcSwap::from_pointee(config));
+    let config_now = config.load_full();
+    bridge_log::init(&config_now.logConfig)?;
+    // 启动前完成本地数据升级
+    for m in bridge_data::migrate::run(&config_now.bindStoreConfig)? {
+        tracing::info!("数据已升级到 v{}: {}", m.version, m.description);
+    }
+    bridge_data::bind_map::init(bridge_data::store::open(&config_now.bindStoreConfig)?)?;
+    bridge_face::init(config_now.faceMapFile.as_deref())?;
     let mut bridge_service = bridge::BridgeService::new();
+    if config_now.archiveConfig.enable {
+        let archive = bridge_archive::Archive::open(&config_now.archiveConfig.path)?;
+        bridge_service.archive = Some(Arc::new(archive));
+    }
     let bridge_service = Arc::new(Mutex::new(bridge_service));
     let bridge_dc_client =
-        bridge::BridgeService::create_client("bridge_dc_client", bridge_service.clone());
+        bridge::BridgeService::create_client(bridge::DC_CLIENT, bridge_service.clone());
     let bridge_qq_client =
-        bridge::BridgeService::create_client("bridge_qq_client", bridge_service.clone());
+        bridge::BridgeService::create_client(bridge::QQ_CLIENT, bridge_service.clone());
     let bridge_cmd_adapter =
-        bridge::BridgeService::create_client("bridge_cmd_adapter", bridge_service.clone());
+        bridge::BridgeService::create_client(bridge::CMD_CLIENT, bridge_service.clone());
+    let bridge_admin_client =
+        bridge::BridgeService::create_client(bridge::ADMIN_CLIENT, bridge_service.clone());
     // let a = Some(bridge_service.clone());
 
     tokio::select! {
         _ = bridge_dc::start(config.clone(), bridge_dc_client) => {},
         _ = bridge_qq::start(config.clone(), bridge_qq_client) => {},
         _ = cmd_adapter::start(config.clone(), bridge_cmd_adapter) => {},
+        _ = bridge_metrics::serve(config_now.metricsConfig.clone()) => {},
+        _ = bridge_admin::serve(config.clone(), bridge_admin_client) => {},
+        _ = config_watch::watch(path.into(), config.clone()) => {},
+        _ = bridge_face::watch(config.clone()) => {},
     }
+    bridge_data::bind_map::flush();
 
     Ok(())
 }